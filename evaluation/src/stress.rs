@@ -1,6 +1,8 @@
 use project::{
     checkpoint::{maybe_remove_dir_all, Checkpointer},
-    restore::restore_checkpoint,
+    fd::FdPolicy,
+    filesnap::SnapshotConfig,
+    restore::{restore_checkpoint, RestoreConfig},
 };
 use rand::prelude::*;
 use std::{
@@ -53,6 +55,8 @@ fn main() {
             // create_dir_all(CP_DIR).unwrap();
 
             let mut cp = Checkpointer::attach(pid as i32, CP_DIR.into()).unwrap();
+            // Otherwise whatever markov wrote after the checkpoint stays in its output
+            cp.config.snapshot_files = Some(SnapshotConfig::default());
             // cp.checkpoint().unwrap();
             // k_send.send(()).unwrap();
 
@@ -67,7 +71,12 @@ fn main() {
             restore_b_.wait();
 
             let restore_start = Instant::now();
-            let proc = match restore_checkpoint(&CP_DIR.into(), false) {
+            // markov's stdio is ours, whatever that is, which it can just inherit again
+            let config = RestoreConfig {
+                unsupported_fds: FdPolicy::Skip,
+                ..Default::default()
+            };
+            let proc = match restore_checkpoint(&CP_DIR.into(), &config) {
                 Ok(p) => p,
                 Err(e) => {
                     println!("[CP]: exiting ({e:?})");
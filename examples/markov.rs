@@ -18,7 +18,7 @@ fn main() {
         .take(NUM_FILES)
         .filter_map(|dir| {
             let path = dir.unwrap().path();
-            (metadata(&path).unwrap().size() < 20_000).then(|| path)
+            (metadata(&path).unwrap().size() < 20_000).then_some(path)
         })
         .collect();
 
@@ -37,7 +37,7 @@ fn main() {
             print!("{s} ");
         }
 
-        println!("");
+        println!();
         return;
     }
 
@@ -48,6 +48,6 @@ fn main() {
             print!("{s} ");
         }
 
-        println!("");
+        println!();
     }
 }
@@ -3,6 +3,8 @@ use std::{
     process::id,
 };
 
+// is_multiple_of would need Rust 1.87
+#[allow(clippy::manual_is_multiple_of)]
 fn main() {
     println!("pid: {}", id());
 
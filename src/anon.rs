@@ -0,0 +1,312 @@
+use std::{
+    error::Error,
+    ffi::{CString, OsStr},
+    fs::{self, File, OpenOptions},
+    io::{self, ErrorKind},
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd},
+        unix::{
+            ffi::OsStrExt,
+            fs::{FileExt, MetadataExt, OpenOptionsExt},
+        },
+    },
+    path::{Path, PathBuf},
+};
+
+use libc::{ENXIO, MFD_CLOEXEC, O_CLOEXEC, O_TMPFILE, SEEK_DATA, SEEK_HOLE};
+use log::{debug, warn};
+use procfs::process::{FDTarget, MMapPath, MemoryMap, Process};
+use serde::{Deserialize, Serialize};
+
+use crate::checkpoint::OpenFile;
+
+/// How /proc marks the paths of files that are gone
+const DELETED: &[u8] = b" (deleted)";
+
+/// A file that a process has open or mapped that can't be opened again by its path:
+/// a memfd, a file that was deleted, or what's behind shared anonymous memory
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AnonFile {
+    pub dev: u64,
+    pub inode: u64,
+    /// Where /proc says the file is, without the " (deleted)"
+    pub path: PathBuf,
+    pub size: u64,
+    /// The start addresses of the process' mappings of the file
+    pub maps: Vec<u64>,
+    /// The `(offset, data)` of every run of data in the file, which is written next to
+    /// the list of files, as a sparse file
+    #[serde(skip)]
+    pub data: Vec<(u64, Vec<u8>)>,
+}
+
+/// Whether `path`, as /proc has it, is a file that's gone
+pub fn is_deleted(path: &Path) -> bool {
+    path.as_os_str().as_bytes().ends_with(DELETED)
+}
+
+impl AnonFile {
+    /// Reads every file of the stopped process `procfs` that is open at one of `files`, or
+    /// mapped at one of `maps`, and that can't be opened by its path anymore
+    pub fn capture_all(
+        procfs: &Process,
+        files: &[OpenFile],
+        maps: &[MemoryMap],
+    ) -> Result<Vec<Self>, Box<dyn Error>> {
+        let pid = procfs.pid;
+
+        let mut sources = vec![];
+        for file in files {
+            let path = match &file.target {
+                FDTarget::MemFD(name) => PathBuf::from(format!("/memfd:{name}")),
+                FDTarget::Path(path) if is_deleted(path) => path.clone(),
+                _ => continue,
+            };
+            sources.push((format!("/proc/{pid}/fd/{}", file.fd), path, None, None));
+        }
+        for map in maps {
+            if let MMapPath::Path(path) = &map.pathname {
+                if is_deleted(path) {
+                    let (start, end) = map.address;
+                    let source = format!("/proc/{pid}/map_files/{start:x}-{end:x}");
+                    let (major, minor) = map.dev;
+                    let id = (libc::makedev(major as u32, minor as u32), map.inode);
+                    sources.push((source, path.clone(), Some(start), Some(id)));
+                }
+            }
+        }
+
+        let mut anon: Vec<Self> = vec![];
+        for (source, path, addr, map_id) in sources {
+            // Following the links in map_files takes CAP_CHECKPOINT_RESTORE
+            let meta = match fs::metadata(&source) {
+                Ok(meta) => meta,
+                Err(e) if addr.is_some() && e.kind() == ErrorKind::PermissionDenied => {
+                    // Fine if we got the file through an fd already
+                    match anon.iter_mut().find(|a| Some((a.dev, a.inode)) == map_id) {
+                        Some(file) => file.maps.extend(addr),
+                        None => warn!(
+                            "Can't get at {path:?} to dump it, its mapping will be restored private"
+                        ),
+                    }
+                    continue;
+                }
+                Err(e) => return Err(e.into()),
+            };
+            if !meta.is_file() {
+                continue;
+            }
+
+            let index = match anon
+                .iter()
+                .position(|a| (a.dev, a.inode) == (meta.dev(), meta.ino()))
+            {
+                Some(index) => index,
+                None => {
+                    debug!("Dumping {path:?}, which can't be opened again");
+                    let bytes = path.as_os_str().as_bytes();
+                    let path = PathBuf::from(OsStr::from_bytes(
+                        bytes.strip_suffix(DELETED).unwrap_or(bytes),
+                    ));
+
+                    let file = File::open(&source)?;
+                    let mut data = vec![];
+                    for (offset, len) in data_extents(&file, meta.size())? {
+                        let mut buf = vec![0; len as usize];
+                        file.read_exact_at(&mut buf, offset)?;
+                        data.push((offset, buf));
+                    }
+
+                    anon.push(Self {
+                        dev: meta.dev(),
+                        inode: meta.ino(),
+                        path,
+                        size: meta.size(),
+                        maps: vec![],
+                        data,
+                    });
+                    anon.len() - 1
+                }
+            };
+            anon[index].maps.extend(addr);
+        }
+
+        Ok(anon)
+    }
+
+    /// Writes `anon` to the checkpoint in `cp_dir`, the data of each in its own file
+    pub fn persist_all(anon: &[Self], cp_dir: &Path) -> Result<(), Box<dyn Error>> {
+        for (i, file) in anon.iter().enumerate() {
+            let out = File::create(cp_dir.join(format!("anon.{i}")))?;
+            for (offset, data) in &file.data {
+                out.write_all_at(data, *offset)?;
+            }
+            out.set_len(file.size)?;
+        }
+        serde_json::to_writer(File::create(cp_dir.join("anon"))?, anon)?;
+
+        Ok(())
+    }
+
+    /// Reads the files of the checkpoint in `cp_dir`, without their data,
+    /// which older checkpoints and ones that didn't have any don't have
+    pub fn open_all(cp_dir: &Path) -> Result<Vec<Self>, Box<dyn Error>> {
+        match File::open(cp_dir.join("anon")) {
+            Ok(file) => Ok(serde_json::from_reader(file)?),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(vec![]),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Makes the file again, with the data from `anon.{index}` of the checkpoint in
+    /// `cp_dir`: memfds as memfds, and deleted files as unlinked files in the same
+    /// directory if we can, or memfds otherwise
+    pub fn create(&self, cp_dir: &Path, index: usize) -> Result<File, Box<dyn Error>> {
+        let memfd_name = match self.path.to_str().and_then(|p| p.strip_prefix("/memfd:")) {
+            Some(name) => Some(name),
+            // Shared anonymous memory, which would make the tmpfile in devtmpfs
+            None if self.path == Path::new("/dev/zero") => Some("dev/zero"),
+            None => None,
+        };
+
+        let tmpfile = memfd_name.is_none().then(|| {
+            let dir = self.path.parent().unwrap_or(Path::new("/"));
+            OpenOptions::new()
+                .read(true)
+                .write(true)
+                .custom_flags(O_TMPFILE | O_CLOEXEC)
+                .open(dir)
+        });
+        let file = match tmpfile {
+            Some(Ok(file)) => file,
+            tmpfile => {
+                if let Some(Err(e)) = tmpfile {
+                    debug!("Making {:?} as a memfd, no tmpfile for it: {e}", self.path);
+                }
+
+                let name = match memfd_name {
+                    Some(name) => name.as_bytes(),
+                    None => self.path.file_name().map_or(&[][..], OsStr::as_bytes),
+                };
+                let name = CString::new(name)?;
+                let fd = unsafe { libc::memfd_create(name.as_ptr(), MFD_CLOEXEC) };
+                if fd < 0 {
+                    return Err(io::Error::last_os_error().into());
+                }
+                File::from(unsafe { OwnedFd::from_raw_fd(fd) })
+            }
+        };
+
+        let dump = File::open(cp_dir.join(format!("anon.{index}")))?;
+        for (offset, len) in data_extents(&dump, self.size)? {
+            let mut buf = vec![0; len as usize];
+            dump.read_exact_at(&mut buf, offset)?;
+            file.write_all_at(&buf, offset)?;
+        }
+        file.set_len(self.size)?;
+
+        Ok(file)
+    }
+}
+
+/// The `(offset, length)` runs of data in the first `size` bytes of `file`, skipping holes
+fn data_extents(file: &File, size: u64) -> io::Result<Vec<(u64, u64)>> {
+    let mut extents = vec![];
+    let mut offset = 0;
+    while offset < size {
+        let start = unsafe { libc::lseek(file.as_raw_fd(), offset as i64, SEEK_DATA) };
+        if start < 0 {
+            // ENXIO is there being no data left
+            match io::Error::last_os_error() {
+                e if e.raw_os_error() == Some(ENXIO) => break,
+                e => return Err(e),
+            }
+        }
+        let end = unsafe { libc::lseek(file.as_raw_fd(), start, SEEK_HOLE) };
+        if end < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let end = (end as u64).min(size);
+        extents.push((start as u64, end - start as u64));
+        offset = end;
+    }
+
+    Ok(extents)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+
+    use super::*;
+
+    #[test]
+    fn memfds_and_deleted_files_are_made_again() {
+        let dir = env::temp_dir().join(format!("anon-test-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let memfd = unsafe { libc::memfd_create(c"anon-test".as_ptr(), MFD_CLOEXEC) };
+        let memfd = File::from(unsafe { OwnedFd::from_raw_fd(memfd) });
+        memfd.write_all_at(b"start", 0).unwrap();
+        memfd.write_all_at(b"end", 1 << 20).unwrap();
+        memfd.set_len(2 << 20).unwrap();
+
+        let path = dir.join("gone");
+        let deleted = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        deleted.write_all_at(b"still here", 0).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let procfs = Process::myself().unwrap();
+        let files: Vec<_> = [&memfd, &deleted]
+            .map(|file| {
+                let target = procfs.fd_from_fd(file.as_raw_fd()).unwrap().target;
+                OpenFile {
+                    fd: file.as_raw_fd(),
+                    target,
+                    offset: 0,
+                    flags: 0,
+                    mnt_id: None,
+                    identity: None,
+                }
+            })
+            .into();
+        assert!(matches!(&files[1].target, FDTarget::Path(p) if is_deleted(p)));
+
+        let anon = AnonFile::capture_all(&procfs, &files, &[]).unwrap();
+        assert_eq!(anon.len(), 2);
+        assert_eq!(anon[0].path, Path::new("/memfd:anon-test"));
+        assert_eq!(anon[0].data.len(), 2);
+        assert_eq!(anon[1].path, path);
+
+        let cp_dir = dir.join("cp");
+        fs::create_dir_all(&cp_dir).unwrap();
+        AnonFile::persist_all(&anon, &cp_dir).unwrap();
+        let opened = AnonFile::open_all(&cp_dir).unwrap();
+
+        let mut contents = vec![0; 2 << 20];
+        memfd.read_exact_at(&mut contents, 0).unwrap();
+        let made = opened[0].create(&cp_dir, 0).unwrap();
+        assert_eq!(
+            fs::read(format!("/proc/self/fd/{}", made.as_raw_fd())).unwrap(),
+            contents
+        );
+        let made = opened[1].create(&cp_dir, 1).unwrap();
+        assert_eq!(
+            fs::read(format!("/proc/self/fd/{}", made.as_raw_fd())).unwrap(),
+            b"still here"
+        );
+
+        // Made in the same directory, without a name
+        let target = procfs.fd_from_fd(made.as_raw_fd()).unwrap().target;
+        assert!(matches!(target, FDTarget::Path(p) if p.starts_with(&dir) && is_deleted(&p)));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
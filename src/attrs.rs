@@ -0,0 +1,276 @@
+use std::{
+    error::Error,
+    fs::{self, File},
+    io::{self, ErrorKind},
+    mem,
+    path::{Path, PathBuf},
+};
+
+use libc::{c_int, cpu_set_t, pid_t, rlimit64, sched_param, CPU_SETSIZE, PRIO_PROCESS};
+use log::warn;
+use procfs::process::Process;
+use serde::{Deserialize, Serialize};
+
+/// Every resource limit there is, see `getrlimit(2)`
+const RESOURCES: [c_int; 16] = [
+    libc::RLIMIT_CPU as c_int,
+    libc::RLIMIT_FSIZE as c_int,
+    libc::RLIMIT_DATA as c_int,
+    libc::RLIMIT_STACK as c_int,
+    libc::RLIMIT_CORE as c_int,
+    libc::RLIMIT_RSS as c_int,
+    libc::RLIMIT_NPROC as c_int,
+    libc::RLIMIT_NOFILE as c_int,
+    libc::RLIMIT_MEMLOCK as c_int,
+    libc::RLIMIT_AS as c_int,
+    libc::RLIMIT_LOCKS as c_int,
+    libc::RLIMIT_SIGPENDING as c_int,
+    libc::RLIMIT_MSGQUEUE as c_int,
+    libc::RLIMIT_NICE as c_int,
+    libc::RLIMIT_RTPRIO as c_int,
+    libc::RLIMIT_RTTIME as c_int,
+];
+
+/// The attributes of a process that aren't part of its memory, files or threads,
+/// which the restored process would otherwise inherit from whoever restored it.
+///
+/// The bootstrapper sets the ones that a process can only set for itself (everything
+/// up to `comm`), and `apply` sets the rest from the outside.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProcAttrs {
+    pub cwd: PathBuf,
+    pub root: PathBuf,
+    /// Only reported by Linux 4.7 and up
+    pub umask: Option<u32>,
+    pub personality: u32,
+    /// The name of the process, as `PR_SET_NAME` takes it
+    pub comm: String,
+    pub rlimits: Vec<Rlimit>,
+    /// The thread group leader's, which checkpoints from before every thread
+    /// had its own (see `Thread::sched`) restore all of the threads with
+    #[serde(flatten)]
+    pub sched: SchedAttrs,
+}
+
+/// The scheduling attributes of a thread, which every thread has its own of
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SchedAttrs {
+    pub nice: i32,
+    /// The CPUs the thread may run on
+    pub affinity: Vec<usize>,
+    /// The scheduling policy, e.g. `SCHED_OTHER`, with `SCHED_RESET_ON_FORK` if it was set
+    pub policy: i32,
+    /// The static priority that goes with realtime policies, otherwise 0
+    pub priority: i32,
+}
+
+/// One resource limit, as `prlimit` has it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Rlimit {
+    pub resource: c_int,
+    pub cur: u64,
+    pub max: u64,
+}
+
+impl ProcAttrs {
+    /// Reads the attributes of the process `procfs`, all of which we can get at from the outside
+    pub fn capture(procfs: &Process) -> Result<Self, Box<dyn Error>> {
+        let pid = procfs.pid;
+        let stat = procfs.stat()?;
+
+        let raw_personality = fs::read_to_string(format!("/proc/{pid}/personality"))?;
+        let personality = u32::from_str_radix(raw_personality.trim(), 16)?;
+
+        let mut rlimits = vec![];
+        for resource in RESOURCES {
+            let mut limit = rlimit64 {
+                rlim_cur: 0,
+                rlim_max: 0,
+            };
+            let res = unsafe { libc::prlimit64(pid, resource as _, std::ptr::null(), &mut limit) };
+            if res < 0 {
+                return Err(io::Error::last_os_error().into());
+            }
+
+            rlimits.push(Rlimit {
+                resource,
+                cur: limit.rlim_cur,
+                max: limit.rlim_max,
+            });
+        }
+
+        Ok(Self {
+            cwd: procfs.cwd()?,
+            root: procfs.root()?,
+            umask: procfs.status()?.umask,
+            personality,
+            comm: stat.comm,
+            rlimits,
+            sched: SchedAttrs::capture(procfs, pid)?,
+        })
+    }
+
+    /// Reads the attributes of the checkpoint in `cp_dir`, which older checkpoints don't have
+    pub fn open(cp_dir: &Path) -> Result<Option<Self>, Box<dyn Error>> {
+        match File::open(cp_dir.join("proc")) {
+            Ok(file) => Ok(Some(serde_json::from_reader(file)?)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Sets the attributes that can be set from the outside on the stopped process `pid`,
+    /// and the scheduling attributes of each of its `threads`, and checks that the
+    /// bootstrapper managed to set the rest.
+    ///
+    /// Raising a hard limit, the priority, or switching to a realtime policy takes
+    /// privileges that we may not have, so anything that fails is only warned about.
+    pub fn apply(
+        &self,
+        pid: pid_t,
+        threads: &[(pid_t, &SchedAttrs)],
+    ) -> Result<(), Box<dyn Error>> {
+        for limit in &self.rlimits {
+            let new = rlimit64 {
+                rlim_cur: limit.cur,
+                rlim_max: limit.max,
+            };
+            let res =
+                unsafe { libc::prlimit64(pid, limit.resource as _, &new, std::ptr::null_mut()) };
+            if res < 0 {
+                warn!(
+                    "Couldn't restore resource limit {} to {:?}: {}",
+                    limit.resource,
+                    (limit.cur, limit.max),
+                    io::Error::last_os_error()
+                );
+            }
+        }
+
+        // The threads were started with the bootstrapper's
+        for (tid, sched) in threads {
+            sched.apply(*tid);
+        }
+
+        let procfs = Process::new(pid)?;
+        if procfs.cwd()? != self.cwd {
+            warn!(
+                "Couldn't restore the working directory {:?}, it's {:?}",
+                self.cwd,
+                procfs.cwd()?
+            );
+        }
+        if procfs.root()? != self.root {
+            warn!(
+                "Couldn't restore the root directory {:?} (chroot needs CAP_SYS_CHROOT)",
+                self.root
+            );
+        }
+
+        Ok(())
+    }
+}
+
+impl SchedAttrs {
+    /// Reads the scheduling attributes of the thread `tid` of the process `procfs`
+    pub fn capture(procfs: &Process, tid: pid_t) -> Result<Self, Box<dyn Error>> {
+        let stat = procfs.task_from_tid(tid)?.stat()?;
+
+        let mut set: cpu_set_t = unsafe { mem::zeroed() };
+        if unsafe { libc::sched_getaffinity(tid, mem::size_of::<cpu_set_t>(), &mut set) } < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        let affinity = (0..CPU_SETSIZE as usize)
+            .filter(|&cpu| unsafe { libc::CPU_ISSET(cpu, &set) })
+            .collect();
+
+        let policy = unsafe { libc::sched_getscheduler(tid) };
+        let mut param = sched_param { sched_priority: 0 };
+        if policy < 0 || unsafe { libc::sched_getparam(tid, &mut param) } < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+
+        Ok(Self {
+            nice: stat.nice as i32,
+            affinity,
+            policy,
+            priority: param.sched_priority,
+        })
+    }
+
+    /// Sets these on the thread `tid`, warning about anything that fails
+    pub fn apply(&self, tid: pid_t) {
+        let mut set: cpu_set_t = unsafe { mem::zeroed() };
+        for &cpu in &self.affinity {
+            unsafe { libc::CPU_SET(cpu, &mut set) };
+        }
+        if unsafe { libc::sched_setaffinity(tid, mem::size_of::<cpu_set_t>(), &set) } < 0 {
+            warn!(
+                "Couldn't restore the CPU affinity of thread {tid}: {}",
+                io::Error::last_os_error()
+            );
+        }
+
+        let param = sched_param {
+            sched_priority: self.priority,
+        };
+        if unsafe { libc::sched_setscheduler(tid, self.policy, &param) } < 0 {
+            warn!(
+                "Couldn't restore the scheduling policy {} of thread {tid}: {}",
+                self.policy,
+                io::Error::last_os_error()
+            );
+        }
+
+        if unsafe { libc::setpriority(PRIO_PROCESS, tid as _, self.nice) } < 0 {
+            warn!(
+                "Couldn't restore the nice value {} of thread {tid}: {}",
+                self.nice,
+                io::Error::last_os_error()
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process::Command};
+
+    use super::*;
+
+    #[test]
+    fn apply_sets_what_capture_read() {
+        let cwd = env::temp_dir().canonicalize().unwrap();
+        let mut tracee = Command::new("sleep")
+            .arg("30")
+            .current_dir(&cwd)
+            .spawn()
+            .unwrap();
+        let pid = tracee.id() as pid_t;
+
+        let mut attrs = ProcAttrs::capture(&Process::new(pid).unwrap()).unwrap();
+        assert_eq!(
+            (attrs.cwd.as_path(), attrs.comm.as_str()),
+            (cwd.as_path(), "sleep")
+        );
+        assert_eq!(attrs.rlimits.len(), RESOURCES.len());
+        assert!(!attrs.sched.affinity.is_empty());
+
+        // Lowering a limit and the priority never needs privileges
+        let nofile = attrs
+            .rlimits
+            .iter_mut()
+            .find(|l| l.resource == libc::RLIMIT_NOFILE as c_int)
+            .unwrap();
+        nofile.cur = 64;
+        attrs.sched.nice = 5;
+        attrs.apply(pid, &[(pid, &attrs.sched)]).unwrap();
+
+        let applied = ProcAttrs::capture(&Process::new(pid).unwrap()).unwrap();
+        assert_eq!(applied.rlimits, attrs.rlimits);
+        assert_eq!(applied.sched.nice, 5);
+
+        tracee.kill().unwrap();
+        tracee.wait().unwrap();
+    }
+}
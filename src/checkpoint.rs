@@ -1,38 +1,64 @@
 use std::{
+    collections::HashMap,
     error::Error,
-    fs::{create_dir, hard_link, read_to_string, remove_dir_all, write, File, OpenOptions},
-    io::{ErrorKind, Read, Seek, SeekFrom, Write},
-    os::unix::fs::FileExt,
+    fs::{
+        create_dir, create_dir_all, hard_link, metadata, read_dir, read_to_string, remove_dir_all,
+        rename, write, File, Metadata, OpenOptions,
+    },
+    io::{self, ErrorKind, Read, Write},
+    os::unix::fs::{FileExt, MetadataExt},
     path::{Path, PathBuf},
     thread,
     time::{Duration, Instant},
 };
 
-use libc::pid_t;
+use libc::{
+    pid_t, SYS_clone, SYS_wait4, __WALL, EIO, O_RDONLY, O_RDWR, O_WRONLY, PTRACE_O_TRACECLONE,
+    SIGKILL,
+};
 use log::{debug, info};
-use procfs::process::{FDInfo, MMPermissions, MMapPath, MemoryMap, Process};
+use procfs::process::{FDInfo, FDTarget, MMPermissions, MMapPath, MemoryMap, Process};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
 use crate::{
-    ptrace::{PTrace, Registers},
+    anon::{is_deleted, AnonFile},
+    attrs::ProcAttrs,
+    compress::Compression,
+    fd::{self, FdDump},
+    filesnap::{FileSnapshot, SnapshotConfig},
+    memory::{self, MemReader},
+    mm::MmFields,
+    overlay::{self, FileOverlay},
+    pagemap::{self, PAGE_SIZE},
+    ptrace::PTrace,
     restore,
+    shared::SharedMap,
+    signal::Signals,
+    store::ChunkStore,
+    thread::{attach_all, detach_all, Thread},
+    tree::{self, TreeNode, PROCS_DIR},
+    vdso, vma,
 };
 
-// TODOS:
-// - Threads (TLS, etc.)
-// -- what do with vvar and vdso
-// - File descriptors (basic)
-// - more register sets (vectors)
-
 pub struct StepData {
     pub seq: u64,
     pub seq_file: File,
     pub last_maps: Vec<MemoryMap>,
+    pub last_deltas: Vec<DeltaImage>,
+    pub last_sparse: Vec<SparseImage>,
+    pub last_chunks: Vec<ChunkedImage>,
+    pub last_reservations: Vec<usize>,
+    pub last_bytes_copied: u64,
+    /// How many bytes of memory the last checkpoint wrote, before and after compression
+    pub last_bytes_written: (u64, u64),
+    pub compression: Compression,
 }
 
 impl StepData {
-    pub fn open(path: &PathBuf) -> Result<Self, Box<dyn Error>> {
+    pub fn open(path: &Path) -> Result<Self, Box<dyn Error>> {
         let mut seq_file = OpenOptions::new()
             .create(true)
+            .truncate(false)
             .read(true)
             .write(true)
             .open(path.join("seq"))?;
@@ -41,195 +67,1326 @@ impl StepData {
         seq_file.read_to_string(&mut seq_buf)?;
         let seq: u64 = seq_buf.parse().unwrap_or(0);
 
-        let last_maps = if seq != 0 {
-            let map_file = File::open(path.join(seq.to_string()).join("maps"))?;
-            serde_json::from_reader(map_file)?
-        } else {
-            vec![]
+        let mut step = Self {
+            seq,
+            seq_file,
+            last_maps: vec![],
+            last_deltas: vec![],
+            last_sparse: vec![],
+            last_chunks: vec![],
+            last_reservations: vec![],
+            last_bytes_copied: 0,
+            last_bytes_written: (0, 0),
+            compression: Compression::open(path)?,
         };
 
+        if seq != 0 {
+            let cp_dir = path.join(seq.to_string());
+            step.last_maps = serde_json::from_reader(File::open(cp_dir.join("maps"))?)?;
+            step.last_deltas = Deltas::open(&cp_dir)?.map(|d| d.images).unwrap_or_default();
+            step.last_sparse = SparseImage::open_all(&cp_dir)?;
+            step.last_chunks = ChunkedImage::open_all(&cp_dir)?;
+            step.last_reservations = open_reservations(&cp_dir)?;
+        }
+
+        Ok(step)
+    }
+
+    /// Forgets what the last checkpoint saved, so that the next one saves everything anew
+    pub fn forget_last(&mut self) {
+        self.last_maps.clear();
+        self.last_deltas.clear();
+        self.last_sparse.clear();
+        self.last_chunks.clear();
+        self.last_reservations.clear();
+    }
+}
+
+/// The page-level parent chain of the memory images written by an incremental checkpoint
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Deltas {
+    /// The oldest checkpoint that this checkpoint's images (transitively) depend on
+    pub base: u64,
+    pub images: Vec<DeltaImage>,
+}
+
+/// A memory image that only holds the pages dirtied since the previous checkpoint.
+///
+/// The image file is the dirty pages concatenated in the order of `pages`,
+/// every other page comes from the image of `parent` in the previous checkpoint.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeltaImage {
+    /// The index of the memory region in this checkpoint's maps
+    pub index: usize,
+    /// The index of the same memory region in the previous checkpoint's maps
+    pub parent: usize,
+    /// How many checkpoints back the nearest full image of this region is
+    pub depth: u64,
+    /// The page numbers, relative to the start of the region, stored in the image
+    pub pages: Vec<u64>,
+}
+
+impl Deltas {
+    /// Reads the deltas of the checkpoint in `cp_dir`,
+    /// or `None` if it was not an incremental checkpoint
+    pub fn open(cp_dir: &Path) -> Result<Option<Self>, Box<dyn Error>> {
+        match File::open(cp_dir.join("deltas")) {
+            Ok(file) => Ok(Some(serde_json::from_reader(file)?)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+/// A memory image with holes where the region was never touched or held only zeros.
+///
+/// The image file is as long as the region, but only `extents` of it were written.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SparseImage {
+    /// The index of the memory region in this checkpoint's maps
+    pub index: usize,
+    /// The `(offset, length)` of every run of data in the image
+    pub extents: Vec<(u64, u64)>,
+}
+
+impl SparseImage {
+    /// Reads the sparse images of the checkpoint in `cp_dir`
+    pub fn open_all(cp_dir: &Path) -> Result<Vec<Self>, Box<dyn Error>> {
+        open_list(cp_dir, "sparse")
+    }
+}
+
+/// A memory image kept in the chunk store rather than in its own file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChunkedImage {
+    /// The index of the memory region in this checkpoint's maps
+    pub index: usize,
+    /// The length of the image, which is that of the memory region unless it's a delta
+    pub len: u64,
+    /// The hash of each chunk of the region, or `None` if it's all zeros
+    pub chunks: Vec<Option<String>>,
+}
+
+impl ChunkedImage {
+    /// Reads the chunked images of the checkpoint in `cp_dir`
+    pub fn open_all(cp_dir: &Path) -> Result<Vec<Self>, Box<dyn Error>> {
+        open_list(cp_dir, "chunks")
+    }
+}
+
+/// An open file of a process, with what it takes to open it again the same way
+/// and to tell whether the file is still the one it was open to
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OpenFile {
+    pub fd: i32,
+    pub target: FDTarget,
+    pub offset: u64,
+    /// The flags from fdinfo, as `open` takes them: the access mode, the file status
+    /// flags like `O_APPEND` and `O_NONBLOCK`, and `O_CLOEXEC` if the fd is close-on-exec
+    pub flags: i32,
+    /// Only reported by Linux 3.15 and up
+    pub mnt_id: Option<u64>,
+    /// Older checkpoints don't have this
+    pub identity: Option<FileIdentity>,
+}
+
+/// What the file behind an fd was at checkpoint time
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct FileIdentity {
+    pub inode: u64,
+    pub dev: u64,
+    pub size: u64,
+    /// The modification time, as `(seconds, nanoseconds)`
+    pub mtime: (i64, i64),
+}
+
+/// How checkpoints from before `OpenFile` stored their files
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredFile {
+    Record(OpenFile),
+    Legacy((FDInfo, u64)),
+}
+
+impl OpenFile {
+    /// Reads the fd `file` of the stopped process `procfs`
+    fn capture(procfs: &Process, file: FDInfo) -> Result<Self, Box<dyn Error>> {
+        let pid = procfs.pid;
+        let info = read_to_string(format!("/proc/{pid}/fdinfo/{}", file.fd))?;
+
         Ok(Self {
-            seq,
-            seq_file,
-            last_maps,
+            fd: file.fd,
+            offset: fd::field(&info, "pos")?.parse()?,
+            flags: i32::from_str_radix(fd::field(&info, "flags")?, 8)?,
+            mnt_id: fd::field(&info, "mnt_id")
+                .ok()
+                .map(str::parse)
+                .transpose()?,
+            identity: Some(FileIdentity::from(&metadata(format!(
+                "/proc/{pid}/fd/{}",
+                file.fd
+            ))?)),
+            target: file.target,
         })
     }
+
+    /// Reads the open files of the checkpoint in `cp_dir`
+    pub fn open_all(cp_dir: &Path) -> Result<Vec<Self>, Box<dyn Error>> {
+        let stored: Vec<StoredFile> = serde_json::from_reader(File::open(cp_dir.join("files"))?)?;
+
+        Ok(stored
+            .into_iter()
+            .map(|file| match file {
+                StoredFile::Record(file) => file,
+                // All we had then was the mode of the fd's link in /proc, whose
+                // owner read and write bits are the access mode it was opened with
+                StoredFile::Legacy((file, offset)) => Self {
+                    fd: file.fd,
+                    target: file.target,
+                    offset,
+                    flags: match (file.mode & 0o400 != 0, file.mode & 0o200 != 0) {
+                        (true, true) => O_RDWR,
+                        (false, true) => O_WRONLY,
+                        _ => O_RDONLY,
+                    },
+                    mnt_id: None,
+                    identity: None,
+                },
+            })
+            .collect())
+    }
+}
+
+impl From<&Metadata> for FileIdentity {
+    fn from(meta: &Metadata) -> Self {
+        Self {
+            inode: meta.ino(),
+            dev: meta.dev(),
+            size: meta.size(),
+            mtime: (meta.mtime(), meta.mtime_nsec()),
+        }
+    }
+}
+
+impl FileIdentity {
+    /// What the file that `map` of process `pid` maps from `path` is,
+    /// or `None` if `path` isn't that file anymore
+    pub fn of_map(
+        pid: pid_t,
+        map: &MemoryMap,
+        path: &Path,
+    ) -> Result<Option<Self>, Box<dyn Error>> {
+        let (start, end) = map.address;
+        let meta = match metadata(format!("/proc/{pid}/map_files/{start:x}-{end:x}")) {
+            Ok(meta) => meta,
+            // Following the links in map_files takes CAP_CHECKPOINT_RESTORE,
+            // so make do with the path if it's still the same file
+            Err(e) if e.kind() == ErrorKind::PermissionDenied => metadata(path)?,
+            Err(e) => return Err(e.into()),
+        };
+
+        let identity = Self::from(&meta);
+        let (major, minor) = map.dev;
+        let dev = libc::makedev(major as u32, minor as u32);
+        Ok(((identity.dev, identity.inode) == (dev, map.inode)).then_some(identity))
+    }
+}
+
+/// Reads the indices of the memory regions of the checkpoint in `cp_dir` that are
+/// only reservations: they have no image, and get restored as `PROT_NONE` memory
+pub fn open_reservations(cp_dir: &Path) -> Result<Vec<usize>, Box<dyn Error>> {
+    open_list(cp_dir, "reservations")
+}
+
+/// Reads the list in the file `name` of the checkpoint in `cp_dir`,
+/// which is empty for checkpoints from before we wrote it
+fn open_list<T: DeserializeOwned>(cp_dir: &Path, name: &str) -> Result<Vec<T>, Box<dyn Error>> {
+    match File::open(cp_dir.join(name)) {
+        Ok(file) => Ok(serde_json::from_reader(file)?),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(vec![]),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CheckpointConfig {
+    /// Only save the pages that were written since the last checkpoint,
+    /// using the kernel's soft-dirty page tracking
+    pub incremental: bool,
+    /// The maximum number of deltas stacked on top of a full memory image
+    /// before an incremental checkpoint saves the region in full again
+    pub max_chain: u64,
+    /// Have the process fork and copy memory out of the child,
+    /// so the process is only stopped for as long as the fork takes
+    pub cow: bool,
+    /// The maximum number of pre-dump rounds before the final freeze
+    pub predump_rounds: u64,
+    /// Pre-dumping stops once a round copies at most this many bytes
+    pub predump_threshold: u64,
+    /// Keep memory images in a content-addressed chunk store,
+    /// so identical data is only stored once across checkpoints
+    pub dedup: bool,
+    /// How to compress memory images, which has to match
+    /// what the checkpoint directory was created with
+    pub compression: Compression,
+    /// Checkpoint every descendant of the process along with it, each under
+    /// `procs/<pid>/`, and record the shape of the tree in every checkpoint.
+    pub tree: bool,
+    /// Save the contents of the files the process has open for writing, which the
+    /// restore rolls them back to, so that they agree with the process' memory
+    pub snapshot_files: Option<SnapshotConfig>,
+    /// Also save the private memory the process marked `MADV_DONTDUMP`,
+    /// which otherwise comes back zeroed
+    pub dump_dontdump: bool,
+    /// Save private file mappings in full, instead of only the pages that differ from
+    /// the file, so that the restore doesn't need the file to still be there
+    pub dump_file_maps: bool,
+}
+
+impl Default for CheckpointConfig {
+    fn default() -> Self {
+        Self {
+            incremental: false,
+            max_chain: 8,
+            cow: false,
+            predump_rounds: 8,
+            predump_threshold: 1 << 20,
+            dedup: false,
+            compression: Compression::default(),
+            tree: false,
+            snapshot_files: None,
+            dump_dontdump: false,
+            dump_file_maps: false,
+        }
+    }
 }
 
 pub struct Checkpointer {
     pub procfs: Process,
+    /// When the process started, which tells it apart from a later one with the same pid
+    pub start_time: u64,
     // pub ptrace: PTrace,
-    pub mem_file: File,
+    pub mem: MemReader,
     pub path: PathBuf,
+    pub config: CheckpointConfig,
+
+    /// Whether the soft-dirty bits of the process were cleared at the last checkpoint,
+    /// i.e. whether its pagemap currently tells us what changed since then
+    pub dirty_tracking: bool,
+
+    /// Snapshot processes from copy-on-write checkpoints that the tracee still has to reap
+    pub snapshots: Vec<pid_t>,
+
+    /// Memory copied by `predump` while the process was running,
+    /// which the next checkpoint only has to patch with the pages dirtied since
+    pub predumped: Vec<(MemoryMap, Vec<u8>)>,
+
+    /// The chunk store, opened the first time it's needed
+    pub store: Option<ChunkStore>,
+
+    /// The checkpointers of the descendants of the process, in tree mode
+    pub descendants: Vec<Checkpointer>,
+
+    /// The hashes of the mapped parts of files we already hashed for a `FileOverlay`
+    pub file_hashes: HashMap<(FileIdentity, u64, u64), String>,
 
     pub step: StepData,
 }
 
 pub struct VolatileCheckpoint {
-    pub regs: Registers,
-    pub files: Vec<(FDInfo, u64)>,
+    /// Every thread of the process, the thread group leader first
+    pub threads: Vec<Thread>,
+    pub signals: Signals,
+    pub attrs: ProcAttrs,
+    pub mm: MmFields,
+    pub files: Vec<OpenFile>,
+    /// How to restore the `files` that aren't regular files
+    pub fds: Vec<FdDump>,
+    /// The contents of the `files` that are open for writing, if we're snapshotting them
+    pub file_snapshots: Vec<FileSnapshot>,
+    /// The files in `files` or `maps` that can't be opened by their path again
+    pub anon_files: Vec<AnonFile>,
+    /// The `maps` that were only saved as the pages that differ from their files
+    pub overlays: Vec<FileOverlay>,
+    /// What the shared mappings in `maps` share their memory with, other than `anon_files`
+    pub shared_maps: Vec<SharedMap>,
     pub maps: Vec<MemoryMap>,
     pub mems: Vec<(usize, Vec<u8>)>,
     pub reusable_mems: Vec<(usize, usize)>,
+    pub deltas: Vec<DeltaImage>,
+    /// The `(offset, length)` ranges of the region that `mems[_]` holds, concatenated,
+    /// for the regions where we only read the pages that were actually there
+    pub extents: Vec<(usize, Vec<(u64, u64)>)>,
+    /// The regions we couldn't or didn't need to read, which are restored as `PROT_NONE`
+    pub reservations: Vec<usize>,
+    /// The processes of the tree this is the root of, if we checkpointed the whole tree
+    pub tree: Vec<TreeNode>,
+    /// How long the process was stopped for
+    pub pause_time: Duration,
+    /// How many bytes of memory were read for this checkpoint
+    pub bytes_copied: u64,
+}
+
+/// What `Checkpointer::predump` did
+#[derive(Debug, Clone)]
+pub struct Predump {
+    /// The number of bytes copied in each round
+    pub rounds: Vec<u64>,
+    /// How long the process was stopped in total to collect dirty pages between rounds
+    pub pause_time: Duration,
+}
+
+/// How the memory of a region ends up in a checkpoint
+enum MemPlan {
+    /// Don't save anything, just keep the address range reserved
+    Reserve,
+    /// Don't save anything, the restored process gets this from its own kernel
+    Kernel,
+    /// Don't save anything, the process didn't want it in a core dump either
+    Skip,
+    /// Hard link the image of `old_maps[_]` from the previous checkpoint
+    Reuse(usize),
+    /// Save the whole region
+    Full,
+    /// Only save these pages of an anonymous region, the rest were never touched
+    Sparse(Vec<u64>),
+    /// Only save the dirty pages, on top of the parent's image
+    Delta(DeltaImage),
+    /// Copy the dirty pages over a pre-dumped image of the region
+    Patch(Vec<u8>, Vec<u64>),
+    /// Only save the pages of a private file mapping that differ from the file (at the
+    /// path), out of these ones that were written to
+    Overlay(FileOverlay, PathBuf, Vec<u64>),
 }
 
 impl Checkpointer {
     pub fn attach(pid: pid_t, path: PathBuf) -> Result<Self, Box<dyn Error>> {
         let procfs = Process::new(pid)?;
-        let mem_file = procfs.mem()?;
+        let mem = MemReader::new(&procfs)?;
+        let start_time = procfs.stat()?.starttime;
 
         Ok(Self {
             step: StepData::open(&path)?,
+            config: CheckpointConfig::default(),
+            dirty_tracking: false,
+            snapshots: vec![],
+            predumped: vec![],
+            store: None,
+            descendants: vec![],
+            file_hashes: HashMap::new(),
 
             procfs,
-            mem_file,
+            start_time,
+            mem,
             path,
         })
     }
 
+    fn store(&mut self) -> Result<&mut ChunkStore, Box<dyn Error>> {
+        if self.store.is_none() {
+            self.store = Some(ChunkStore::open(&self.path, self.step.compression)?);
+        }
+
+        Ok(self.store.as_mut().unwrap())
+    }
+
+    /// Plans to save `map` as a `FileOverlay` if it's a private mapping of a file
+    /// that we can count on being able to map again
+    fn overlay_plan(&self, map: &MemoryMap) -> Result<Option<MemPlan>, Box<dyn Error>> {
+        let MMapPath::Path(path) = &map.pathname else {
+            return Ok(None);
+        };
+        if self.config.dump_file_maps
+            || !map.perms.contains(MMPermissions::PRIVATE)
+            || is_deleted(path)
+            || vma::needs_anonymous(map)
+            || overlay::is_image(path, &self.path)
+        {
+            return Ok(None);
+        }
+
+        let pid = self.procfs.pid;
+        let Some(identity) = FileIdentity::of_map(pid, map, path)? else {
+            return Ok(None);
+        };
+
+        // Where we can read the file even if it was renamed since
+        let (start, end) = map.address;
+        let map_file = PathBuf::from(format!("/proc/{pid}/map_files/{start:x}-{end:x}"));
+        let source = match File::open(&map_file) {
+            Ok(_) => map_file,
+            Err(_) => path.clone(),
+        };
+
+        // The rest are still the pages of the file
+        let pages = pagemap::page_infos(&self.procfs, map.address)?
+            .iter()
+            .enumerate()
+            .filter_map(|(i, info)| pagemap::is_copied(info).then_some(i as u64))
+            .collect();
+
+        let overlay = FileOverlay {
+            index: 0,
+            path: path.clone(),
+            identity,
+            hash: None,
+            pages: vec![],
+        };
+        Ok(Some(MemPlan::Overlay(overlay, source, pages)))
+    }
+
+    /// Returns the pages of a private mapping that the process wrote to, so that
+    /// they're no longer zeros or the pages of the file
+    fn written_pages(&self, map: &MemoryMap) -> Result<Vec<u64>, Box<dyn Error>> {
+        let infos = pagemap::page_infos(&self.procfs, map.address)?;
+        Ok(infos
+            .iter()
+            .enumerate()
+            .filter_map(|(i, info)| pagemap::is_copied(info).then_some(i as u64))
+            .collect())
+    }
+
+    /// Returns the page numbers, relative to the start of `map`,
+    /// of the pages dirtied since the last checkpoint
+    fn dirty_pages(&self, map: &MemoryMap) -> Result<Vec<u64>, Box<dyn Error>> {
+        let infos = pagemap::page_infos(&self.procfs, map.address)?;
+        Ok(infos
+            .iter()
+            .enumerate()
+            .filter_map(|(i, info)| pagemap::is_soft_dirty(info).then_some(i as u64))
+            .collect())
+    }
+
+    /// Makes the stopped process clone itself without sharing its memory,
+    /// so that the copy-on-write child holds a snapshot of the parent's memory.
+    ///
+    /// The child is traced from its first instruction, so it never runs.
+    fn fork_snapshot(&self, ptrace: &PTrace, gadget: u64) -> Result<PTrace, Box<dyn Error>> {
+        ptrace.set_options(PTRACE_O_TRACECLONE)?;
+
+        // No flags and no exit signal, so the parent never hears about
+        // the child and a plain `wait` in the parent won't reap it
+        let child = ptrace.syscall(gadget, SYS_clone, &[0, 0, 0, 0, 0])?;
+        if child < 0 {
+            return Err(io::Error::from_raw_os_error(-child as i32).into());
+        }
+
+        let child = PTrace {
+            pid: child as pid_t,
+            attached: true,
+        };
+        child.wait_pause()?;
+
+        Ok(child)
+    }
+
+    /// Reaps the snapshot children left behind by earlier checkpoints.
+    ///
+    /// They are children of the tracee, so only it can reap them,
+    /// which we can only make it do while it is stopped.
+    fn reap_snapshots(&mut self, ptrace: &PTrace, gadget: u64) -> Result<(), Box<dyn Error>> {
+        for pid in self.snapshots.drain(..) {
+            let res = ptrace.syscall(gadget, SYS_wait4, &[pid as u64, 0, __WALL as u64, 0])?;
+            debug!("Reaped snapshot process {pid} ({res})");
+        }
+
+        Ok(())
+    }
+
     pub fn volatile_checkpoint(&mut self) -> Result<VolatileCheckpoint, Box<dyn Error>> {
-        let maps = self.procfs.maps()?;
-        let mut mems = vec![];
-        let mut reusable_mems = vec![];
-        let mut ptrace = PTrace::new(self.procfs.pid); // TODO: make this a member of self
-        ptrace.attach()?;
-        ptrace.wait_pause()?;
-        info!("Attached ptrace");
+        let pause_start = Instant::now();
 
-        let regs = ptrace.get_regs()?;
+        let tasks = attach_all(&self.procfs)?;
+        let (mut v_cp, tasks) = self.capture(tasks, pause_start)?;
 
-        let mut files = vec![]; // I want try_collect
-        for file in self.procfs.fd()? {
-            let file = file?;
+        if !tasks.is_empty() {
+            detach_all(tasks)?;
+            v_cp.pause_time = pause_start.elapsed();
+        }
+
+        Ok(v_cp)
+    }
 
-            // I feel like procfs should do this for me
-            // Also because it doesn't I should probably make my own
-            // fdinfo type struct so I don't have to have this vector of tuples
-            let fdinfo_raw = read_to_string(format!("/proc/{}/fdinfo/{:?}", ptrace.pid, file.fd))?;
-            let offset: u64 = fdinfo_raw
-                .strip_prefix("pos:\t")
-                .unwrap()
-                .lines()
-                .next()
-                .unwrap()
-                .parse()?;
+    /// Takes the in memory checkpoint of the process, whose threads `tasks` (from `attach_all`)
+    /// were all stopped at `pause_start`.
+    ///
+    /// Returns the threads that are still attached, which are all of them unless this
+    /// was a copy-on-write checkpoint, so the caller decides when the process resumes.
+    fn capture(
+        &mut self,
+        mut tasks: Vec<PTrace>,
+        pause_start: Instant,
+    ) -> Result<(VolatileCheckpoint, Vec<PTrace>), Box<dyn Error>> {
+        let maps = vma::maps(&self.procfs)?;
+        let mut ptrace = tasks.remove(0); // TODO: make this a member of self
+        info!("Attached ptrace to {} threads", tasks.len() + 1);
 
-            files.push((file, offset));
+        // We can do without it unless we have to make the process fork or reap
+        let gadget = vdso::syscall_gadget(&self.mem, &maps.0);
+        let gadget = match self.config.cow || !self.snapshots.is_empty() {
+            true => Some(gadget?),
+            false => gadget.ok(),
+        };
+
+        if let Some(gadget) = gadget {
+            self.reap_snapshots(&ptrace, gadget)?;
         }
 
-        let mut checkpointed_maps = vec![];
+        let mut threads = vec![Thread::capture(&self.procfs, &ptrace, &self.mem, gadget)?];
+        for task in &tasks {
+            threads.push(Thread::capture(&self.procfs, task, &self.mem, gadget)?);
+        }
+        let signals = Signals::capture(&self.procfs, &ptrace, &self.mem, gadget)?;
+        let attrs = ProcAttrs::capture(&self.procfs)?;
+        let mm = MmFields::capture(&self.procfs, &ptrace, gadget, &maps.0)?;
+
+        let mut files = vec![]; // I want try_collect
+        for file in self.procfs.fd()? {
+            files.push(OpenFile::capture(&self.procfs, file?)?);
+        }
+        let fds = FdDump::capture_all(&self.procfs, &files)?;
+        let anon_files = AnonFile::capture_all(&self.procfs, &files, &maps.0)?;
+        let shared_maps = SharedMap::capture_all(&self.procfs, &maps.0)?;
+        let file_snapshots = match &self.config.snapshot_files {
+            Some(config) => {
+                FileSnapshot::capture_all(self.procfs.pid, &files, config, self.config.compression)?
+            }
+            None => vec![],
+        };
+
+        let mut predumped = std::mem::take(&mut self.predumped);
+
+        // First decide how each memory region gets saved, which
+        // has to happen while the process is stopped
+        let mut plans = vec![];
         for map in maps {
-            // TODO: this is a way of avoiding checkpointing the bootstrapper's memory
-            match map.pathname {
-                MMapPath::Path(path)
-                    if path
-                        .to_str()
-                        .filter(|p| p.contains(restore::BS_GUID))
-                        .is_some() =>
-                {
-                    debug!("Ignoring bootstrapper memory mapping {path:?}");
-                    continue;
-                }
-                _ => {}
+            if is_bootstrapper(&map) {
+                debug!("Ignoring bootstrapper memory mapping {:?}", map.pathname);
+                continue;
+            }
+
+            if vdso::is_vdso(&map) {
+                plans.push((map, MemPlan::Kernel));
+                continue;
+            }
+
+            // PROT_NONE regions are usually reservations or guard pages
+            // that were never touched, so there's nothing to read. Unless
+            // the process wrote to them before taking the access away, then
+            // we read those pages anyway (/proc/pid/mem doesn't mind)
+            if !map.perms.contains(MMPermissions::READ) && !is_kernel_mapping(&map) {
+                let written = match map.perms.contains(MMPermissions::PRIVATE) {
+                    true => self.written_pages(&map)?,
+                    false => vec![],
+                };
+                let plan = if written.is_empty() {
+                    MemPlan::Reserve
+                } else if matches!(map.pathname, MMapPath::Path(_)) {
+                    self.overlay_plan(&map)?.unwrap_or(MemPlan::Full)
+                } else {
+                    MemPlan::Sparse(written)
+                };
+                plans.push((map, plan));
+                continue;
+            }
+
+            if vma::dont_dump(&map) && !self.config.dump_dontdump {
+                plans.push((map, MemPlan::Skip));
+                continue;
+            }
+
+            if let Some(plan) = self.overlay_plan(&map)? {
+                plans.push((map, plan));
+                continue;
             }
 
             let immutable = !map.perms.contains(MMPermissions::WRITE);
 
             if immutable {
-                // It seems like there are some parts of an ELF file that will
-                // end up in a read only memory mapping but differ from the on disk
-                // version of the file, so for now we comment this out
-                // if matches!(map.pathname, MMapPath::Path(_)) {
-                //     debug!(
-                //         "ignoring memory region {:?} @ {:x?}, it is an immutable file",
-                //         map.pathname, map.address
-                //     );
-                //     continue;
-                // }
-
                 if let Some(old) = self
                     .step
                     .last_maps
                     .iter()
                     .enumerate()
                     .find_map(|(j, m)| (m == &map).then_some(j))
+                    .filter(|old| !self.step.last_reservations.contains(old))
                 {
-                    let new = checkpointed_maps.len();
+                    plans.push((map, MemPlan::Reuse(old)));
+                    continue;
+                }
+            } else if let Some(i) = predumped.iter().position(|(m, _)| m == &map) {
+                let (_, image) = predumped.swap_remove(i);
+                let pages = self.dirty_pages(&map)?;
+                plans.push((map, MemPlan::Patch(image, pages)));
+                continue;
+            } else if self.dirty_tracking {
+                let parent = self
+                    .step
+                    .last_maps
+                    .iter()
+                    .position(|m| m == &map)
+                    .map(|old| {
+                        let depth = self
+                            .step
+                            .last_deltas
+                            .iter()
+                            .find_map(|d| (d.index == old).then_some(d.depth))
+                            .unwrap_or(0);
+                        (old, depth)
+                    })
+                    .filter(|(old, depth)| {
+                        *depth < self.config.max_chain && !self.step.last_reservations.contains(old)
+                    });
+
+                if let Some((parent, depth)) = parent {
+                    let pages = self.dirty_pages(&map)?;
+                    plans.push((
+                        map,
+                        MemPlan::Delta(DeltaImage {
+                            index: 0,
+                            parent,
+                            depth: depth + 1,
+                            pages,
+                        }),
+                    ));
+                    continue;
+                }
+            }
+
+            // Pages of private anonymous memory that aren't there (or swapped out)
+            // were never touched, so they are just zeros we don't have to copy.
+            // That doesn't hold for file mappings, where they'd come from the file.
+            let anonymous = matches!(
+                map.pathname,
+                MMapPath::Anonymous | MMapPath::Heap | MMapPath::Stack | MMapPath::TStack(_)
+            ) && map.perms.contains(MMPermissions::PRIVATE);
+
+            if anonymous {
+                let infos = pagemap::page_infos(&self.procfs, map.address)?;
+                let pages: Vec<u64> = infos
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(i, info)| pagemap::is_resident(info).then_some(i as u64))
+                    .collect();
+
+                if pages.len() < infos.len() {
+                    plans.push((map, MemPlan::Sparse(pages)));
+                    continue;
+                }
+            }
+
+            plans.push((map, MemPlan::Full));
+        }
+
+        if self.config.incremental {
+            if !self.dirty_tracking && !pagemap::soft_dirty_supported()? {
+                return Err("incremental checkpoints need a kernel with soft-dirty tracking (CONFIG_MEM_SOFT_DIRTY)".into());
+            }
+
+            // Clear the soft-dirty bits while the process is still stopped,
+            // so that the next checkpoint sees every write made after this one
+            pagemap::clear_soft_dirty(&self.procfs)?;
+            self.dirty_tracking = true;
+        }
+
+        // In copy-on-write mode we let the process go as soon as it has forked,
+        // and copy its memory out of the frozen child instead
+        let mut snapshot = match gadget.filter(|_| self.config.cow) {
+            Some(gadget) => Some(self.fork_snapshot(&ptrace, gadget)?),
+            None => None,
+        };
+
+        let snapshot_mem = match &snapshot {
+            Some(child) => {
+                debug!("Copying memory from snapshot process {}", child.pid);
+                for mut task in tasks.drain(..) {
+                    task.detach()?;
+                }
+                ptrace.detach()?;
+                Some(MemReader::new(&Process::new(child.pid)?)?)
+            }
+            None => None,
+        };
+        let reader = snapshot_mem.as_ref().unwrap_or(&self.mem);
+        let pause_time = pause_start.elapsed();
+
+        // Read everything we need in one batch
+        let requests: Vec<_> = plans
+            .iter()
+            .map(|(map, plan)| match plan {
+                MemPlan::Reserve | MemPlan::Kernel | MemPlan::Skip | MemPlan::Reuse(_) => vec![],
+                MemPlan::Full => vec![(map.address.0, (map.address.1 - map.address.0) as usize)],
+                MemPlan::Delta(DeltaImage { pages, .. })
+                | MemPlan::Patch(_, pages)
+                | MemPlan::Sparse(pages)
+                | MemPlan::Overlay(_, _, pages) => memory::page_ranges(map, pages),
+            })
+            .collect();
+        let reads = reader.read_batch_partial(&requests);
+
+        let mut mems = vec![];
+        let mut reusable_mems = vec![];
+        let mut deltas = vec![];
+        let mut checkpointed_maps = vec![];
+        let mut extents = vec![];
+        let mut overlays = vec![];
+        let mut reservations = vec![];
+        let mut bytes_copied = 0;
+        for ((map, plan), read) in plans.into_iter().zip(reads) {
+            let new = checkpointed_maps.len();
+
+            // Pages that can't be read would come back as zeros, so the region is
+            // reserved instead, like one that can't be read at all
+            let mem = read.and_then(|read| match read.unreadable.as_slice() {
+                [] => Ok(read.mem),
+                unreadable => {
                     debug!(
-                            "reusing old_maps[{old}] for memory region maps[{new}] = {:?}, it is immutable and already checkpointed",
-                            map.pathname
-                        );
+                        "maps[{new}] = {:?} can't be read at {unreadable:x?}",
+                        map.pathname
+                    );
+                    Err(io::Error::from_raw_os_error(EIO))
+                }
+            });
+
+            let mem = match plan {
+                MemPlan::Reserve => {
+                    debug!(
+                        "reserving memory region maps[{new}] = {:?}, it isn't readable",
+                        map.pathname
+                    );
+
+                    reservations.push(new);
+                    checkpointed_maps.push(map);
+                    continue;
+                }
+                MemPlan::Kernel => {
+                    debug!(
+                        "not saving memory region maps[{new}] = {:?}, it comes from the kernel",
+                        map.pathname
+                    );
+
+                    checkpointed_maps.push(map);
+                    continue;
+                }
+                MemPlan::Skip => {
+                    debug!(
+                        "not saving memory region maps[{new}] = {:?}, it is marked MADV_DONTDUMP",
+                        map.pathname
+                    );
+
+                    checkpointed_maps.push(map);
+                    continue;
+                }
+                MemPlan::Reuse(old) => {
+                    debug!(
+                        "reusing old_maps[{old}] for memory region maps[{new}] = {:?}, it is immutable and already checkpointed",
+                        map.pathname
+                    );
 
                     reusable_mems.push((new, old));
                     checkpointed_maps.push(map);
                     continue;
                 }
-            }
+                MemPlan::Overlay(mut overlay, source, pages) => {
+                    let mem = mem?;
+                    bytes_copied += mem.len() as u64;
+                    let differ = overlay.diff(&map, &source, &pages, &mem)?;
+                    debug!(
+                        "only saving the {} of {} written pages of memory region maps[{new}] = {:?} that differ from the file",
+                        overlay.pages.len(),
+                        pages.len(),
+                        map.pathname
+                    );
 
-            let mut mem = vec![];
-            (&self.mem_file).seek(SeekFrom::Start(map.address.0))?;
-            match (&self.mem_file)
-                .take(map.address.1 - map.address.0)
-                .read_to_end(&mut mem)
-            {
-                Err(e) if e.raw_os_error() == Some(5) => {
+                    if !overlay.pages.is_empty() {
+                        let ranges = memory::page_ranges(&map, &overlay.pages)
+                            .into_iter()
+                            .map(|(addr, len)| (addr - map.address.0, len as u64))
+                            .collect();
+                        extents.push((new, ranges));
+                        mems.push((new, differ));
+                    }
+
+                    overlays.push(FileOverlay {
+                        index: new,
+                        ..overlay
+                    });
+                    checkpointed_maps.push(map);
+                    continue;
+                }
+                MemPlan::Full => mem.inspect(|mem| {
+                    bytes_copied += mem.len() as u64;
+                }),
+                MemPlan::Sparse(pages) => mem.inspect(|mem| {
+                    debug!(
+                        "only saving the {} resident pages of memory region maps[{new}] = {:?}",
+                        pages.len(),
+                        map.pathname
+                    );
+
+                    bytes_copied += mem.len() as u64;
+                    let ranges = memory::page_ranges(&map, &pages)
+                        .into_iter()
+                        .map(|(addr, len)| (addr - map.address.0, len as u64))
+                        .collect();
+                    extents.push((new, ranges));
+                }),
+                MemPlan::Patch(mut image, pages) => {
+                    mem.map(|mem| {
+                        debug!(
+                            "patching {} dirty pages into the pre-dumped memory region maps[{new}] = {:?}",
+                            pages.len(),
+                            map.pathname
+                        );
+
+                        bytes_copied += mem.len() as u64;
+                        pagemap::patch_pages(&mut image, &pages, &mem);
+                        image
+                    })
+                }
+                MemPlan::Delta(delta) => {
+                    if let Ok(mem) = &mem {
+                        bytes_copied += mem.len() as u64;
+                        debug!(
+                            "saving {} dirty pages of memory region maps[{new}] = {:?} on top of old_maps[{}]",
+                            delta.pages.len(),
+                            map.pathname,
+                            delta.parent,
+                        );
+
+                        deltas.push(DeltaImage {
+                            index: new,
+                            ..delta
+                        });
+                    }
+
+                    mem
+                }
+            };
+
+            let mem = match mem {
+                Err(e) if e.raw_os_error() == Some(EIO) && is_kernel_mapping(&map) => {
                     debug!(
                         "ignoring memory region map {:?} due to read error",
                         map.pathname
                     );
                     continue;
                 }
-                res => {
-                    res?;
+                Err(e) if e.raw_os_error() == Some(EIO) => {
+                    debug!(
+                        "reserving memory region maps[{new}] = {:?} due to read error",
+                        map.pathname
+                    );
+
+                    reservations.push(new);
+                    checkpointed_maps.push(map);
+                    continue;
                 }
+                res => res?,
             };
 
-            let new = checkpointed_maps.len();
             debug!("saving memory region maps[{new}] = {:?}", map.pathname);
             mems.push((new, mem));
             checkpointed_maps.push(map);
         }
 
-        // This is redundant because the process should 
-        // get resumed when `ptrace` is dropped anyways.
-        ptrace.resume()?;
+        if let Some(child) = &mut snapshot {
+            child.signal(SIGKILL)?;
+            child.wait(__WALL)?;
+            child.attached = false;
+            self.snapshots.push(child.pid);
+        } else {
+            tasks.insert(0, ptrace);
+        }
 
-        Ok(VolatileCheckpoint {
-            regs,
+        let v_cp = VolatileCheckpoint {
+            threads,
+            signals,
+            attrs,
+            mm,
             files,
+            fds,
+            file_snapshots,
+            anon_files,
+            overlays,
+            shared_maps,
             maps: checkpointed_maps,
             mems,
             reusable_mems,
-        })
+            deltas,
+            extents,
+            reservations,
+            tree: vec![],
+            pause_time,
+            bytes_copied,
+        };
+
+        Ok((v_cp, tasks))
+    }
+
+    /// Copies the writable memory of the process while it keeps running, in rounds.
+    ///
+    /// The first round copies everything, and every later round only copies the
+    /// pages dirtied during the round before it. This goes on until a round is small
+    /// enough or stops shrinking, so that the next checkpoint only has to stop the
+    /// process for long enough to copy the last few dirty pages.
+    pub fn predump(&mut self) -> Result<Predump, Box<dyn Error>> {
+        if !pagemap::soft_dirty_supported()? {
+            return Err(
+                "pre-dumping needs a kernel with soft-dirty tracking (CONFIG_MEM_SOFT_DIRTY)"
+                    .into(),
+            );
+        }
+
+        let mut images: Vec<(MemoryMap, Vec<u8>)> = vec![];
+        let mut rounds = vec![];
+        let mut pause_time = Duration::ZERO;
+        while (rounds.len() as u64) < self.config.predump_rounds {
+            // The soft-dirty bits are about to stop meaning "written since the last checkpoint"
+            self.dirty_tracking = false;
+
+            let maps: Vec<_> = vma::maps(&self.procfs)?
+                .into_iter()
+                .filter(|m| m.perms.contains(MMPermissions::WRITE) && !is_bootstrapper(m))
+                .collect();
+
+            // Work out what to copy this round. After the first round, reading the dirty
+            // pages and clearing their bits has to happen with the process stopped,
+            // otherwise a write in between the two would be lost.
+            let mut dirty = vec![];
+            if images.is_empty() {
+                pagemap::clear_soft_dirty(&self.procfs)?;
+            } else {
+                let pause_start = Instant::now();
+                let threads = attach_all(&self.procfs)?;
+
+                for map in &maps {
+                    dirty.push(self.dirty_pages(map)?);
+                }
+                pagemap::clear_soft_dirty(&self.procfs)?;
+
+                for mut thread in threads {
+                    thread.detach()?;
+                }
+                pause_time += pause_start.elapsed();
+            }
+
+            let mut bytes = 0;
+            let mut old_images = vec![];
+            let mut requests = vec![];
+            for (i, map) in maps.iter().enumerate() {
+                let old = images
+                    .iter()
+                    .position(|(m, _)| m == map)
+                    .map(|j| images.swap_remove(j).1);
+
+                requests.push(match (&old, dirty.get(i)) {
+                    (Some(_), Some(pages)) => memory::page_ranges(map, pages),
+                    _ => vec![(map.address.0, (map.address.1 - map.address.0) as usize)],
+                });
+                old_images.push(old);
+            }
+
+            let reads = self.mem.read_batch(&requests);
+
+            let mut new_images = vec![];
+            for (i, ((map, old), mem)) in maps.into_iter().zip(old_images).zip(reads).enumerate() {
+                let mem = match mem {
+                    Err(e) if e.raw_os_error() == Some(EIO) => continue,
+                    mem => mem?,
+                };
+                bytes += mem.len() as u64;
+
+                let image = match (old, dirty.get(i)) {
+                    (Some(mut image), Some(pages)) => {
+                        pagemap::patch_pages(&mut image, pages, &mem);
+                        image
+                    }
+                    _ => mem,
+                };
+                new_images.push((map, image));
+            }
+            images = new_images;
+
+            debug!("Pre-dump round {} copied {bytes} bytes", rounds.len());
+
+            let shrinking = rounds.last().is_none_or(|&last| bytes < last);
+            rounds.push(bytes);
+
+            if bytes <= self.config.predump_threshold || !shrinking {
+                break;
+            }
+        }
+
+        info!("Pre-dumped memory in {} rounds", rounds.len());
+        self.predumped = images;
+
+        Ok(Predump { rounds, pause_time })
     }
 
     pub fn checkpoint(&mut self) -> Result<Duration, Box<dyn Error>> {
+        if self.config.tree {
+            return self.checkpoint_tree();
+        }
+
+        self.start_checkpoint()?;
+        let v_cp = self.volatile_checkpoint()?;
+        info!("Created in memory checkpoint");
+
+        self.persist(v_cp)
+    }
+
+    /// Checkpoints the process and all of its descendants, see `CheckpointConfig::tree`
+    fn checkpoint_tree(&mut self) -> Result<Duration, Box<dyn Error>> {
+        let pause_start = Instant::now();
+
+        // Freeze the whole tree before taking anything from it. A frozen process
+        // can't fork, so once a pass over the tree finds nothing new we have all of it.
+        let mut frozen = vec![(self.procfs.pid, 0, attach_all(&self.procfs)?)];
+        loop {
+            let mut found = false;
+            for (pid, parent) in tree::descendants(self.procfs.pid)? {
+                if frozen.iter().any(|(p, ..)| *p == pid) {
+                    continue;
+                }
+
+                let tasks = Process::new(pid)
+                    .map_err(Into::into)
+                    .and_then(|procfs| attach_all(&procfs));
+                match tasks {
+                    Ok(tasks) => frozen.push((pid, parent, tasks)),
+                    Err(_) if !tree::is_alive(pid) => continue,
+                    Err(e) => return Err(e),
+                }
+                found = true;
+            }
+
+            if !found {
+                break;
+            }
+        }
+        info!("Froze a tree of {} processes", frozen.len());
+
+        // Every descendant gets a checkpointer, and a checkpoint path, of its own.
+        // A pid can be reused by the time we get back to it, so they only
+        // carry over if the process also started at the same time.
+        let mut start_times = vec![];
+        for (pid, ..) in frozen.iter().skip(1) {
+            start_times.push((*pid, Process::new(*pid)?.stat()?.starttime));
+        }
+        self.descendants
+            .retain(|cp| start_times.contains(&(cp.procfs.pid, cp.start_time)));
+        for (pid, ..) in frozen.iter().skip(1) {
+            if self.descendants.iter().any(|cp| cp.procfs.pid == *pid) {
+                continue;
+            }
+
+            let path = self.path.join(PROCS_DIR).join(pid.to_string());
+            create_dir_all(&path)?;
+
+            let mut cp = Checkpointer::attach(*pid, path)?;
+            cp.config = CheckpointConfig {
+                tree: false,
+                ..self.config.clone()
+            };
+            // Whatever was checkpointed there before was another process with this pid,
+            // so none of it can be reused. Its checkpoints stay for the ones that refer to them.
+            cp.step.forget_last();
+            self.descendants.push(cp);
+        }
+
+        let mut captured = vec![];
+        let mut attached = vec![];
+        for (pid, parent, tasks) in frozen {
+            let cp = match parent {
+                0 => &mut *self,
+                _ => self.descendant(pid),
+            };
+
+            cp.start_checkpoint()?;
+            let (v_cp, tasks) = cp.capture(tasks, pause_start)?;
+            captured.push((pid, parent, v_cp));
+            attached.push(tasks);
+        }
+
+        // Fds that are shared between processes are only restored once, and then passed around
+        fd::link_shared(
+            captured
+                .iter_mut()
+                .map(|(pid, _, v_cp)| (*pid, &mut v_cp.fds))
+                .collect(),
+        );
+
+        // Only let the tree go once all of it is captured, so that it's one consistent snapshot
+        for tasks in attached.into_iter().filter(|tasks| !tasks.is_empty()) {
+            detach_all(tasks)?;
+        }
+        let pause_time = match self.config.cow {
+            true => captured.iter().map(|(.., v_cp)| v_cp.pause_time).max(),
+            false => None,
+        }
+        .unwrap_or_else(|| pause_start.elapsed());
+        info!("Created in memory checkpoints");
+
+        // The root goes last, since its manifest points at everyone else's checkpoints
+        let mut nodes = vec![TreeNode {
+            pid: self.procfs.pid,
+            parent: 0,
+            seq: self.step.seq,
+        }];
+        let mut root = None;
+        for (pid, parent, v_cp) in captured {
+            if parent == 0 {
+                root = Some(v_cp);
+                continue;
+            }
+
+            let cp = self.descendant(pid);
+            cp.persist(v_cp)?;
+            nodes.push(TreeNode {
+                pid,
+                parent,
+                seq: cp.step.seq,
+            });
+        }
+
+        let mut root = root.unwrap();
+        root.tree = nodes;
+        root.pause_time = pause_time;
+        self.persist(root)
+    }
+
+    fn descendant(&mut self, pid: pid_t) -> &mut Checkpointer {
+        self.descendants
+            .iter_mut()
+            .find(|cp| cp.procfs.pid == pid)
+            .unwrap()
+    }
+
+    /// Checks that the checkpoint directory can take the checkpoint we're about to make,
+    /// and moves on to its sequence number
+    fn start_checkpoint(&mut self) -> Result<(), Box<dyn Error>> {
+        if self.step.seq == 0 {
+            self.config.compression.save(&self.path)?;
+        } else if self.config.compression.algorithm != self.step.compression.algorithm {
+            // The level only matters when compressing, so that can change
+            return Err(format!(
+                "checkpoint directory {:?} was created with {:?} compression, not {:?}",
+                self.path, self.step.compression.algorithm, self.config.compression.algorithm
+            )
+            .into());
+        }
+        self.step.compression = self.config.compression;
+
         self.step.seq = self.step.seq.wrapping_add(1);
         info!("Starting a checkpoint");
 
-        let pause_start = Instant::now();
-        let v_cp = self.volatile_checkpoint()?;
-        let pause_time = pause_start.elapsed();
+        Ok(())
+    }
 
-        info!("Created in memory checkpoint");
+    /// Writes the in memory checkpoint `v_cp` to disk, once the process is resumed,
+    /// and returns how long the process was stopped for it
+    fn persist(&mut self, v_cp: VolatileCheckpoint) -> Result<Duration, Box<dyn Error>> {
+        let pause_time = v_cp.pause_time;
 
-        // Now that the process is resumed we can persist the checkpoint to disk
         let cp_dir = self.path.join(self.step.seq.to_string());
         info!("Checkpointing to {cp_dir:?}");
 
-        maybe_remove_dir_all(&cp_dir)?;
+        self.clean_checkpoints([self.step.seq])?;
         create_dir(&cp_dir)?;
 
+        let mut sparse = vec![];
+        let mut chunked = vec![];
+        let mut bytes_written = (0, 0);
         for (i, mem) in v_cp.mems {
             debug!("Writing maps[{i}]");
+            let path = cp_dir.join(i.to_string());
+
+            if v_cp.deltas.iter().any(|d| d.index == i) {
+                // The store takes the dirty pages as they're concatenated in the image
+                if self.config.dedup {
+                    let len = mem.len() as u64;
+                    let store = self.store()?;
+                    let chunks = store.put_image(len, &mem, &[(0, len)])?;
+                    bytes_written.0 += store.bytes_written.0;
+                    bytes_written.1 += store.bytes_written.1;
+                    store.bytes_written = (0, 0);
+
+                    chunked.push(ChunkedImage {
+                        index: i,
+                        len,
+                        chunks,
+                    });
+                    continue;
+                }
+
+                let data = self.step.compression.compress(&mem)?;
+                bytes_written.0 += mem.len() as u64;
+                bytes_written.1 += data.len() as u64;
+
+                // syncing: this should fsync before it returns
+                write(path, data)?;
+                continue;
+            }
+
+            let map = &v_cp.maps[i];
+            let len = map.address.1 - map.address.0;
+            let extents = v_cp
+                .extents
+                .iter()
+                .find_map(|(j, extents)| (*j == i).then_some(extents.clone()))
+                .unwrap_or_else(|| vec![(0, len)]);
+
+            if self.config.dedup {
+                let store = self.store()?;
+                let chunks = store.put_image(len, &mem, &extents)?;
+                bytes_written.0 += store.bytes_written.0;
+                bytes_written.1 += store.bytes_written.1;
+                store.bytes_written = (0, 0);
+
+                chunked.push(ChunkedImage {
+                    index: i,
+                    len,
+                    chunks,
+                });
+                continue;
+            }
 
-            // syncing: this should fsync before it returns
-            write(cp_dir.join(i.to_string()), mem)?;
+            let (written, written_len) =
+                write_image(&path, len, &mem, &extents, &self.step.compression)?;
+            bytes_written.0 += written.iter().map(|(_, len)| len).sum::<u64>();
+            bytes_written.1 += written_len;
+
+            if written != [(0, len)] {
+                sparse.push(SparseImage {
+                    index: i,
+                    extents: written,
+                });
+            }
         }
 
         for (new, old) in v_cp.reusable_mems {
+            if let Some(image) = self.step.last_chunks.iter().find(|c| c.index == old) {
+                debug!("Referencing the chunks of old_maps[{old}] for maps[{new}]");
+
+                let image = ChunkedImage {
+                    index: new,
+                    ..image.clone()
+                };
+                self.store()?.add_refs(&image.chunks);
+                chunked.push(image);
+                continue;
+            }
+
             debug!("Linking maps[{new}] = old_maps[{old}]");
 
+            if let Some(image) = self.step.last_sparse.iter().find(|s| s.index == old) {
+                sparse.push(SparseImage {
+                    index: new,
+                    extents: image.extents.clone(),
+                });
+            }
+
             hard_link(
                 self.path
                     .join((self.step.seq - 1).to_string())
@@ -238,11 +1395,67 @@ impl Checkpointer {
             )?;
         }
 
+        // The chunk store counts its references from this file,
+        // so it must never be left half written
+        write(cp_dir.join("chunks.tmp"), serde_json::to_vec(&chunked)?)?;
+        rename(cp_dir.join("chunks.tmp"), cp_dir.join("chunks"))?;
+
         // syncing: the scope ensures that the File structs are dropped at thus fsynced
         {
-            serde_json::to_writer(File::create(cp_dir.join("regs"))?, &v_cp.regs)?;
+            serde_json::to_writer(File::create(cp_dir.join("threads"))?, &v_cp.threads)?;
+            serde_json::to_writer(File::create(cp_dir.join("signals"))?, &v_cp.signals)?;
+            serde_json::to_writer(File::create(cp_dir.join("proc"))?, &v_cp.attrs)?;
+            serde_json::to_writer(File::create(cp_dir.join("mm"))?, &v_cp.mm)?;
             serde_json::to_writer(File::create(cp_dir.join("maps"))?, &v_cp.maps)?;
             serde_json::to_writer(File::create(cp_dir.join("files"))?, &v_cp.files)?;
+            serde_json::to_writer(File::create(cp_dir.join("fds"))?, &v_cp.fds)?;
+            if !v_cp.file_snapshots.is_empty() {
+                FileSnapshot::persist_all(&v_cp.file_snapshots, &cp_dir)?;
+            }
+            if !v_cp.anon_files.is_empty() {
+                AnonFile::persist_all(&v_cp.anon_files, &cp_dir)?;
+            }
+            if !v_cp.overlays.is_empty() {
+                let mut overlays = v_cp.overlays;
+                for overlay in &mut overlays {
+                    let map = &v_cp.maps[overlay.index];
+                    let range = (map.offset, map.address.1 - map.address.0);
+                    overlay.hash = FileOverlay::hash(
+                        &overlay.path,
+                        &overlay.identity,
+                        range,
+                        &mut self.file_hashes,
+                    )?;
+                }
+                FileOverlay::persist_all(&overlays, &cp_dir)?;
+            }
+            if !v_cp.shared_maps.is_empty() {
+                SharedMap::persist_all(&v_cp.shared_maps, &cp_dir)?;
+            }
+            serde_json::to_writer(File::create(cp_dir.join("sparse"))?, &sparse)?;
+            serde_json::to_writer(
+                File::create(cp_dir.join("reservations"))?,
+                &v_cp.reservations,
+            )?;
+
+            if self.config.incremental {
+                let base = v_cp
+                    .deltas
+                    .iter()
+                    .map(|d| self.step.seq - d.depth)
+                    .min()
+                    .unwrap_or(self.step.seq);
+
+                let deltas = Deltas {
+                    base,
+                    images: v_cp.deltas.clone(),
+                };
+                serde_json::to_writer(File::create(cp_dir.join("deltas"))?, &deltas)?;
+            }
+
+            if !v_cp.tree.is_empty() {
+                serde_json::to_writer(File::create(cp_dir.join("tree"))?, &v_cp.tree)?;
+            }
         }
 
         self.step
@@ -254,11 +1467,49 @@ impl Checkpointer {
         self.step.seq_file.sync_all()?;
 
         self.step.last_maps = v_cp.maps;
+        self.step.last_deltas = v_cp.deltas;
+        self.step.last_sparse = sparse;
+        self.step.last_chunks = chunked;
+        self.step.last_reservations = v_cp.reservations;
+        self.step.last_bytes_copied = v_cp.bytes_copied;
+        self.step.last_bytes_written = bytes_written;
 
         info!("Completed checkpoint");
         Ok(pause_time)
     }
 
+    /// Ends a line of stats, with the compression ratio of the last checkpoint if it was compressed
+    pub fn write_ratio(&self, mut stats: impl Write) -> io::Result<()> {
+        if self.step.compression.is_none() {
+            return writeln!(stats);
+        }
+
+        let (raw, compressed) = self.step.last_bytes_written;
+        writeln!(stats, ",{:.3}", raw as f64 / compressed.max(1) as f64)
+    }
+
+    /// Writes the stats of a checkpoint that `predump` ran before, short of the line end
+    pub fn write_predump_stats(
+        &self,
+        mut stats: impl Write,
+        predump: &Predump,
+        vcp_time: Duration,
+        cp_time: Duration,
+    ) -> io::Result<()> {
+        let paused_time = vcp_time + predump.pause_time;
+        let rounds: Vec<_> = predump.rounds.iter().map(u64::to_string).collect();
+
+        write!(
+            stats,
+            "{},{},{},{},{}",
+            paused_time.as_nanos(),
+            cp_time.as_nanos(),
+            rounds.len(),
+            rounds.join(","),
+            self.step.last_bytes_copied,
+        )
+    }
+
     pub fn run(
         &mut self,
         period: Duration,
@@ -275,7 +1526,33 @@ impl Checkpointer {
 
             if let Some(stats) = &mut stats {
                 let cp_time = start.elapsed();
-                writeln!(stats, "{},{}", vcp_time.as_nanos(), cp_time.as_nanos())?;
+                write!(stats, "{},{}", vcp_time.as_nanos(), cp_time.as_nanos())?;
+                self.write_ratio(stats)?;
+            }
+
+            wait_time = period.saturating_sub(start.elapsed());
+        }
+    }
+
+    /// Like `run`, but pre-dumps the process' memory before every checkpoint
+    pub fn run_predump(
+        &mut self,
+        period: Duration,
+        max_cps: u64,
+        mut stats: Option<impl Write>,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut wait_time = period;
+        loop {
+            thread::sleep(wait_time);
+            let start = Instant::now();
+
+            let predump = self.predump()?;
+            let vcp_time = self.checkpoint()?;
+            self.cull_checkpoints(max_cps)?;
+
+            if let Some(stats) = &mut stats {
+                self.write_predump_stats(&mut *stats, &predump, vcp_time, start.elapsed())?;
+                self.write_ratio(stats)?;
             }
 
             wait_time = period.saturating_sub(start.elapsed());
@@ -300,12 +1577,13 @@ impl Checkpointer {
 
             let paused_time = self.checkpoint()?;
             self.cull_checkpoints(max_cps)?;
-            
+
             if let Some(stats) = &mut stats {
                 let cp_time = start.elapsed();
-                writeln!(stats, "{},{}", paused_time.as_nanos(), cp_time.as_nanos())?;
+                write!(stats, "{},{}", paused_time.as_nanos(), cp_time.as_nanos())?;
+                self.write_ratio(stats)?;
             }
-            
+
             let cp_time = start.elapsed();
 
             // Calculate how long we should let the process run freely
@@ -331,12 +1609,64 @@ impl Checkpointer {
     }
 
     pub fn cull_checkpoints(&mut self, max_cps: u64) -> Result<(), Box<dyn Error>> {
+        // A descendant only ever gets checkpointed along with us, so its last `max_cps`
+        // checkpoints cover everything our last `max_cps` checkpoints refer to
+        for cp in &mut self.descendants {
+            cp.cull_checkpoints(max_cps)?;
+        }
+
         if self.step.seq < max_cps {
             return Ok(());
         }
 
+        // The oldest checkpoint we keep may still have images
+        // built on top of pages from checkpoints before it
+        let oldest = self.step.seq - max_cps + 1;
+        let base = Deltas::open(&self.path.join(oldest.to_string()))?.map_or(oldest, |d| d.base);
+
         // FIXME: this won't work if self.step.seq wraps back around to 0
-        self.clean_checkpoints(0..=self.step.seq - max_cps)
+        self.clean_checkpoints(0..base)?;
+
+        if self.config.tree {
+            self.clean_procs()?;
+        }
+
+        Ok(())
+    }
+
+    /// Removes the checkpoint paths of descendants that have exited
+    /// and that no checkpoint of the tree refers to anymore
+    fn clean_procs(&mut self) -> Result<(), Box<dyn Error>> {
+        let procs = self.path.join(PROCS_DIR);
+        if !procs.exists() {
+            return Ok(());
+        }
+
+        let mut referenced: Vec<_> = self.descendants.iter().map(|cp| cp.procfs.pid).collect();
+        for entry in read_dir(&self.path)? {
+            let entry = entry?;
+            if entry
+                .file_name()
+                .to_str()
+                .is_some_and(|n| n.parse::<u64>().is_ok())
+            {
+                referenced.extend(TreeNode::open_all(&entry.path())?.iter().map(|n| n.pid));
+            }
+        }
+
+        for entry in read_dir(&procs)? {
+            let entry = entry?;
+            let pid = entry
+                .file_name()
+                .to_str()
+                .and_then(|n| n.parse::<pid_t>().ok());
+            if pid.is_some_and(|pid| !referenced.contains(&pid)) {
+                debug!("Removing the checkpoints of exited process {pid:?}");
+                maybe_remove_dir_all(entry.path())?;
+            }
+        }
+
+        Ok(())
     }
 
     pub fn clean_checkpoints(
@@ -344,13 +1674,111 @@ impl Checkpointer {
         range: impl IntoIterator<Item = u64>,
     ) -> Result<(), Box<dyn Error>> {
         for cp in range {
-            maybe_remove_dir_all(self.path.join(cp.to_string()))?
+            let cp_dir = self.path.join(cp.to_string());
+
+            // Drop the checkpoint's references into the chunk store first,
+            // so that chunks nothing else uses get deleted along with it
+            let chunked = match cp_dir.exists() {
+                true => ChunkedImage::open_all(&cp_dir)?,
+                false => vec![],
+            };
+            if !chunked.is_empty() {
+                let store = self.store()?;
+                for image in chunked {
+                    store.release(&image.chunks)?;
+                }
+            }
+
+            maybe_remove_dir_all(cp_dir)?
         }
 
         Ok(())
     }
 }
 
+// TODO: this is a way of avoiding checkpointing the bootstrapper's memory
+fn is_bootstrapper(map: &MemoryMap) -> bool {
+    match &map.pathname {
+        MMapPath::Path(path) => path
+            .to_str()
+            .filter(|p| p.contains(restore::BS_GUID))
+            .is_some(),
+        _ => false,
+    }
+}
+
+/// Whether `map` is one the kernel sets up by itself rather than memory of the process
+fn is_kernel_mapping(map: &MemoryMap) -> bool {
+    matches!(
+        map.pathname,
+        MMapPath::Vdso | MMapPath::Vvar | MMapPath::Vsyscall | MMapPath::Other(_)
+    )
+}
+
+/// Writes the image of a `len` byte memory region to `path`, where `data` holds the
+/// `extents` of the region concatenated. Everything outside of the extents, and any
+/// page of zeros inside of them, is left as a hole in the file.
+///
+/// Compressed images can't have holes, so they hold the runs of data concatenated instead.
+///
+/// Returns the `(offset, length)` of every run of data that was actually written,
+/// and how many bytes that took.
+fn write_image(
+    path: &Path,
+    len: u64,
+    data: &[u8],
+    extents: &[(u64, u64)],
+    compression: &Compression,
+) -> io::Result<(Vec<(u64, u64)>, u64)> {
+    // (offset in the image, offset in data, length)
+    let mut runs: Vec<(u64, usize, u64)> = vec![];
+    let mut pos = 0;
+    for &(offset, ext_len) in extents {
+        let ext = &data[pos..pos + ext_len as usize];
+        for (i, page) in ext.chunks(PAGE_SIZE as usize).enumerate() {
+            if page.iter().all(|b| *b == 0) {
+                continue;
+            }
+
+            let page_offset = offset + i as u64 * PAGE_SIZE;
+            match runs.last_mut() {
+                Some((start, _, run_len)) if *start + *run_len == page_offset => {
+                    *run_len += page.len() as u64;
+                }
+                _ => runs.push((page_offset, pos + i * PAGE_SIZE as usize, page.len() as u64)),
+            }
+        }
+
+        pos += ext_len as usize;
+    }
+
+    let run_data =
+        |&(_, data_pos, run_len): &(u64, usize, u64)| &data[data_pos..data_pos + run_len as usize];
+
+    let written = if compression.is_none() {
+        let file = File::create(path)?;
+        file.set_len(len)?;
+
+        for run in &runs {
+            file.write_all_at(run_data(run), run.0)?;
+        }
+
+        runs.iter().map(|(_, _, run_len)| run_len).sum()
+    } else {
+        let compressed =
+            compression.compress(&runs.iter().flat_map(run_data).copied().collect::<Vec<_>>())?;
+        write(path, &compressed)?;
+        compressed.len() as u64
+    };
+
+    let runs = runs
+        .into_iter()
+        .map(|(offset, _, run_len)| (offset, run_len))
+        .collect();
+
+    Ok((runs, written))
+}
+
 pub fn maybe_remove_dir_all(path: impl AsRef<Path>) -> std::io::Result<()> {
     match remove_dir_all(path) {
         Ok(_) => Ok(()),
@@ -358,3 +1786,203 @@ pub fn maybe_remove_dir_all(path: impl AsRef<Path>) -> std::io::Result<()> {
         e => e,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        env, fs,
+        os::fd::AsRawFd,
+        process::{Child, Command},
+    };
+
+    use libc::{
+        SYS_getpid, SYS_mmap, MAP_ANONYMOUS, MAP_PRIVATE, O_ACCMODE, O_APPEND, O_CLOEXEC, PROT_NONE,
+    };
+
+    use super::*;
+    use crate::compress::Algorithm;
+
+    /// Starts a process to checkpoint, with a checkpoint directory of its own
+    fn start_tracee(name: &str) -> (Child, Checkpointer) {
+        let tracee = Command::new("sleep").arg("30").spawn().unwrap();
+        let path = env::temp_dir().join(format!("{name}-test-{}", std::process::id()));
+        fs::create_dir_all(&path).unwrap();
+        let cp = Checkpointer::attach(tracee.id() as pid_t, path).unwrap();
+        (tracee, cp)
+    }
+
+    fn stop_tracee(mut tracee: Child, cp: Checkpointer) {
+        tracee.kill().unwrap();
+        tracee.wait().unwrap();
+        fs::remove_dir_all(&cp.path).unwrap();
+    }
+
+    #[test]
+    fn fork_snapshot_clones_the_stopped_process() {
+        let (tracee, cp) = start_tracee("cow");
+        let pid = cp.procfs.pid;
+
+        let mut ptrace = PTrace::new(pid);
+        ptrace.attach().unwrap();
+        ptrace.wait_pause().unwrap();
+
+        let gadget = vdso::syscall_gadget(&cp.mem, &cp.procfs.maps().unwrap().0).unwrap();
+        let regs = ptrace.get_regs().unwrap().regs;
+        assert_eq!(ptrace.syscall(gadget, SYS_getpid, &[]).unwrap(), pid as i64);
+        assert_eq!(ptrace.get_regs().unwrap().regs.rip, regs.rip);
+
+        // The child is the tracee's, and stays stopped where it was forked
+        let mut child = cp.fork_snapshot(&ptrace, gadget).unwrap();
+        let stat = Process::new(child.pid).unwrap().stat().unwrap();
+        assert_eq!((stat.ppid, stat.state), (pid, 't'));
+
+        child.signal(SIGKILL).unwrap();
+        child.wait(__WALL).unwrap();
+        child.attached = false;
+        stop_tracee(tracee, cp);
+    }
+
+    #[test]
+    fn predump_copies_the_running_process() {
+        let (tracee, mut cp) = start_tracee("predump");
+
+        let predump = cp.predump();
+        if pagemap::soft_dirty_supported().unwrap() {
+            let predump = predump.unwrap();
+            assert!(predump.rounds[0] > 0);
+            assert!(!cp.predumped.is_empty());
+        } else {
+            assert!(predump.is_err());
+        }
+
+        stop_tracee(tracee, cp);
+    }
+
+    #[test]
+    fn unreadable_regions_are_reserved() {
+        let (tracee, mut cp) = start_tracee("reserve");
+
+        let mut ptrace = PTrace::new(cp.procfs.pid);
+        ptrace.attach().unwrap();
+        ptrace.wait_pause().unwrap();
+        let gadget = vdso::syscall_gadget(&cp.mem, &cp.procfs.maps().unwrap().0).unwrap();
+        let addr = ptrace
+            .syscall(
+                gadget,
+                SYS_mmap,
+                &[
+                    0,
+                    4 * PAGE_SIZE,
+                    PROT_NONE as u64,
+                    (MAP_PRIVATE | MAP_ANONYMOUS) as u64,
+                    -1i64 as u64,
+                    0,
+                ],
+            )
+            .unwrap() as u64;
+        ptrace.detach().unwrap();
+
+        // Both times it's a reservation, never an image, even an old one
+        for seq in 1..=2 {
+            cp.checkpoint().unwrap();
+
+            let cp_dir = cp.path.join(seq.to_string());
+            let maps: Vec<MemoryMap> =
+                serde_json::from_reader(File::open(cp_dir.join("maps")).unwrap()).unwrap();
+            let i = maps
+                .iter()
+                .position(|m| m.address.0 <= addr && addr < m.address.1)
+                .unwrap();
+            assert!(open_reservations(&cp_dir).unwrap().contains(&i));
+            assert!(!cp_dir.join(i.to_string()).exists());
+        }
+
+        stop_tracee(tracee, cp);
+    }
+
+    #[test]
+    fn write_image_leaves_holes() {
+        let path = env::temp_dir().join(format!("sparse-test-{}", std::process::id()));
+        let page = PAGE_SIZE as usize;
+
+        // Two extents, the second with a page of zeros in the middle
+        let extents = [(0, PAGE_SIZE), (4 * PAGE_SIZE, 3 * PAGE_SIZE)];
+        let mut data = vec![1; page];
+        data.extend(vec![2; page]);
+        data.extend(vec![0; page]);
+        data.extend(vec![3; page / 2]);
+        data.extend(vec![0; page / 2]);
+
+        let none = Compression::default();
+        let (written, bytes) = write_image(&path, 8 * PAGE_SIZE, &data, &extents, &none).unwrap();
+        assert_eq!(
+            written,
+            vec![
+                (0, PAGE_SIZE),
+                (4 * PAGE_SIZE, PAGE_SIZE),
+                (6 * PAGE_SIZE, PAGE_SIZE)
+            ]
+        );
+
+        let image = fs::read(&path).unwrap();
+        let mut expected = vec![0; 8 * page];
+        expected[..page].fill(1);
+        expected[4 * page..5 * page].fill(2);
+        expected[6 * page..6 * page + page / 2].fill(3);
+        assert_eq!(image, expected);
+        assert_eq!(bytes, 3 * PAGE_SIZE);
+
+        // Compressed, it's just the runs of data
+        let lz4 = Compression {
+            algorithm: Algorithm::Lz4,
+            level: 0,
+        };
+        let (compressed, bytes) = write_image(&path, 8 * PAGE_SIZE, &data, &extents, &lz4).unwrap();
+        assert_eq!(compressed, written);
+        let image = fs::read(&path).unwrap();
+        assert_eq!(bytes, image.len() as u64);
+        let runs: Vec<_> = written
+            .iter()
+            .flat_map(|&(offset, len)| &expected[offset as usize..(offset + len) as usize])
+            .copied()
+            .collect();
+        assert_eq!(lz4.decompress(image).unwrap(), runs);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn open_files_keep_their_flags_and_identity() {
+        let path = env::temp_dir().join(format!("open-file-test-{}", std::process::id()));
+        fs::write(&path, b"hello world").unwrap();
+        let file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .open(&path)
+            .unwrap();
+        let mut buf = [0; 6];
+        (&file).read_exact(&mut buf).unwrap();
+
+        let procfs = Process::myself().unwrap();
+        let info = procfs.fd_from_fd(file.as_raw_fd()).unwrap();
+        let open = OpenFile::capture(&procfs, info.clone()).unwrap();
+        assert_eq!(open.offset, 6);
+        assert_eq!(open.flags & (O_ACCMODE | O_APPEND), O_RDWR | O_APPEND);
+        assert!(open.flags & O_CLOEXEC != 0);
+        let identity = open.identity.unwrap();
+        assert_eq!(identity, FileIdentity::from(&metadata(&path).unwrap()));
+        assert_eq!(identity.size, 11);
+
+        // Older checkpoints only have the mode of the link in /proc
+        let cp_dir = env::temp_dir().join(format!("legacy-files-test-{}", std::process::id()));
+        fs::create_dir_all(&cp_dir).unwrap();
+        let legacy = File::create(cp_dir.join("files")).unwrap();
+        serde_json::to_writer(legacy, &[(info, 6u64)]).unwrap();
+        let opened = OpenFile::open_all(&cp_dir).unwrap();
+        assert_eq!((opened[0].offset, opened[0].flags), (6, O_RDWR));
+        assert!(opened[0].identity.is_none());
+
+        fs::remove_file(&path).unwrap();
+        fs::remove_dir_all(&cp_dir).unwrap();
+    }
+}
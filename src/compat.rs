@@ -1,4 +1,4 @@
-use libc::{c_uint, c_ulonglong, c_ushort, user_fpregs_struct, user_regs_struct};
+use libc::{c_int, c_uint, c_ulong, c_ulonglong, c_ushort, user_fpregs_struct, user_regs_struct};
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -96,9 +96,9 @@ impl From<user_regs_struct> for UserRegs {
     }
 }
 
-impl Into<user_regs_struct> for UserRegs {
-    fn into(self) -> user_regs_struct {
-        let Self {
+impl From<UserRegs> for user_regs_struct {
+    fn from(regs: UserRegs) -> Self {
+        let UserRegs {
             r15,
             r14,
             r13,
@@ -126,8 +126,8 @@ impl Into<user_regs_struct> for UserRegs {
             es,
             fs,
             gs,
-        } = self;
-        user_regs_struct {
+        } = regs;
+        Self {
             r15,
             r14,
             r13,
@@ -217,3 +217,147 @@ impl UserFpregs {
         buf.xmm_space = self.xmm_space.try_into().unwrap();
     }
 }
+
+/// The regset of the XSAVE area, from `linux/elf.h`
+pub const NT_X86_XSTATE: u32 = 0x202;
+
+/// The software reserved bytes at the end of the legacy region of the XSAVE area,
+/// where the kernel describes the area it hands to ptrace (see `asm/user.h`)
+const XSTATE_SW_RESERVED: usize = 464;
+
+/// The XSAVE header, which comes right after the 512 byte legacy region
+const XSTATE_HEADER: usize = 512;
+
+/// Where the kernel puts the XCR0 of the CPU, first in the software reserved bytes
+const XSTATE_SW_XCR0: usize = XSTATE_SW_RESERVED;
+
+/// Where the XSTATE_BV of the XSAVE header is, the components actually in use
+const XSTATE_HEADER_BV: usize = XSTATE_HEADER;
+
+/// The whole XSAVE area of a thread, which on top of the x87 and SSE state in
+/// `UserFpregs` holds everything newer, like the upper halves of the YMM and ZMM
+/// registers and the AVX-512 opmask registers
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct XState {
+    /// The components the CPU had enabled, which decides the layout of `area`
+    pub xcr0: u64,
+    /// The components that were in use, which have to be supported wherever this is restored
+    pub xstate_bv: u64,
+    /// The standard (uncompacted) format XSAVE area, as `PTRACE_GETREGSET` returns it
+    pub area: Vec<u8>,
+}
+
+impl XState {
+    pub fn from_area(area: Vec<u8>) -> Self {
+        let read = |offset: usize| u64::from_ne_bytes(area[offset..offset + 8].try_into().unwrap());
+
+        Self {
+            xcr0: read(XSTATE_SW_XCR0),
+            xstate_bv: read(XSTATE_HEADER_BV),
+            area,
+        }
+    }
+
+    /// Lays this out for a CPU whose own XSAVE area is `target`, which is the same
+    /// layout as long as the CPU supports every component we use, since the offset of
+    /// each component in the standard format is fixed. Errors if it doesn't.
+    pub fn for_target(&self, target: &XState) -> Result<Vec<u8>, String> {
+        let missing = self.xstate_bv & !target.xcr0;
+        if missing != 0 {
+            return Err(format!(
+                "the registers use XSAVE components {missing:#x} that this CPU doesn't support (it has {:#x})",
+                target.xcr0
+            ));
+        }
+
+        // The kernel only takes a buffer of exactly its own size
+        let mut area = target.area.clone();
+        let len = area.len().min(self.area.len());
+        area[..len].copy_from_slice(&self.area[..len]);
+
+        // Keep the target's description of itself
+        area[XSTATE_SW_RESERVED..XSTATE_HEADER]
+            .copy_from_slice(&target.area[XSTATE_SW_RESERVED..XSTATE_HEADER]);
+
+        Ok(area)
+    }
+}
+
+/// The kernel's `struct clone_args` for `clone3`, which libc only has for some targets
+#[repr(C)]
+#[derive(Debug, Clone, Default)]
+pub struct CloneArgs {
+    pub flags: c_ulonglong,
+    pub pidfd: c_ulonglong,
+    pub child_tid: c_ulonglong,
+    pub parent_tid: c_ulonglong,
+    pub exit_signal: c_ulonglong,
+    pub stack: c_ulonglong,
+    pub stack_size: c_ulonglong,
+    pub tls: c_ulonglong,
+    /// A pointer to the pids to give the child, innermost pid namespace first
+    pub set_tid: c_ulonglong,
+    pub set_tid_size: c_ulonglong,
+    pub cgroup: c_ulonglong,
+}
+
+/// The header `capget` and `capset` take, which says how many `CapData` there are
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CapHeader {
+    pub version: u32,
+    pub pid: c_int,
+}
+
+/// One set of 32 capabilities, version 3 of the header takes two
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CapData {
+    pub effective: u32,
+    pub permitted: u32,
+    pub inheritable: u32,
+}
+
+pub const LINUX_CAPABILITY_VERSION_3: u32 = 0x2008_0522;
+/// What `clone3` needs in the owning user namespace of the pid namespace to take `set_tid`
+pub const CAP_SYS_ADMIN: u32 = 21;
+pub const PR_CAP_AMBIENT: c_int = 47;
+pub const PR_CAP_AMBIENT_RAISE: c_ulong = 2;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn xstate(len: usize, xcr0: u64, xstate_bv: u64, fill: u8) -> XState {
+        let mut area = vec![fill; len];
+        area[464..512].fill(0);
+        area[XSTATE_SW_XCR0..XSTATE_SW_XCR0 + 8].copy_from_slice(&xcr0.to_ne_bytes());
+        area[XSTATE_HEADER_BV..XSTATE_HEADER_BV + 8].copy_from_slice(&xstate_bv.to_ne_bytes());
+        XState::from_area(area)
+    }
+
+    #[test]
+    fn for_target_takes_the_targets_size_and_description() {
+        let saved = xstate(832, 0x7, 0x3, 1);
+        assert_eq!((saved.xcr0, saved.xstate_bv), (0x7, 0x3));
+
+        let target = xstate(2696, 0xe7, 0x1, 2);
+        let area = saved.for_target(&target).unwrap();
+        assert_eq!(area.len(), target.area.len());
+        assert!(area[..464].iter().all(|b| *b == 1));
+        assert_eq!(area[464..512], target.area[464..512]);
+        assert_eq!(XState::from_area(area.clone()).xstate_bv, 0x3);
+        assert!(area[512 + 8..832].iter().all(|b| *b == 1));
+        assert!(area[832..].iter().all(|b| *b == 2));
+
+        // And a smaller target cuts off what it doesn't have
+        assert_eq!(target.for_target(&saved).unwrap().len(), saved.area.len());
+    }
+
+    #[test]
+    fn for_target_refuses_missing_components() {
+        let saved = xstate(2696, 0xe7, 0xe3, 0);
+        let target = xstate(832, 0x7, 0x3, 0);
+        assert!(saved.for_target(&target).is_err());
+    }
+}
@@ -0,0 +1,114 @@
+use std::{
+    error::Error,
+    fs::{self, File},
+    io::{self, ErrorKind},
+    path::Path,
+    str::FromStr,
+};
+
+use serde::{Deserialize, Serialize};
+
+/// The file in the checkpoint directory that records how its memory images are compressed
+pub const COMPRESSION_FILE: &str = "compression";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Algorithm {
+    None,
+    Lz4,
+    Zstd,
+}
+
+impl FromStr for Algorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Self::None),
+            "lz4" => Ok(Self::Lz4),
+            "zstd" => Ok(Self::Zstd),
+            _ => Err(format!(
+                "unknown compression algorithm {s:?} (none, lz4, zstd)"
+            )),
+        }
+    }
+}
+
+/// How the memory images of a checkpoint directory are compressed.
+///
+/// This is chosen when the directory gets its first checkpoint, and every
+/// later checkpoint in it has to use the same, since images get reused across them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Compression {
+    pub algorithm: Algorithm,
+    /// Only used by zstd
+    pub level: i32,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Self {
+            algorithm: Algorithm::None,
+            level: 0,
+        }
+    }
+}
+
+impl Compression {
+    pub fn is_none(&self) -> bool {
+        self.algorithm == Algorithm::None
+    }
+
+    /// Reads the compression recorded for the checkpoint directory `cpath`,
+    /// directories from before we compressed anything have none
+    pub fn open(cpath: &Path) -> Result<Self, Box<dyn Error>> {
+        match File::open(cpath.join(COMPRESSION_FILE)) {
+            Ok(file) => Ok(serde_json::from_reader(file)?),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Records this as the compression of the checkpoint directory `cpath`
+    pub fn save(&self, cpath: &Path) -> Result<(), Box<dyn Error>> {
+        fs::write(cpath.join(COMPRESSION_FILE), serde_json::to_vec(self)?)?;
+        Ok(())
+    }
+
+    pub fn compress(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match self.algorithm {
+            Algorithm::None => Ok(data.to_vec()),
+            Algorithm::Lz4 => Ok(lz4_flex::compress_prepend_size(data)),
+            Algorithm::Zstd => zstd::bulk::compress(data, self.level),
+        }
+    }
+
+    pub fn decompress(&self, data: Vec<u8>) -> io::Result<Vec<u8>> {
+        match self.algorithm {
+            Algorithm::None => Ok(data),
+            Algorithm::Lz4 => lz4_flex::decompress_size_prepended(&data)
+                .map_err(|e| io::Error::new(ErrorKind::InvalidData, e)),
+            Algorithm::Zstd => zstd::decode_all(data.as_slice()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let data: Vec<u8> = (0..100_000u32)
+            .map(|i| (i % 251) as u8 ^ (i / 4096) as u8)
+            .collect();
+        for algorithm in [Algorithm::None, Algorithm::Lz4, Algorithm::Zstd] {
+            let compression = Compression {
+                algorithm,
+                level: 3,
+            };
+            let compressed = compression.compress(&data).unwrap();
+            assert_eq!(compression.decompress(compressed).unwrap(), data);
+        }
+    }
+}
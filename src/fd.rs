@@ -0,0 +1,1460 @@
+use std::{
+    collections::{hash_map::Entry, HashMap, HashSet},
+    error::Error,
+    ffi::CString,
+    fs::{self, File, OpenOptions},
+    io::{self, ErrorKind, Read, Write},
+    mem,
+    os::{
+        fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd},
+        unix::{
+            fs::{FileExt, FileTypeExt, MetadataExt, OpenOptionsExt},
+            process::CommandExt,
+        },
+    },
+    path::{Path, PathBuf},
+    process::Command,
+    str::FromStr,
+};
+
+use libc::{
+    c_int, c_long, c_ulong, pid_t, ucred, SYS_close, SYS_dup2, SYS_epoll_create1, SYS_epoll_ctl,
+    SYS_fcntl, SYS_kcmp, SYS_mmap, SYS_pidfd_getfd, SYS_pidfd_open, SYS_recvmsg, SYS_signalfd4,
+    AF_NETLINK, AF_UNIX, EFD_CLOEXEC, EFD_SEMAPHORE, ENOENT, EPOLL_CTL_ADD, FD_CLOEXEC, FIONREAD,
+    F_GETPIPE_SZ, F_SETFD, F_SETFL, F_SETPIPE_SZ, MAP_FIXED, MAP_PRIVATE, MAP_SHARED,
+    MSG_CMSG_CLOEXEC, MSG_CTRUNC, MSG_DONTWAIT, MSG_NOSIGNAL, MSG_PEEK, MSG_TRUNC,
+    NETLINK_SOCK_DIAG, NLMSG_ERROR, NLM_F_REQUEST, O_ACCMODE, O_APPEND, O_CLOEXEC, O_NOCTTY,
+    O_NONBLOCK, O_WRONLY, SCM_CREDENTIALS, SCM_RIGHTS, SEEK_SET, SFD_CLOEXEC, SOCK_CLOEXEC,
+    SOCK_DGRAM, SOL_SOCKET, SO_PASSCRED, SO_PEEK_OFF, SO_RCVTIMEO, SPLICE_F_NONBLOCK, TFD_CLOEXEC,
+};
+use log::{debug, warn};
+use procfs::process::{FDTarget, MMPermissions, MemoryMap, Process};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    anon::{is_deleted, AnonFile},
+    checkpoint::OpenFile,
+    memory::MemReader,
+    ptrace::PTrace,
+    signal::SIGSET_SIZE,
+    vdso,
+};
+
+/// How much of the restored process' memory `FdRestorer::restore` needs for syscall arguments
+pub const SCRATCH_LEN: u64 = 128;
+
+/// The `kcmp` type that compares the open files behind two fds
+const KCMP_FILE: c_int = 0;
+
+/// `_IOW('T', 0, u64)`, which sets how many times a timerfd has expired
+const TFD_IOC_SET_TICKS: c_ulong = 0x4008_5400;
+
+/// The sock_diag request for the sockets of one family
+const SOCK_DIAG_BY_FAMILY: u16 = 20;
+const UDIAG_SHOW_NAME: u32 = 0x1;
+const UDIAG_SHOW_PEER: u32 = 0x4;
+const UNIX_DIAG_NAME: u16 = 0;
+const UNIX_DIAG_PEER: u16 = 2;
+const UNIX_DIAG_SHUTDOWN: u16 = 6;
+
+/// The states sock_diag reports unix sockets in, borrowed from TCP
+const TCP_ESTABLISHED: u8 = 1;
+const TCP_CLOSE: u8 = 7;
+
+/// The file status flags that `F_SETFL` can set again
+const STATUS_FLAGS: c_int = O_APPEND | O_NONBLOCK;
+
+/// What to do with the fds of a checkpoint that can't be restored
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FdPolicy {
+    /// Refuse to restore the process
+    #[default]
+    Error,
+    /// Restore the process without them, so it has whatever it inherited from
+    /// the restore in their place (for stdio, the restorer's own) or nothing
+    Skip,
+}
+
+impl FromStr for FdPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "error" => Ok(Self::Error),
+            "skip" => Ok(Self::Skip),
+            _ => Err(format!("unknown fd policy {s:?}, expected error or skip")),
+        }
+    }
+}
+
+/// A file descriptor that the bootstrapper doesn't open by itself, which is anything
+/// but a regular file, with what it takes to make it again
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FdDump {
+    pub fd: i32,
+    /// The flags from fdinfo: the access mode, the file status flags,
+    /// and `O_CLOEXEC` if the fd is close-on-exec
+    pub flags: i32,
+    pub kind: FdKind,
+}
+
+/// Every type of fd we know how to dump and restore
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum FdKind {
+    /// A device, directory or such, which is opened again by its path
+    Path(PathBuf),
+    Pipe(PipeEnd),
+    Socket(UnixSocket),
+    EventFd(EventFd),
+    TimerFd(TimerFd),
+    /// A signalfd, with the signals it reads
+    SignalFd(u64),
+    /// An epoll instance, with everything it watches
+    Epoll(Vec<EpollTarget>),
+    /// The same open file as the lower fd `.0` of the process
+    Dup(i32),
+    /// A memfd or a file that was deleted, which is made again from its `AnonFile`
+    Anon {
+        dev: u64,
+        inode: u64,
+        offset: u64,
+    },
+    /// The same open file as `fd` of the process `pid` of the tree, which is restored first
+    Shared {
+        pid: pid_t,
+        fd: i32,
+    },
+    /// Something we can't restore, and what it is
+    Unsupported(String),
+}
+
+/// One end of a pipe
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PipeEnd {
+    pub inode: u64,
+    pub write: bool,
+    /// The capacity of the pipe
+    pub size: u32,
+    /// What was in the pipe, waiting to be read
+    pub contents: Vec<u8>,
+}
+
+/// An unnamed unix socket, like either end of a socketpair
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UnixSocket {
+    pub inode: u64,
+    /// `SOCK_STREAM`, `SOCK_DGRAM` or `SOCK_SEQPACKET`
+    pub kind: c_int,
+    /// Whether it's connected, which it stays when its peer is closed
+    pub connected: bool,
+    /// The socket on the other end, if it's still open
+    pub peer: Option<u64>,
+    /// What's shut down, with 1 for receiving and 2 for sending
+    pub shutdown: u8,
+    /// The messages waiting to be read from it (for a stream, chunks of the data)
+    pub queue: Vec<Vec<u8>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EventFd {
+    pub count: u64,
+    /// Only reported by Linux 6.5 and up
+    pub semaphore: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TimerFd {
+    pub clockid: c_int,
+    /// How many times it expired without being read
+    pub ticks: u64,
+    /// The time left until it next expires, as (seconds, nanoseconds)
+    pub value: (i64, i64),
+    pub interval: (i64, i64),
+}
+
+/// An fd in the interest set of an epoll instance, as `epoll_ctl` takes it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EpollTarget {
+    pub fd: i32,
+    pub events: u32,
+    pub data: u64,
+}
+
+impl FdDump {
+    /// Dumps every fd in `files` of the stopped process `procfs` that isn't a regular file.
+    ///
+    /// Fds that are dups of an earlier one are only recorded as that, and anything
+    /// we can't restore is warned about now, since the restore is going to refuse it.
+    pub fn capture_all(procfs: &Process, files: &[OpenFile]) -> Result<Vec<Self>, Box<dyn Error>> {
+        let pid = procfs.pid;
+
+        let mut dumps: Vec<Self> = vec![];
+        for file in files {
+            let fd_path = format!("/proc/{pid}/fd/{}", file.fd);
+            let regular = matches!(&file.target, FDTarget::Path(path) if !is_deleted(path));
+            if regular && fs::metadata(&fd_path)?.is_file() {
+                continue;
+            }
+
+            let info = fs::read_to_string(format!("/proc/{pid}/fdinfo/{}", file.fd))?;
+            let kind = match dumps.iter().find(|d| same_file(pid, d.fd, pid, file.fd)) {
+                Some(original) => FdKind::Dup(original.fd),
+                None => FdKind::capture(pid, file, &info)?,
+            };
+            match &kind {
+                FdKind::Unsupported(what) => {
+                    warn!("fd {} is {what}, which can't be restored", file.fd)
+                }
+                kind => debug!("fd {} is {kind:?}", file.fd),
+            }
+
+            dumps.push(Self {
+                fd: file.fd,
+                flags: file.flags,
+                kind,
+            });
+        }
+
+        Ok(dumps)
+    }
+
+    /// Reads the fds of the checkpoint in `cp_dir`, which older checkpoints don't have
+    pub fn open_all(cp_dir: &Path) -> Result<Vec<Self>, Box<dyn Error>> {
+        match File::open(cp_dir.join("fds")) {
+            Ok(file) => Ok(serde_json::from_reader(file)?),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(vec![]),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+impl FdKind {
+    /// Dumps the fd `file` of the stopped process `pid`, whose fdinfo is `info`
+    fn capture(pid: pid_t, file: &OpenFile, info: &str) -> Result<Self, Box<dyn Error>> {
+        let flags = file.flags;
+        let kind = match &file.target {
+            FDTarget::Path(path) if !is_deleted(path) => {
+                let meta = fs::metadata(format!("/proc/{pid}/fd/{}", file.fd))?;
+                match meta.file_type().is_fifo() {
+                    true => Self::Unsupported(format!("the FIFO {path:?}")),
+                    false => Self::Path(path.clone()),
+                }
+            }
+            FDTarget::Path(_) | FDTarget::MemFD(_) => {
+                let meta = fs::metadata(format!("/proc/{pid}/fd/{}", file.fd))?;
+                match meta.is_file() {
+                    true => Self::Anon {
+                        dev: meta.dev(),
+                        inode: meta.ino(),
+                        offset: file.offset,
+                    },
+                    false => Self::Unsupported(format!("{:?}, which was deleted", file.target)),
+                }
+            }
+            FDTarget::Pipe(inode) => Self::Pipe(PipeEnd::capture(pid, file.fd, *inode, flags)?),
+            FDTarget::Socket(inode) => UnixSocket::capture(pid, file.fd, *inode)?,
+            FDTarget::AnonInode(name) => match name.as_str() {
+                "[eventfd]" => Self::EventFd(EventFd {
+                    count: u64::from_str_radix(field(info, "eventfd-count")?, 16)?,
+                    semaphore: field(info, "eventfd-semaphore").is_ok_and(|s| s == "1"),
+                }),
+                "[timerfd]" => Self::TimerFd(TimerFd {
+                    clockid: field(info, "clockid")?.parse()?,
+                    ticks: field(info, "ticks")?.parse()?,
+                    value: timespec_field(info, "it_value")?,
+                    interval: timespec_field(info, "it_interval")?,
+                }),
+                "[signalfd]" => Self::SignalFd(u64::from_str_radix(field(info, "sigmask")?, 16)?),
+                "[eventpoll]" => Self::Epoll(epoll_targets(info)?),
+                _ => Self::Unsupported(format!("an {name} anonymous inode")),
+            },
+            FDTarget::Net(_) => Self::Unsupported("a network namespace".to_string()),
+            FDTarget::Other(what, inode) => Self::Unsupported(format!("{what}:[{inode}]")),
+        };
+
+        Ok(kind)
+    }
+}
+
+impl PipeEnd {
+    /// Dumps the end of a pipe at `fd` of the stopped process `pid` without taking anything
+    /// out of the pipe: opening it through `/proc` gets us a read end of our own (like a FIFO
+    /// would), and `tee` copies what's in it into a pipe we can read out of
+    fn capture(pid: pid_t, fd: i32, inode: u64, flags: i32) -> Result<Self, Box<dyn Error>> {
+        let pipe = OpenOptions::new()
+            .read(true)
+            .custom_flags(O_NONBLOCK)
+            .open(format!("/proc/{pid}/fd/{fd}"))?;
+        let size = cvt(unsafe { libc::fcntl(pipe.as_raw_fd(), F_GETPIPE_SZ) } as i64)? as u32;
+
+        let mut len: c_int = 0;
+        cvt(unsafe { libc::ioctl(pipe.as_raw_fd(), FIONREAD, &mut len) } as i64)?;
+
+        let mut contents = vec![];
+        if len > 0 {
+            let (read, write) = make_pipe()?;
+            cvt(unsafe { libc::fcntl(write.as_raw_fd(), F_SETPIPE_SZ, size) } as i64)?;
+
+            let copied = unsafe {
+                libc::tee(
+                    pipe.as_raw_fd(),
+                    write.as_raw_fd(),
+                    len as usize,
+                    SPLICE_F_NONBLOCK,
+                )
+            };
+            contents.resize(cvt(copied as i64)? as usize, 0);
+            File::from(read).read_exact(&mut contents)?;
+        }
+
+        Ok(Self {
+            inode,
+            write: flags & O_ACCMODE == O_WRONLY,
+            size,
+            contents,
+        })
+    }
+}
+
+/// What sock_diag tells us about a unix socket
+struct UnixDiag {
+    kind: c_int,
+    state: u8,
+    name: Option<String>,
+    peer: Option<u64>,
+    shutdown: u8,
+}
+
+impl UnixSocket {
+    /// Dumps the socket `inode` at `fd` of the stopped process `pid`, if it's one we can restore
+    fn capture(pid: pid_t, fd: i32, inode: u64) -> Result<FdKind, Box<dyn Error>> {
+        let Some(diag) = unix_diag(inode)? else {
+            return Ok(FdKind::Unsupported(format!(
+                "socket:[{inode}], which isn't a unix socket"
+            )));
+        };
+
+        if let Some(name) = diag.name {
+            return Ok(FdKind::Unsupported(format!(
+                "a unix socket bound to {name:?}"
+            )));
+        }
+        if diag.state != TCP_ESTABLISHED && diag.state != TCP_CLOSE {
+            return Ok(FdKind::Unsupported(format!(
+                "a unix socket in state {}",
+                diag.state
+            )));
+        }
+
+        let Some(queue) = peek_queue(pid, fd)? else {
+            return Ok(FdKind::Unsupported(format!(
+                "a unix socket with fds queued on it (socket:[{inode}])"
+            )));
+        };
+
+        Ok(FdKind::Socket(Self {
+            inode,
+            kind: diag.kind,
+            connected: diag.state == TCP_ESTABLISHED,
+            peer: diag.peer,
+            shutdown: diag.shutdown,
+            queue,
+        }))
+    }
+}
+
+/// Asks sock_diag about the unix socket `inode`, which is `None` if it isn't one
+fn unix_diag(inode: u64) -> io::Result<Option<UnixDiag>> {
+    let sock = unsafe { libc::socket(AF_NETLINK, SOCK_DGRAM | SOCK_CLOEXEC, NETLINK_SOCK_DIAG) };
+    let sock = unsafe { OwnedFd::from_raw_fd(cvt(sock as i64)? as RawFd) };
+
+    // A struct nlmsghdr and then a struct unix_diag_req
+    let mut req = vec![];
+    req.extend(40u32.to_ne_bytes());
+    req.extend(SOCK_DIAG_BY_FAMILY.to_ne_bytes());
+    req.extend((NLM_F_REQUEST as u16).to_ne_bytes());
+    req.extend([0; 8]);
+    req.extend([AF_UNIX as u8, 0, 0, 0]);
+    req.extend(u32::MAX.to_ne_bytes());
+    req.extend((inode as u32).to_ne_bytes());
+    req.extend((UDIAG_SHOW_NAME | UDIAG_SHOW_PEER).to_ne_bytes());
+    req.extend([0xff; 8]);
+
+    let sent = unsafe { libc::send(sock.as_raw_fd(), req.as_ptr().cast(), req.len(), 0) };
+    cvt(sent as i64)?;
+
+    let mut buf = vec![0u8; 8192];
+    let len = unsafe { libc::recv(sock.as_raw_fd(), buf.as_mut_ptr().cast(), buf.len(), 0) };
+    buf.truncate(cvt(len as i64)? as usize);
+
+    let msg_type = u16::from_ne_bytes(buf[4..6].try_into().unwrap());
+    if msg_type == NLMSG_ERROR as u16 {
+        return match -i32::from_ne_bytes(buf[16..20].try_into().unwrap()) {
+            ENOENT => Ok(None),
+            errno => Err(io::Error::from_raw_os_error(errno)),
+        };
+    }
+
+    // The struct unix_diag_msg, and then the attributes we asked for
+    let mut diag = UnixDiag {
+        kind: buf[17] as c_int,
+        state: buf[18],
+        name: None,
+        peer: None,
+        shutdown: 0,
+    };
+
+    let msg_len = u32::from_ne_bytes(buf[0..4].try_into().unwrap()) as usize;
+    let mut pos = 32;
+    while pos + 4 <= msg_len {
+        let attr_len = u16::from_ne_bytes(buf[pos..pos + 2].try_into().unwrap()) as usize;
+        let attr_type = u16::from_ne_bytes(buf[pos + 2..pos + 4].try_into().unwrap());
+        let data = &buf[pos + 4..pos + attr_len];
+
+        match attr_type {
+            UNIX_DIAG_NAME => {
+                // Abstract names start with a nul, which is usually shown as an @
+                let name = String::from_utf8_lossy(data);
+                diag.name = Some(name.replacen('\0', "@", 1));
+            }
+            UNIX_DIAG_PEER => {
+                diag.peer = Some(u32::from_ne_bytes(data[..4].try_into().unwrap()) as u64)
+            }
+            UNIX_DIAG_SHUTDOWN => diag.shutdown = data[0],
+            _ => {}
+        }
+
+        pos += attr_len.next_multiple_of(4);
+    }
+
+    Ok(Some(diag))
+}
+
+/// Reads every message waiting on the socket at `fd` of the stopped process `pid` without
+/// taking them off of it, by peeking at them through our own copy of the socket.
+///
+/// Returns `None` if fds are queued on it too, which we can't put back.
+fn peek_queue(pid: pid_t, fd: i32) -> Result<Option<Vec<Vec<u8>>>, Box<dyn Error>> {
+    let sock = getfd(pid, fd)?;
+
+    let mut len: c_int = 0;
+    cvt(unsafe { libc::ioctl(sock.as_raw_fd(), FIONREAD, &mut len) } as i64)?;
+    if len == 0 {
+        return Ok(Some(vec![]));
+    }
+
+    // The peek offset belongs to the socket, which the process shares with us
+    let old_offset = peek_offset(&sock)?;
+    let queue = peek_all(&sock);
+    set_peek_offset(&sock, old_offset)?;
+    queue
+}
+
+/// Peeks at every message on `sock`, see `peek_queue`
+fn peek_all(sock: &OwnedFd) -> Result<Option<Vec<Vec<u8>>>, Box<dyn Error>> {
+    let mut queue = vec![];
+    let mut buf = vec![0u8; 1 << 16];
+    // Room for as many fds as a message can carry (SCM_MAX_FD), and credentials
+    let mut control = [0u64; 256];
+    let mut offset = 0;
+    loop {
+        // With a peek offset the peek starts that far into the queue. MSG_TRUNC has
+        // datagrams tell us their whole length, so we can peek again if the buffer was short.
+        set_peek_offset(sock, offset)?;
+
+        let mut iov = libc::iovec {
+            iov_base: buf.as_mut_ptr().cast(),
+            iov_len: buf.len(),
+        };
+        let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr().cast();
+        msg.msg_controllen = mem::size_of_val(&control);
+
+        let flags = MSG_PEEK | MSG_DONTWAIT | MSG_TRUNC | MSG_CMSG_CLOEXEC;
+        let n = unsafe { libc::recvmsg(sock.as_raw_fd(), &mut msg, flags) };
+        let n = match n {
+            0 => break,
+            1.. => n as usize,
+            _ if io::Error::last_os_error().kind() == ErrorKind::WouldBlock => break,
+            _ => return Err(io::Error::last_os_error().into()),
+        };
+
+        // Peeking at fds gives us copies of them, which we don't want either
+        if close_passed_fds(&msg) || msg.msg_flags & MSG_CTRUNC != 0 {
+            return Ok(None);
+        }
+
+        if n > buf.len() {
+            buf.resize(n, 0);
+            continue;
+        }
+
+        queue.push(buf[..n].to_vec());
+        offset += n as c_int;
+    }
+
+    Ok(Some(queue))
+}
+
+/// Closes the fds passed in the control messages of `msg`, returning whether there were any
+fn close_passed_fds(msg: &libc::msghdr) -> bool {
+    let mut found = false;
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == SOL_SOCKET && (*cmsg).cmsg_type == SCM_RIGHTS {
+                let data_len = (*cmsg).cmsg_len - libc::CMSG_LEN(0) as usize;
+                let fds = libc::CMSG_DATA(cmsg).cast::<c_int>();
+                for i in 0..data_len / mem::size_of::<c_int>() {
+                    libc::close(fds.add(i).read_unaligned());
+                }
+                found = true;
+            }
+            cmsg = libc::CMSG_NXTHDR(msg, cmsg);
+        }
+    }
+    found
+}
+
+fn peek_offset(sock: &OwnedFd) -> io::Result<c_int> {
+    let mut offset: c_int = 0;
+    let mut len = mem::size_of::<c_int>() as u32;
+    let res = unsafe {
+        libc::getsockopt(
+            sock.as_raw_fd(),
+            SOL_SOCKET,
+            SO_PEEK_OFF,
+            (&mut offset as *mut c_int).cast(),
+            &mut len,
+        )
+    };
+    cvt(res as i64).map(|_| offset)
+}
+
+fn set_peek_offset(sock: &OwnedFd, offset: c_int) -> io::Result<()> {
+    let res = unsafe {
+        libc::setsockopt(
+            sock.as_raw_fd(),
+            SOL_SOCKET,
+            SO_PEEK_OFF,
+            (&offset as *const c_int).cast(),
+            mem::size_of::<c_int>() as u32,
+        )
+    };
+    cvt(res as i64).map(drop)
+}
+
+/// Finds the fds in the dumps of each process of a tree that are the same open file as
+/// one in the dumps of an earlier process, and makes them `Shared`s of that.
+///
+/// `procs` are the `(pid, dumps)` of every process, in the order they're restored in.
+pub fn link_shared(mut procs: Vec<(pid_t, &mut Vec<FdDump>)>) {
+    for i in 1..procs.len() {
+        let (earlier, rest) = procs.split_at_mut(i);
+        let (pid, dumps) = &mut rest[0];
+
+        for dump in dumps.iter_mut() {
+            if matches!(dump.kind, FdKind::Dup(_)) {
+                continue;
+            }
+
+            let original = earlier.iter().find_map(|(other, theirs)| {
+                theirs
+                    .iter()
+                    .filter(|d| !matches!(d.kind, FdKind::Dup(_) | FdKind::Shared { .. }))
+                    .find(|d| same_file(*other, d.fd, *pid, dump.fd))
+                    .map(|d| (*other, d.fd))
+            });
+
+            if let Some((pid, fd)) = original {
+                dump.kind = FdKind::Shared { pid, fd };
+            }
+        }
+    }
+}
+
+/// Whether `fd1` of `pid1` and `fd2` of `pid2` are the same open file
+fn same_file(pid1: pid_t, fd1: i32, pid2: pid_t, fd2: i32) -> bool {
+    unsafe { libc::syscall(SYS_kcmp, pid1, pid2, KCMP_FILE, fd1, fd2) == 0 }
+}
+
+/// Puts back the fds of the processes of a tree that their bootstrappers don't open themselves.
+///
+/// We make most of them here, and hand them to each stopped bootstrapper over a unix socket
+/// it inherited at `channel_fd` by having it `recvmsg`. That takes nothing the bootstrapper
+/// couldn't do anyways wherever it is, and lets pipes and socketpairs go between processes.
+/// epoll instances are the exception: what they watch is tied to the fd numbers of their
+/// process, so the bootstrapper makes those itself.
+pub struct FdRestorer {
+    /// The fd the bootstrappers have the channel at, which is higher than any they restore
+    pub channel_fd: RawFd,
+    channel: OwnedFd,
+    bs_channel: OwnedFd,
+    /// The ends of every pipe we made, by inode and whether it's the write end
+    pipes: HashMap<(u64, bool), OwnedFd>,
+    /// The ends of every socket we made, by inode
+    sockets: HashMap<u64, OwnedFd>,
+    /// The dumps of every socket in the tree, by inode
+    socket_dumps: HashMap<u64, UnixSocket>,
+    /// The ends of every pipe in the tree
+    pipe_ends: HashSet<(u64, bool)>,
+    /// The fds that were left out, by the pid of their process at checkpoint time
+    skipped: HashSet<(pid_t, i32)>,
+    /// The pid each process was restored as, by its pid at checkpoint time
+    pids: HashMap<pid_t, pid_t>,
+    /// The memfds and deleted files we made again, by device and inode
+    anon: HashMap<(u64, u64), File>,
+}
+
+impl FdRestorer {
+    /// Gets ready to restore the fds of the processes `procs`, as `(pid, dumps)`, with the
+    /// channel at `channel_fd`. Any fd that can't be restored is dealt with by `policy` now,
+    /// so that we can refuse the restore before anything is started.
+    pub fn new(
+        policy: FdPolicy,
+        procs: &[(pid_t, &[FdDump])],
+        channel_fd: RawFd,
+    ) -> Result<Self, Box<dyn Error>> {
+        let mut fds = [0; 2];
+        let res =
+            unsafe { libc::socketpair(AF_UNIX, SOCK_DGRAM | SOCK_CLOEXEC, 0, fds.as_mut_ptr()) };
+        cvt(res as i64)?;
+        let [channel, bs_channel] = fds.map(|fd| unsafe { OwnedFd::from_raw_fd(fd) });
+
+        // The bootstrappers say when they're done over it too (see `next_stopping`)
+        let passcred: c_int = 1;
+        let timeout = libc::timeval {
+            tv_sec: 10,
+            tv_usec: 0,
+        };
+        for (option, value, len) in [
+            (
+                SO_PASSCRED,
+                (&passcred as *const c_int).cast(),
+                mem::size_of::<c_int>(),
+            ),
+            (
+                SO_RCVTIMEO,
+                (&timeout as *const libc::timeval).cast(),
+                mem::size_of::<libc::timeval>(),
+            ),
+        ] {
+            let res = unsafe {
+                libc::setsockopt(channel.as_raw_fd(), SOL_SOCKET, option, value, len as u32)
+            };
+            cvt(res as i64)?;
+        }
+
+        let dumps = || procs.iter().flat_map(|(_, dumps)| *dumps);
+        let mut restorer = Self {
+            channel_fd,
+            channel,
+            bs_channel,
+            pipes: HashMap::new(),
+            sockets: HashMap::new(),
+            socket_dumps: dumps()
+                .filter_map(|d| match &d.kind {
+                    FdKind::Socket(sock) => Some((sock.inode, sock.clone())),
+                    _ => None,
+                })
+                .collect(),
+            pipe_ends: dumps()
+                .filter_map(|d| match &d.kind {
+                    FdKind::Pipe(end) => Some((end.inode, end.write)),
+                    _ => None,
+                })
+                .collect(),
+            skipped: HashSet::new(),
+            pids: HashMap::new(),
+            anon: HashMap::new(),
+        };
+
+        for (pid, dumps) in procs {
+            for dump in dumps.iter() {
+                let Some(what) = restorer.unrestorable(dump) else {
+                    continue;
+                };
+
+                let fd = dump.fd;
+                match policy {
+                    FdPolicy::Error => return Err(format!("fd {fd} of process {pid} is {what}, which can't be restored (--unsupported-fds skip leaves it out)").into()),
+                    FdPolicy::Skip => warn!("Leaving out fd {fd} of process {pid}, it's {what}"),
+                }
+                restorer.skipped.insert((*pid, fd));
+            }
+        }
+
+        Ok(restorer)
+    }
+
+    /// Makes the files `anon` of the checkpoint in `cp_dir` again, for the fds and mappings
+    /// that have them, unless another process of the tree had them too
+    pub fn add_anon_files(
+        &mut self,
+        anon: &[AnonFile],
+        cp_dir: &Path,
+    ) -> Result<(), Box<dyn Error>> {
+        for (i, file) in anon.iter().enumerate() {
+            if let Entry::Vacant(entry) = self.anon.entry((file.dev, file.inode)) {
+                entry.insert(file.create(cp_dir, i)?);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// What `dump` is if it's something we can't restore
+    fn unrestorable(&self, dump: &FdDump) -> Option<String> {
+        match &dump.kind {
+            FdKind::Unsupported(what) => Some(what.clone()),
+            FdKind::Pipe(end) if !self.pipe_ends.contains(&(end.inode, !end.write)) => {
+                Some("a pipe to something outside of the checkpoint".to_string())
+            }
+            FdKind::Socket(UnixSocket {
+                peer: Some(peer), ..
+            }) if !self.socket_dumps.contains_key(peer) => {
+                Some("a socket connected to something outside of the checkpoint".to_string())
+            }
+            _ => None,
+        }
+    }
+
+    /// Waits for a bootstrapper to say that it's about to stop itself, which it does by
+    /// sending its index in the tree over the channel, and returns that and its pid.
+    ///
+    /// We get the pid from the kernel as we see it, which with `pidns::spawn_with_pid`
+    /// isn't the one the bootstrapper has for itself.
+    pub fn next_stopping(&self) -> Result<(usize, pid_t), Box<dyn Error>> {
+        let mut index = [0u8; 8];
+        let mut iov = libc::iovec {
+            iov_base: index.as_mut_ptr().cast(),
+            iov_len: index.len(),
+        };
+        // Room for a struct ucred, aligned like a struct cmsghdr
+        let mut control = [0u64; 4];
+
+        let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr().cast();
+        msg.msg_controllen = unsafe { libc::CMSG_SPACE(mem::size_of::<ucred>() as u32) } as usize;
+
+        let res = unsafe { libc::recvmsg(self.channel.as_raw_fd(), &mut msg, 0) };
+        if res < 0 && io::Error::last_os_error().kind() == ErrorKind::WouldBlock {
+            return Err("no bootstrapper got as far as stopping itself in 10 seconds".into());
+        }
+        let received = cvt(res as i64)?;
+
+        let cmsg = unsafe { libc::CMSG_FIRSTHDR(&msg) };
+        let ok = !cmsg.is_null()
+            && unsafe { ((*cmsg).cmsg_level, (*cmsg).cmsg_type) } == (SOL_SOCKET, SCM_CREDENTIALS)
+            && received == index.len() as i64
+            && msg.msg_flags & (MSG_TRUNC | MSG_CTRUNC) == 0;
+        if !ok {
+            return Err("got something other than a bootstrapper stopping over the channel".into());
+        }
+
+        let cred = unsafe { *libc::CMSG_DATA(cmsg).cast::<ucred>() };
+        Ok((u64::from_ne_bytes(index) as usize, cred.pid))
+    }
+
+    /// Has the bootstrapper that `command` starts inherit the channel
+    pub fn pass_channel(&self, command: &mut Command) {
+        let (fd, channel_fd) = (self.bs_channel.as_raw_fd(), self.channel_fd);
+
+        unsafe {
+            command.pre_exec(move || {
+                let res = match fd == channel_fd {
+                    true => libc::fcntl(fd, F_SETFD, 0),
+                    false => libc::dup2(fd, channel_fd),
+                };
+                match res {
+                    0.. => Ok(()),
+                    _ => Err(io::Error::last_os_error()),
+                }
+            });
+        }
+    }
+
+    /// Puts the fds `dumps` of the process `old_pid` into its stopped bootstrapper `ptrace`,
+    /// using `SCRATCH_LEN` bytes of its memory at `scratch` for the arguments of its syscalls,
+    /// and maps the files `anon` back in where its `maps` had them
+    pub fn restore(
+        &mut self,
+        old_pid: pid_t,
+        ptrace: &PTrace,
+        scratch: u64,
+        dumps: &[FdDump],
+        anon: &[AnonFile],
+        maps: &[MemoryMap],
+    ) -> Result<(), Box<dyn Error>> {
+        let pid = ptrace.pid;
+        self.pids.insert(old_pid, pid);
+
+        let procfs = Process::new(pid)?;
+        let remote = Remote {
+            ptrace,
+            gadget: vdso::syscall_gadget(&MemReader::new(&procfs)?, &procfs.maps()?.0)?,
+            scratch,
+            mem: OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(format!("/proc/{pid}/mem"))?,
+        };
+
+        for map in maps {
+            let Some(file) = anon.iter().find(|a| a.maps.contains(&map.address.0)) else {
+                continue;
+            };
+
+            debug!("Mapping {:?} back in at {:#x}", file.path, map.address.0);
+            self.map_anon(&remote, map, &self.anon[&(file.dev, file.inode)])?;
+        }
+
+        // epolls need what they watch, and dups what they're dups of, so they go last
+        let (last, first): (Vec<_>, Vec<_>) = dumps
+            .iter()
+            .partition(|d| matches!(d.kind, FdKind::Epoll(_) | FdKind::Dup(_)));
+
+        for dump in first.into_iter().chain(last) {
+            let original = match dump.kind {
+                FdKind::Dup(fd) => Some((old_pid, fd)),
+                FdKind::Shared { pid, fd } => Some((pid, fd)),
+                _ => None,
+            };
+            if original.is_some_and(|original| self.skipped.contains(&original)) {
+                warn!(
+                    "Leaving out fd {} of process {old_pid}, it's the same file as one that was left out",
+                    dump.fd
+                );
+                self.skipped.insert((old_pid, dump.fd));
+            }
+            if self.skipped.contains(&(old_pid, dump.fd)) {
+                continue;
+            }
+
+            debug!("Restoring fd {} as {:?}", dump.fd, dump.kind);
+            match &dump.kind {
+                FdKind::Epoll(targets) => self.restore_epoll(&remote, old_pid, dump, targets)?,
+                FdKind::Dup(fd) => {
+                    remote.call("dup2", SYS_dup2, &[*fd as u64, dump.fd as u64])?;
+                }
+                _ => {
+                    let file = self.open(dump)?;
+                    self.send(&remote, file.as_fd(), dump.fd)?;
+                }
+            }
+
+            if dump.flags & O_CLOEXEC != 0 {
+                let args = [dump.fd as u64, F_SETFD as u64, FD_CLOEXEC as u64];
+                remote.call("fcntl", SYS_fcntl, &args)?;
+            }
+        }
+
+        remote.call("close", SYS_close, &[self.channel_fd as u64])?;
+        Ok(())
+    }
+
+    /// Makes the file that `dump` gets restored as, or gets it from the process that has it
+    fn open(&mut self, dump: &FdDump) -> Result<OwnedFd, Box<dyn Error>> {
+        let file = match &dump.kind {
+            FdKind::Path(path) => {
+                let flags = (dump.flags & !O_CLOEXEC) | O_CLOEXEC | O_NOCTTY;
+                let path = CString::new(path.to_str().unwrap())?;
+                let fd = unsafe { libc::open(path.as_ptr(), flags) };
+                return Ok(unsafe { OwnedFd::from_raw_fd(cvt(fd as i64)? as RawFd) });
+            }
+            FdKind::Anon { dev, inode, offset } => {
+                let Some(file) = self.anon.get(&(*dev, *inode)) else {
+                    return Err(format!("fd {} is a file that wasn't dumped", dump.fd).into());
+                };
+
+                // Opened again rather than duped, so that it has an offset and flags of its own
+                let path = CString::new(format!("/proc/self/fd/{}", file.as_raw_fd()))?;
+                let fd =
+                    unsafe { libc::open(path.as_ptr(), (dump.flags & !O_CLOEXEC) | O_CLOEXEC) };
+                let file = unsafe { OwnedFd::from_raw_fd(cvt(fd as i64)? as RawFd) };
+                cvt(unsafe { libc::lseek(file.as_raw_fd(), *offset as i64, SEEK_SET) })?;
+                return Ok(file);
+            }
+            FdKind::Shared { pid, fd } => {
+                let Some(pid) = self.pids.get(pid) else {
+                    return Err(format!(
+                        "process {pid} wasn't restored before fd {} that it shares",
+                        dump.fd
+                    )
+                    .into());
+                };
+                return Ok(getfd(*pid, *fd)?);
+            }
+            FdKind::Pipe(end) => self.pipe(end)?,
+            FdKind::Socket(sock) => self.socket(sock)?,
+            FdKind::EventFd(eventfd) => eventfd.create()?,
+            FdKind::TimerFd(timerfd) => timerfd.create()?,
+            FdKind::SignalFd(mask) => {
+                let fd = unsafe {
+                    libc::syscall(
+                        SYS_signalfd4,
+                        -1,
+                        mask as *const u64,
+                        SIGSET_SIZE,
+                        SFD_CLOEXEC,
+                    )
+                };
+                unsafe { OwnedFd::from_raw_fd(cvt(fd)? as RawFd) }
+            }
+            FdKind::Epoll(_) | FdKind::Dup(_) | FdKind::Unsupported(_) => unreachable!(),
+        };
+
+        let res = unsafe { libc::fcntl(file.as_raw_fd(), F_SETFL, dump.flags & STATUS_FLAGS) };
+        cvt(res as i64)?;
+
+        Ok(file)
+    }
+
+    /// Gets the end `end` of a pipe, making the pipe with what was in it the first time
+    fn pipe(&mut self, end: &PipeEnd) -> Result<OwnedFd, Box<dyn Error>> {
+        if !self.pipes.contains_key(&(end.inode, end.write)) {
+            let (read, write) = make_pipe()?;
+            if unsafe { libc::fcntl(write.as_raw_fd(), F_SETPIPE_SZ, end.size) } < 0 {
+                warn!(
+                    "Couldn't resize pipe:[{}] to {} bytes: {}",
+                    end.inode,
+                    end.size,
+                    io::Error::last_os_error()
+                );
+            }
+
+            // Non-blocking, so that a pipe we couldn't make big enough errors out instead of hanging
+            let mut file = File::from(write.try_clone()?);
+            cvt(unsafe { libc::fcntl(write.as_raw_fd(), F_SETFL, O_NONBLOCK) } as i64)?;
+            file.write_all(&end.contents).map_err(|e| {
+                format!(
+                    "couldn't put the {} bytes in pipe:[{}] back: {e}",
+                    end.contents.len(),
+                    end.inode
+                )
+            })?;
+
+            self.pipes.insert((end.inode, false), read);
+            self.pipes.insert((end.inode, true), write);
+        }
+
+        Ok(self.pipes[&(end.inode, end.write)].try_clone()?)
+    }
+
+    /// Gets the socket `sock`, making it (and its peer) with what was queued on them the first time
+    fn socket(&mut self, sock: &UnixSocket) -> Result<OwnedFd, Box<dyn Error>> {
+        if !self.sockets.contains_key(&sock.inode) {
+            let mut fds = [0; 2];
+            if !sock.connected {
+                let fd = unsafe { libc::socket(AF_UNIX, sock.kind | SOCK_CLOEXEC, 0) };
+                fds[0] = cvt(fd as i64)? as RawFd;
+            } else {
+                let kind = sock.kind | SOCK_CLOEXEC;
+                cvt(unsafe { libc::socketpair(AF_UNIX, kind, 0, fds.as_mut_ptr()) } as i64)?;
+            }
+            let [ours, theirs] =
+                fds.map(|fd| (fd >= 0).then(|| unsafe { OwnedFd::from_raw_fd(fd) }));
+            let ours = ours.unwrap();
+
+            // The data queued on a socket was sent from its peer. Without a peer,
+            // the peer was closed and we drop ours for the same effect
+            let peer = sock
+                .peer
+                .and_then(|peer| self.socket_dumps.get(&peer))
+                .cloned();
+            match &theirs {
+                Some(theirs) => {
+                    fill(theirs, sock)?;
+                    if let Some(peer) = &peer {
+                        fill(&ours, peer)?;
+                        shutdown(theirs, peer.shutdown)?;
+                    }
+                }
+                None if !sock.queue.is_empty() => warn!(
+                    "Dropping the {} messages queued on unconnected socket:[{}]",
+                    sock.queue.len(),
+                    sock.inode
+                ),
+                None => {}
+            }
+            shutdown(&ours, sock.shutdown)?;
+
+            self.sockets.insert(sock.inode, ours);
+            if let (Some(peer), Some(theirs)) = (peer, theirs) {
+                self.sockets.insert(peer.inode, theirs);
+            }
+        }
+
+        Ok(self.sockets[&sock.inode].try_clone()?)
+    }
+
+    /// Hands `file` to the bootstrapper `remote` as `fd`
+    fn send(&self, remote: &Remote, file: BorrowedFd, fd: i32) -> Result<(), Box<dyn Error>> {
+        let received = self.receive(remote, file)?;
+        remote.place(received, fd)
+    }
+
+    /// Has the bootstrapper `remote` receive `file`, and returns the fd it got it as
+    fn receive(&self, remote: &Remote, file: BorrowedFd) -> Result<u64, Box<dyn Error>> {
+        send_fd(self.channel.as_fd(), file)?;
+
+        // A struct msghdr, its one iovec for its one byte of data, and room for one fd
+        let scratch = remote.scratch;
+        let (iov, data, control) = (scratch + 56, scratch + 72, scratch + 80);
+        let control_len = unsafe { libc::CMSG_SPACE(mem::size_of::<c_int>() as u32) } as u64;
+
+        let mut msg = vec![];
+        for word in [0, 0, iov, 1, control, control_len, 0, data, 1, 0] {
+            msg.extend(u64::to_ne_bytes(word));
+        }
+        msg.resize(msg.len() + control_len as usize, 0);
+        remote.mem.write_all_at(&msg, scratch)?;
+
+        let args = [self.channel_fd as u64, scratch, 0];
+        let res = remote.call("recvmsg", SYS_recvmsg, &args)?;
+
+        // Anything but our one byte with one SCM_RIGHTS fd means the fd never made it,
+        // and what's left in the control buffer are the zeroes we put there
+        let mut header = [0; 56];
+        remote.mem.read_exact_at(&mut header, scratch)?;
+        let word =
+            |bytes: &[u8], at: usize| u64::from_ne_bytes(bytes[at..at + 8].try_into().unwrap());
+        let msg_controllen = word(&header, 40);
+        let msg_flags = word(&header, 48) as c_int;
+
+        let mut cmsg = [0; 20];
+        remote.mem.read_exact_at(&mut cmsg, control)?;
+        let cmsg_len = word(&cmsg, 0);
+        let cmsg_level = c_int::from_ne_bytes(cmsg[8..12].try_into().unwrap());
+        let cmsg_type = c_int::from_ne_bytes(cmsg[12..16].try_into().unwrap());
+        let fd_len = unsafe { libc::CMSG_LEN(mem::size_of::<c_int>() as u32) } as u64;
+
+        if res != 1
+            || msg_flags & MSG_CTRUNC != 0
+            || msg_controllen < fd_len
+            || (cmsg_level, cmsg_type, cmsg_len) != (SOL_SOCKET, SCM_RIGHTS, fd_len)
+        {
+            return Err(format!(
+                "the bootstrapper {} didn't get an fd over its channel \
+                 (got {res} bytes, flags {msg_flags:#x}, control message {cmsg_level}/{cmsg_type} of {cmsg_len} bytes)",
+                remote.ptrace.pid
+            )
+            .into());
+        }
+        let received = i32::from_ne_bytes(cmsg[16..20].try_into().unwrap());
+
+        Ok(received as u64)
+    }
+
+    /// Maps `file` over the memory that the bootstrapper `remote` made for `map`
+    /// out of its image, keeping what's in there if the mapping is private
+    fn map_anon(
+        &self,
+        remote: &Remote,
+        map: &MemoryMap,
+        file: &File,
+    ) -> Result<(), Box<dyn Error>> {
+        let (addr, end) = map.address;
+        let len = end - addr;
+        let shared = map.perms.contains(MMPermissions::SHARED);
+
+        // The image of a shared mapping is just what's in the file, but the pages of
+        // a private one can be anything, which they can't have been if it's unreadable
+        let contents = match !shared && map.perms.contains(MMPermissions::READ) {
+            true => {
+                let mut contents = vec![0; len as usize];
+                remote.mem.read_exact_at(&mut contents, addr)?;
+                Some(contents)
+            }
+            false => None,
+        };
+
+        let prot =
+            map.perms & (MMPermissions::READ | MMPermissions::WRITE | MMPermissions::EXECUTE);
+        let flags = MAP_FIXED | if shared { MAP_SHARED } else { MAP_PRIVATE };
+        let fd = self.receive(remote, file.as_fd())?;
+        let args = [addr, len, prot.bits() as u64, flags as u64, fd, map.offset];
+        remote.call("mmap", SYS_mmap, &args)?;
+        remote.call("close", SYS_close, &[fd])?;
+
+        if let Some(contents) = contents {
+            remote.mem.write_all_at(&contents, addr)?;
+        }
+
+        Ok(())
+    }
+
+    /// Makes the epoll instance `dump` in the bootstrapper `remote`,
+    /// watching whichever of its `targets` weren't left out
+    fn restore_epoll(
+        &self,
+        remote: &Remote,
+        old_pid: pid_t,
+        dump: &FdDump,
+        targets: &[EpollTarget],
+    ) -> Result<(), Box<dyn Error>> {
+        let epoll = remote.call("epoll_create1", SYS_epoll_create1, &[0])?;
+        remote.place(epoll, dump.fd)?;
+
+        for target in targets {
+            if self.skipped.contains(&(old_pid, target.fd)) {
+                warn!(
+                    "The epoll at fd {} won't watch fd {}, it was left out",
+                    dump.fd, target.fd
+                );
+                continue;
+            }
+
+            // A struct epoll_event, which is packed
+            let mut event = target.events.to_ne_bytes().to_vec();
+            event.extend(target.data.to_ne_bytes());
+            remote.mem.write_all_at(&event, remote.scratch)?;
+
+            let args = [
+                dump.fd as u64,
+                EPOLL_CTL_ADD as u64,
+                target.fd as u64,
+                remote.scratch,
+            ];
+            remote.call("epoll_ctl", SYS_epoll_ctl, &args)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl EventFd {
+    fn create(&self) -> io::Result<OwnedFd> {
+        let mut flags = EFD_CLOEXEC;
+        if self.semaphore {
+            flags |= EFD_SEMAPHORE;
+        }
+
+        let fd = unsafe { libc::eventfd(0, flags) };
+        let mut file = unsafe { File::from_raw_fd(cvt(fd as i64)? as RawFd) };
+        if self.count > 0 {
+            file.write_all(&self.count.to_ne_bytes())?;
+        }
+
+        Ok(file.into())
+    }
+}
+
+impl TimerFd {
+    fn create(&self) -> io::Result<OwnedFd> {
+        let fd = unsafe { libc::timerfd_create(self.clockid, TFD_CLOEXEC) };
+        let fd = unsafe { OwnedFd::from_raw_fd(cvt(fd as i64)? as RawFd) };
+
+        // It's set by the time left, so it doesn't matter whether it was set with an absolute time
+        // A periodic timer that expired isn't set again until it's read, so it has no time left
+        let value = match self.value {
+            (0, 0) => self.interval,
+            value => value,
+        };
+
+        let timespec = |(tv_sec, tv_nsec)| libc::timespec { tv_sec, tv_nsec };
+        let spec = libc::itimerspec {
+            it_interval: timespec(self.interval),
+            it_value: timespec(value),
+        };
+        if value != (0, 0) {
+            let res =
+                unsafe { libc::timerfd_settime(fd.as_raw_fd(), 0, &spec, std::ptr::null_mut()) };
+            cvt(res as i64)?;
+        }
+
+        if self.ticks > 0 {
+            let res = unsafe { libc::ioctl(fd.as_raw_fd(), TFD_IOC_SET_TICKS, &self.ticks) };
+            if res < 0 {
+                warn!(
+                    "Couldn't restore the {} expirations of a timerfd (needs CONFIG_CHECKPOINT_RESTORE): {}",
+                    self.ticks,
+                    io::Error::last_os_error()
+                );
+            }
+        }
+
+        Ok(fd)
+    }
+}
+
+/// A stopped bootstrapper that we make syscalls as, with `SCRATCH_LEN`
+/// bytes of memory at `scratch` for their arguments
+struct Remote<'a> {
+    ptrace: &'a PTrace,
+    gadget: u64,
+    scratch: u64,
+    mem: File,
+}
+
+impl Remote<'_> {
+    fn call(&self, name: &str, nr: c_long, args: &[u64]) -> Result<u64, Box<dyn Error>> {
+        let res = self.ptrace.syscall(self.gadget, nr, args)?;
+        if res < 0 {
+            return Err(format!(
+                "{name} failed in the bootstrapper {}: {}",
+                self.ptrace.pid,
+                io::Error::from_raw_os_error(-res as i32)
+            )
+            .into());
+        }
+
+        Ok(res as u64)
+    }
+
+    /// Moves the fd `from` to `to`
+    fn place(&self, from: u64, to: i32) -> Result<(), Box<dyn Error>> {
+        if from != to as u64 {
+            self.call("dup2", SYS_dup2, &[from, to as u64])?;
+            self.call("close", SYS_close, &[from])?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Sends `fd` over the unix socket `channel`
+fn send_fd(channel: BorrowedFd, fd: BorrowedFd) -> io::Result<()> {
+    let mut data = 0u8;
+    let mut iov = libc::iovec {
+        iov_base: (&mut data as *mut u8).cast(),
+        iov_len: 1,
+    };
+    // Room for one fd, aligned like a struct cmsghdr
+    let mut control = [0u64; 3];
+
+    unsafe {
+        let mut msg: libc::msghdr = mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr().cast();
+        msg.msg_controllen = libc::CMSG_SPACE(mem::size_of::<c_int>() as u32) as usize;
+
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = SOL_SOCKET;
+        (*cmsg).cmsg_type = SCM_RIGHTS;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<c_int>() as u32) as usize;
+        *libc::CMSG_DATA(cmsg).cast::<c_int>() = fd.as_raw_fd();
+
+        cvt(libc::sendmsg(channel.as_raw_fd(), &msg, 0) as i64)?;
+    }
+
+    Ok(())
+}
+
+/// Sends the messages queued on `sock` from its peer `peer`
+fn fill(peer: &OwnedFd, sock: &UnixSocket) -> Result<(), Box<dyn Error>> {
+    for msg in &sock.queue {
+        let flags = MSG_DONTWAIT | MSG_NOSIGNAL;
+        let sent = unsafe { libc::send(peer.as_raw_fd(), msg.as_ptr().cast(), msg.len(), flags) };
+        if sent != msg.len() as isize {
+            return Err(format!(
+                "couldn't put the messages queued on socket:[{}] back: {}",
+                sock.inode,
+                io::Error::last_os_error()
+            )
+            .into());
+        }
+    }
+
+    Ok(())
+}
+
+/// Shuts down what `shutdown` says of `sock`, like sock_diag has it
+fn shutdown(sock: &OwnedFd, shutdown: u8) -> io::Result<()> {
+    // 1 (receiving) is SHUT_RD, 2 (sending) is SHUT_WR and 3 is SHUT_RDWR
+    if shutdown != 0 {
+        cvt(unsafe { libc::shutdown(sock.as_raw_fd(), shutdown as c_int - 1) } as i64)?;
+    }
+
+    Ok(())
+}
+
+/// Gets our own copy of `fd` of the process `pid`
+fn getfd(pid: pid_t, fd: i32) -> io::Result<OwnedFd> {
+    let pidfd = unsafe { libc::syscall(SYS_pidfd_open, pid, 0) };
+    let pidfd = unsafe { OwnedFd::from_raw_fd(cvt(pidfd)? as RawFd) };
+
+    let fd = unsafe { libc::syscall(SYS_pidfd_getfd, pidfd.as_raw_fd(), fd, 0) };
+    Ok(unsafe { OwnedFd::from_raw_fd(cvt(fd)? as RawFd) })
+}
+
+fn make_pipe() -> io::Result<(OwnedFd, OwnedFd)> {
+    let mut fds = [0; 2];
+    cvt(unsafe { libc::pipe2(fds.as_mut_ptr(), O_CLOEXEC) } as i64)?;
+    let [read, write] = fds.map(|fd| unsafe { OwnedFd::from_raw_fd(fd) });
+    Ok((read, write))
+}
+
+/// Turns the -1 that libc fails with into the error
+fn cvt(res: i64) -> io::Result<i64> {
+    match res {
+        0.. => Ok(res),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/// The value of `name` in the fdinfo `info`
+pub fn field<'a>(info: &'a str, name: &str) -> Result<&'a str, Box<dyn Error>> {
+    info.lines()
+        .find_map(|line| line.strip_prefix(name)?.strip_prefix(':'))
+        .map(str::trim)
+        .ok_or_else(|| format!("fdinfo has no {name}").into())
+}
+
+/// A `(seconds, nanoseconds)` value of the fdinfo `info`
+fn timespec_field(info: &str, name: &str) -> Result<(i64, i64), Box<dyn Error>> {
+    let raw = field(info, name)?;
+    let (secs, nsecs) = raw
+        .trim_matches(['(', ')'])
+        .split_once(',')
+        .ok_or_else(|| format!("fdinfo has a malformed {name}: {raw}"))?;
+
+    Ok((secs.trim().parse()?, nsecs.trim().parse()?))
+}
+
+/// The interest set of an epoll instance, from its fdinfo `info`, which has one line like
+/// `tfd: 5 events: 19 data: 5 pos:0 ino:1a sdev:f` for each fd in it
+fn epoll_targets(info: &str) -> Result<Vec<EpollTarget>, Box<dyn Error>> {
+    let mut targets = vec![];
+    for line in info.lines().filter(|line| line.starts_with("tfd:")) {
+        let words: Vec<_> = line.split_whitespace().collect();
+        let [_, fd, _, events, _, data, ..] = words[..] else {
+            return Err(format!("fdinfo has a malformed epoll target: {line}").into());
+        };
+
+        targets.push(EpollTarget {
+            fd: fd.parse()?,
+            events: u32::from_str_radix(events, 16)?,
+            data: u64::from_str_radix(data, 16)?,
+        });
+    }
+
+    Ok(targets)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process;
+
+    use libc::SOCK_SEQPACKET;
+
+    use super::*;
+
+    fn socketpair(kind: c_int) -> (OwnedFd, OwnedFd) {
+        let mut fds = [0; 2];
+        let res = unsafe { libc::socketpair(AF_UNIX, kind | SOCK_CLOEXEC, 0, fds.as_mut_ptr()) };
+        cvt(res as i64).unwrap();
+        let [a, b] = fds.map(|fd| unsafe { OwnedFd::from_raw_fd(fd) });
+        (a, b)
+    }
+
+    fn recv(sock: &OwnedFd) -> Vec<u8> {
+        let mut buf = [0u8; 64];
+        let n = unsafe {
+            libc::recv(
+                sock.as_raw_fd(),
+                buf.as_mut_ptr().cast(),
+                buf.len(),
+                MSG_DONTWAIT,
+            )
+        };
+        buf[..cvt(n as i64).unwrap() as usize].to_vec()
+    }
+
+    /// Dumps our own fds `fds`
+    fn capture(fds: &[RawFd]) -> Vec<FdDump> {
+        let procfs = Process::new(process::id() as pid_t).unwrap();
+        let files: Vec<_> = procfs
+            .fd()
+            .unwrap()
+            .flatten()
+            .filter(|f| fds.contains(&f.fd))
+            .map(|f| {
+                let info = fs::read_to_string(format!("/proc/self/fdinfo/{}", f.fd)).unwrap();
+                OpenFile {
+                    fd: f.fd,
+                    target: f.target,
+                    offset: 0,
+                    flags: i32::from_str_radix(field(&info, "flags").unwrap(), 8).unwrap(),
+                    mnt_id: None,
+                    identity: None,
+                }
+            })
+            .collect();
+        let mut dumps = FdDump::capture_all(&procfs, &files).unwrap();
+        dumps.sort_by_key(|d| fds.iter().position(|&fd| fd == d.fd));
+        dumps
+    }
+
+    #[test]
+    fn dumped_fds_are_made_again_with_their_contents() {
+        let (read, write) = make_pipe().unwrap();
+        File::from(write.try_clone().unwrap())
+            .write_all(b"hello")
+            .unwrap();
+        let dup = read.try_clone().unwrap();
+        let (ours, theirs) = socketpair(SOCK_SEQPACKET);
+        for msg in [&b"a"[..], b"bc"] {
+            unsafe { libc::send(theirs.as_raw_fd(), msg.as_ptr().cast(), msg.len(), 0) };
+        }
+        let eventfd = EventFd {
+            count: 3,
+            semaphore: false,
+        }
+        .create()
+        .unwrap();
+
+        let fds = [&read, &write, &dup, &ours, &theirs, &eventfd].map(|fd| fd.as_raw_fd());
+        let dumps = capture(&fds);
+        assert_eq!(dumps.len(), fds.len());
+
+        let FdKind::Pipe(end) = &dumps[0].kind else {
+            panic!("{:?} isn't a pipe", dumps[0]);
+        };
+        assert_eq!((end.write, end.contents.as_slice()), (false, &b"hello"[..]));
+        assert!(matches!(&dumps[1].kind, FdKind::Pipe(end) if end.write));
+        assert!(matches!(dumps[2].kind, FdKind::Dup(fd) if fd == fds[0]));
+        let FdKind::Socket(sock) = &dumps[3].kind else {
+            panic!("{:?} isn't a socket", dumps[3]);
+        };
+        assert_eq!(sock.queue, [&b"a"[..], b"bc"]);
+        assert!(sock.connected && sock.kind == SOCK_SEQPACKET);
+        assert!(matches!(&dumps[5].kind, FdKind::EventFd(e) if e.count == 3));
+
+        // Which were only peeked at
+        assert_eq!(recv(&ours), b"a");
+        let mut contents = [0; 5];
+        File::from(read).read_exact(&mut contents).unwrap();
+        assert_eq!(&contents, b"hello");
+
+        // Everything comes back in new files
+        let pid = process::id() as pid_t;
+        let mut restorer = FdRestorer::new(FdPolicy::Error, &[(pid, &dumps)], 1000).unwrap();
+        let pipe = restorer.open(&dumps[0]).unwrap();
+        File::from(pipe).read_exact(&mut contents).unwrap();
+        assert_eq!(&contents, b"hello");
+        let sock = restorer.open(&dumps[3]).unwrap();
+        assert_eq!((recv(&sock), recv(&sock)), (b"a".to_vec(), b"bc".to_vec()));
+        let mut count = [0; 8];
+        File::from(restorer.open(&dumps[5]).unwrap())
+            .read_exact(&mut count)
+            .unwrap();
+        assert_eq!(u64::from_ne_bytes(count), 3);
+    }
+
+    #[test]
+    fn half_a_pipe_is_unrestorable() {
+        let (read, _write) = make_pipe().unwrap();
+        let dumps = capture(&[read.as_raw_fd()]);
+        let pid = process::id() as pid_t;
+
+        assert!(FdRestorer::new(FdPolicy::Error, &[(pid, &dumps)], 1000).is_err());
+        let restorer = FdRestorer::new(FdPolicy::Skip, &[(pid, &dumps)], 1000).unwrap();
+        assert!(restorer.skipped.contains(&(pid, read.as_raw_fd())));
+    }
+}
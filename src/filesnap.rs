@@ -0,0 +1,311 @@
+use std::{
+    collections::HashSet,
+    error::Error,
+    fs::{self, File, OpenOptions},
+    io::{ErrorKind, Read},
+    os::unix::fs::FileExt,
+    path::{Path, PathBuf},
+};
+
+use glob::Pattern;
+use libc::{pid_t, O_ACCMODE, O_RDONLY};
+use log::{debug, info, warn};
+use procfs::process::FDTarget;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    anon::is_deleted,
+    checkpoint::{FileIdentity, OpenFile},
+    compress::Compression,
+};
+
+/// Which of the files a process has open for writing get snapshotted
+#[derive(Debug, Clone)]
+pub struct SnapshotConfig {
+    /// Files bigger than this are left alone
+    pub max_size: u64,
+    /// Only files with a path that matches one of these, or every file if it's empty
+    pub include: Vec<Pattern>,
+    /// Files with a path that matches one of these are left alone
+    pub exclude: Vec<Pattern>,
+}
+
+impl Default for SnapshotConfig {
+    fn default() -> Self {
+        Self {
+            max_size: 64 << 20,
+            include: vec![],
+            exclude: vec![],
+        }
+    }
+}
+
+impl SnapshotConfig {
+    fn wants(&self, path: &Path) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|p| p.matches_path(path)))
+            && !self.exclude.iter().any(|p| p.matches_path(path))
+    }
+}
+
+/// A regular file that the process had open for writing, as it was at checkpoint time,
+/// so that the restore can take back whatever the process wrote to it since
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileSnapshot {
+    pub path: PathBuf,
+    pub identity: FileIdentity,
+    /// What the contents are compressed with, older checkpoints didn't
+    #[serde(default)]
+    pub compression: Compression,
+    /// What was in the file, which is written next to the list of snapshots
+    #[serde(skip)]
+    pub contents: Vec<u8>,
+}
+
+impl FileSnapshot {
+    /// Reads every regular file in `files` of the stopped process `pid` that's open for
+    /// writing and that `config` wants, once no matter how many fds it's open at.
+    /// They're saved with `compression`.
+    pub fn capture_all(
+        pid: pid_t,
+        files: &[OpenFile],
+        config: &SnapshotConfig,
+        compression: Compression,
+    ) -> Result<Vec<Self>, Box<dyn Error>> {
+        let mut snapshots = vec![];
+        let mut seen = HashSet::new();
+        for file in files {
+            let (FDTarget::Path(path), Some(identity)) = (&file.target, &file.identity) else {
+                continue;
+            };
+            // Those are dumped whole anyways, see `AnonFile`
+            if file.flags & O_ACCMODE == O_RDONLY || is_deleted(path) || !config.wants(path) {
+                continue;
+            }
+            if !seen.insert((identity.dev, identity.inode)) {
+                continue;
+            }
+
+            // Through /proc, so that we get the file even if it was renamed
+            let fd_path = format!("/proc/{pid}/fd/{}", file.fd);
+            if !fs::metadata(&fd_path)?.is_file() {
+                continue;
+            }
+            if identity.size > config.max_size {
+                info!(
+                    "Not snapshotting {path:?}, it's {} bytes, which is over the limit",
+                    identity.size
+                );
+                continue;
+            }
+
+            debug!("Snapshotting {path:?} at fd {}", file.fd);
+            snapshots.push(Self {
+                path: path.clone(),
+                identity: identity.clone(),
+                compression,
+                contents: fs::read(fd_path)?,
+            });
+        }
+
+        Ok(snapshots)
+    }
+
+    /// Writes `snapshots` to the checkpoint in `cp_dir`, the contents of each in its own file
+    pub fn persist_all(snapshots: &[Self], cp_dir: &Path) -> Result<(), Box<dyn Error>> {
+        for (i, snapshot) in snapshots.iter().enumerate() {
+            let contents = snapshot.compression.compress(&snapshot.contents)?;
+            fs::write(cp_dir.join(format!("snapshot.{i}")), contents)?;
+        }
+        serde_json::to_writer(File::create(cp_dir.join("snapshots"))?, snapshots)?;
+
+        Ok(())
+    }
+
+    /// Reads the snapshots of the checkpoint in `cp_dir`, without their contents,
+    /// which older checkpoints and ones that didn't snapshot anything don't have
+    pub fn open_all(cp_dir: &Path) -> Result<Vec<Self>, Box<dyn Error>> {
+        match File::open(cp_dir.join("snapshots")) {
+            Ok(file) => Ok(serde_json::from_reader(file)?),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(vec![]),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Puts every file in `snapshots`, of the checkpoint in `cp_dir`, back to how it was,
+    /// in place, and makes it again if it's gone. What was in them before goes in `undo`.
+    ///
+    /// A different file at the path (the one we snapshotted was renamed or replaced) is
+    /// left alone, `check_files` in the restore decides what happens with those.
+    pub fn roll_back_all(
+        snapshots: &[Self],
+        cp_dir: &Path,
+        undo: &mut RolledBack,
+    ) -> Result<(), Box<dyn Error>> {
+        for (i, snapshot) in snapshots.iter().enumerate() {
+            // Not truncated first, which would leave it empty for a while to
+            // anyone else that has it open, like the process we checkpointed
+            let (file, before) = match OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(&snapshot.path)
+            {
+                Ok(file) => {
+                    if !snapshot.is_file(&FileIdentity::from(&file.metadata()?)) {
+                        warn!(
+                            "Not rolling back {:?}, it's no longer the file that was snapshotted",
+                            snapshot.path
+                        );
+                        continue;
+                    }
+                    let mut before = vec![];
+                    (&file).read_to_end(&mut before)?;
+                    (file, Some(before))
+                }
+                Err(e) if e.kind() == ErrorKind::NotFound => {
+                    let file = OpenOptions::new()
+                        .write(true)
+                        .create_new(true)
+                        .open(&snapshot.path)?;
+                    (file, None)
+                }
+                Err(e) => return Err(e.into()),
+            };
+            undo.0.push((snapshot.path.clone(), before));
+
+            info!("Rolling back {:?}", snapshot.path);
+            let contents = fs::read(cp_dir.join(format!("snapshot.{i}")))?;
+            let contents = snapshot.compression.decompress(contents)?;
+            file.write_all_at(&contents, 0)?;
+            file.set_len(contents.len() as u64)?;
+        }
+
+        Ok(())
+    }
+
+    /// Whether the file with `identity` is the one that was snapshotted, going by
+    /// its inode since everything else about it is what the rollback changes
+    pub fn is_file(&self, identity: &FileIdentity) -> bool {
+        (identity.dev, identity.inode) == (self.identity.dev, self.identity.inode)
+    }
+}
+
+/// What the files that a restore rolled back had in them before, or `None` for ones it
+/// made again, which are put back when this is dropped, unless the restore got through
+/// and `keep`s them rolled back
+#[derive(Debug, Default)]
+pub struct RolledBack(pub Vec<(PathBuf, Option<Vec<u8>>)>);
+
+impl RolledBack {
+    pub fn keep(mut self) {
+        self.0.clear();
+    }
+}
+
+impl Drop for RolledBack {
+    fn drop(&mut self) {
+        for (path, before) in &self.0 {
+            debug!("Undoing the rollback of {path:?}, the restore failed");
+            let res = match before {
+                Some(before) => OpenOptions::new().write(true).open(path).and_then(|file| {
+                    file.write_all_at(before, 0)?;
+                    file.set_len(before.len() as u64)
+                }),
+                None => fs::remove_file(path),
+            };
+            if let Err(e) = res {
+                warn!("Couldn't undo the rollback of {path:?}: {e}");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, os::fd::AsRawFd, process};
+
+    use libc::O_RDWR;
+
+    use super::*;
+    use crate::compress::Algorithm;
+
+    #[test]
+    fn roll_back_puts_back_what_was_captured() {
+        let dir = env::temp_dir().join(format!("filesnap-test-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("log");
+        fs::write(&path, b"first").unwrap();
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .unwrap();
+
+        let open = |fd, flags| OpenFile {
+            fd,
+            target: FDTarget::Path(path.clone()),
+            offset: 0,
+            flags,
+            mnt_id: None,
+            identity: Some(FileIdentity::from(&fs::metadata(&path).unwrap())),
+        };
+        // The same file twice, and once more read only
+        let files = [
+            open(file.as_raw_fd(), O_RDWR),
+            open(file.as_raw_fd(), O_RDWR),
+            open(file.as_raw_fd(), O_RDONLY),
+        ];
+        let pid = process::id() as pid_t;
+
+        let excluded = SnapshotConfig {
+            exclude: vec![Pattern::new(&format!("{}/*", dir.display())).unwrap()],
+            ..Default::default()
+        };
+        let compression = Compression {
+            algorithm: Algorithm::Zstd,
+            level: 3,
+        };
+        assert!(
+            FileSnapshot::capture_all(pid, &files, &excluded, compression)
+                .unwrap()
+                .is_empty()
+        );
+
+        let snapshots =
+            FileSnapshot::capture_all(pid, &files, &Default::default(), compression).unwrap();
+        assert_eq!(snapshots.len(), 1);
+        assert_eq!(snapshots[0].contents, b"first");
+        FileSnapshot::persist_all(&snapshots, &dir).unwrap();
+        let snapshots = FileSnapshot::open_all(&dir).unwrap();
+
+        file.write_all_at(b"second, longer", 0).unwrap();
+        let roll_back = || {
+            let mut undo = RolledBack::default();
+            FileSnapshot::roll_back_all(&snapshots, &dir, &mut undo).unwrap();
+            undo
+        };
+        // A failed restore puts back what it rolled back
+        let undo = roll_back();
+        assert_eq!(fs::read(&path).unwrap(), b"first");
+        drop(undo);
+        assert_eq!(fs::read(&path).unwrap(), b"second, longer");
+        roll_back().keep();
+        assert_eq!(fs::read(&path).unwrap(), b"first");
+
+        // And removes what it made again
+        fs::remove_file(&path).unwrap();
+        let undo = roll_back();
+        assert_eq!(fs::read(&path).unwrap(), b"first");
+        drop(undo);
+        assert!(!path.exists());
+        roll_back().keep();
+        assert_eq!(fs::read(&path).unwrap(), b"first");
+
+        // A different file in its place is left alone
+        fs::write(dir.join("new"), b"other").unwrap();
+        fs::rename(dir.join("new"), &path).unwrap();
+        roll_back().keep();
+        assert_eq!(fs::read(&path).unwrap(), b"other");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,4 +1,21 @@
+pub mod anon;
+pub mod attrs;
 pub mod checkpoint;
 pub mod compat;
+pub mod compress;
+pub mod fd;
+pub mod filesnap;
+pub mod memory;
+pub mod mm;
+pub mod overlay;
+pub mod pagemap;
+pub mod pidns;
 pub mod ptrace;
 pub mod restore;
+pub mod shared;
+pub mod signal;
+pub mod store;
+pub mod thread;
+pub mod tree;
+pub mod vdso;
+pub mod vma;
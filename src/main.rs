@@ -3,14 +3,18 @@ use std::{
     fs::{create_dir, File},
     io::{self, Write},
     process::exit,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
-use clap::{arg, command, Parser};
+use clap::Parser;
+use glob::Pattern;
 use libc::pid_t;
 use project::{
     checkpoint::{self, Checkpointer},
-    restore::restore_checkpoint,
+    compress::{Algorithm, Compression},
+    fd::FdPolicy,
+    filesnap::SnapshotConfig,
+    restore::{restore_checkpoint, FilePolicy, RestoreConfig},
 };
 
 /// SLSify compute-oriented applications
@@ -57,6 +61,92 @@ enum Args {
         /// A path to store checkpointing statistics.
         #[arg(short, long)]
         stats: Option<String>,
+
+        /// Only save the pages dirtied since the previous checkpoint,
+        /// tracked with the kernel's soft-dirty bits.
+        /// Unchanged pages are restored from earlier checkpoints.
+        #[arg(short, long)]
+        incremental: bool,
+
+        /// The maximum number of incremental checkpoints stacked
+        /// on top of a memory region before it is saved in full again.
+        /// Only takes effect if `incremental` is specified.
+        #[arg(long, default_value = "8")]
+        max_chain: u64,
+
+        /// Have the process fork at each checkpoint and copy its memory
+        /// out of the child, so that it is only stopped for as long as
+        /// the fork takes rather than for the whole memory copy.
+        #[arg(long)]
+        cow: bool,
+
+        /// Before each checkpoint, copy the process' memory in rounds while
+        /// it keeps running, so that the checkpoint itself only has to stop
+        /// the process to copy the pages dirtied during the last round.
+        #[arg(long, conflicts_with_all = ["incremental", "overhead"])]
+        predump: bool,
+
+        /// The maximum number of pre-dump rounds before each checkpoint.
+        /// Only takes effect if `predump` is specified.
+        #[arg(long, default_value = "8")]
+        predump_rounds: u64,
+
+        /// Pre-dumping stops early once a round copies at most this many bytes.
+        /// Only takes effect if `predump` is specified.
+        #[arg(long, default_value = "1048576")]
+        predump_threshold: u64,
+
+        /// Store memory in a content-addressed chunk store shared by all
+        /// checkpoints, so that data which didn't change between checkpoints
+        /// (or repeats within one) is only written to disk once.
+        #[arg(long)]
+        dedup: bool,
+
+        /// How to compress memory images: none, lz4 or zstd.
+        /// This is fixed when the checkpoint directory is created, so continuing
+        /// to checkpoint into an existing directory has to use the same.
+        #[arg(long, default_value = "none")]
+        compress: Algorithm,
+
+        /// The compression level. Only takes effect with zstd.
+        #[arg(long, default_value = "3")]
+        compress_level: i32,
+
+        /// Also checkpoint every descendant of the process, all frozen at once,
+        /// so that the whole process tree can be restored together.
+        #[arg(long)]
+        tree: bool,
+
+        /// Also save the contents of the regular files that the process has open
+        /// for writing, which restoring rolls them back to, so that whatever
+        /// the process wrote after the checkpoint is taken back too.
+        #[arg(long)]
+        snapshot_files: bool,
+
+        /// Files bigger than this many bytes aren't snapshotted.
+        /// Only takes effect if `snapshot_files` is specified.
+        #[arg(long, default_value = "67108864")]
+        snapshot_max_size: u64,
+
+        /// Only snapshot the files with a path that matches one of these globs.
+        /// Only takes effect if `snapshot_files` is specified.
+        #[arg(long)]
+        snapshot_include: Vec<Pattern>,
+
+        /// Don't snapshot the files with a path that matches one of these globs.
+        /// Only takes effect if `snapshot_files` is specified.
+        #[arg(long)]
+        snapshot_exclude: Vec<Pattern>,
+
+        /// Also save the memory the process marked with MADV_DONTDUMP, which is
+        /// left out by default and comes back zeroed.
+        #[arg(long)]
+        dump_dontdump: bool,
+
+        /// Save private mappings of files in full. By default only the pages that
+        /// differ from the file are saved, and restoring needs the file unchanged.
+        #[arg(long)]
+        dump_file_maps: bool,
     },
 
     Restore {
@@ -69,6 +159,25 @@ enum Args {
         /// attach gdb to it and debug the restoration.
         #[arg(long)]
         hang: bool,
+
+        /// Give the restored process the pid it had when it was checkpointed,
+        /// and its threads and descendants theirs, in new user and pid
+        /// namespaces (which doesn't take any privileges).
+        /// It gets its own /proc too, unless the system doesn't allow mounting
+        /// one, in which case it sees its pid from outside in there.
+        #[arg(long)]
+        same_pid: bool,
+
+        /// What to do with open files of the process that can't be restored,
+        /// like inotify instances or pipes to processes that weren't checkpointed:
+        /// error, or skip to restore the process without them.
+        #[arg(long, default_value = "error")]
+        unsupported_fds: FdPolicy,
+
+        /// What to do with open files that were replaced or truncated since the
+        /// checkpoint: fail, warn and reopen them, or just reopen them.
+        #[arg(long, default_value = "warn")]
+        changed_files: FilePolicy,
     },
 }
 
@@ -85,6 +194,22 @@ fn main() -> Result<(), Box<dyn error::Error>> {
             reset,
             overhead,
             stats,
+            incremental,
+            max_chain,
+            cow,
+            predump,
+            predump_rounds,
+            predump_threshold,
+            dedup,
+            compress,
+            compress_level,
+            tree,
+            snapshot_files,
+            snapshot_max_size,
+            snapshot_include,
+            snapshot_exclude,
+            dump_dontdump,
+            dump_file_maps,
         } => {
             if reset {
                 checkpoint::maybe_remove_dir_all(&cpath)?;
@@ -102,6 +227,24 @@ fn main() -> Result<(), Box<dyn error::Error>> {
             };
 
             let mut cp = Checkpointer::attach(pid, cpath.clone().into())?;
+            cp.config.incremental = incremental;
+            cp.config.max_chain = max_chain;
+            cp.config.cow = cow;
+            cp.config.predump_rounds = predump_rounds;
+            cp.config.predump_threshold = predump_threshold;
+            cp.config.dedup = dedup;
+            cp.config.compression = Compression {
+                algorithm: compress,
+                level: compress_level,
+            };
+            cp.config.tree = tree;
+            cp.config.snapshot_files = snapshot_files.then_some(SnapshotConfig {
+                max_size: snapshot_max_size,
+                include: snapshot_include,
+                exclude: snapshot_exclude,
+            });
+            cp.config.dump_dontdump = dump_dontdump;
+            cp.config.dump_file_maps = dump_file_maps;
 
             if let Some(overhead) = overhead {
                 cp.run_adaptive(
@@ -116,20 +259,50 @@ fn main() -> Result<(), Box<dyn error::Error>> {
             }
 
             match period {
+                Some(s) if predump => {
+                    cp.run_predump(Duration::from_secs_f64(s), max as u64, stats)?
+                }
                 Some(s) => cp.run(Duration::from_secs_f64(s), max as u64, stats)?,
                 None => {
+                    let start = Instant::now();
+                    let predumped = match predump {
+                        true => Some(cp.predump()?),
+                        false => None,
+                    };
+
                     let vcp_time = cp.checkpoint()?;
                     cp.cull_checkpoints(max as u64)?;
 
                     if let Some(mut stats) = stats {
-                        write!(stats, "{}", vcp_time.as_nanos())?;
+                        match &predumped {
+                            Some(predumped) => cp.write_predump_stats(
+                                &mut stats,
+                                predumped,
+                                vcp_time,
+                                start.elapsed(),
+                            )?,
+                            None => write!(stats, "{}", vcp_time.as_nanos())?,
+                        }
+                        cp.write_ratio(stats)?;
                     }
                 }
             }
         }
 
-        Args::Restore { cpath, hang } => {
-            let mut restored = restore_checkpoint(&cpath.into(), hang)?;
+        Args::Restore {
+            cpath,
+            hang,
+            same_pid,
+            unsupported_fds,
+            changed_files,
+        } => {
+            let config = RestoreConfig {
+                hang,
+                same_pid,
+                unsupported_fds,
+                changed_files,
+            };
+            let mut restored = restore_checkpoint(&cpath.into(), &config)?;
             let res = restored.wait()?;
 
             // arguably this shouldn't be here because we want stderr to be
@@ -0,0 +1,356 @@
+use std::{
+    cell::Cell,
+    error::Error,
+    fs::File,
+    io::{self, Read, Seek, SeekFrom},
+    os::unix::fs::FileExt,
+};
+
+use libc::{c_void, iovec, pid_t, process_vm_readv, EACCES, EFAULT, EIO, ENOSYS, EPERM};
+use log::{debug, warn};
+use procfs::process::{MemoryMap, Process};
+
+use crate::pagemap::PAGE_SIZE;
+
+/// The most iovecs the kernel accepts in one `process_vm_readv` call
+const IOV_MAX: usize = 1024;
+
+/// Reads memory out of another process.
+///
+/// Reads go through `process_vm_readv`, batching many ranges into each syscall,
+/// unless the kernel refuses it, in which case we fall back to `/proc/<pid>/mem`.
+pub struct MemReader {
+    pub pid: pid_t,
+    pub mem_file: File,
+    /// Whether to try `process_vm_readv`, it's turned off the first time the kernel refuses it
+    pub vm_readv: Cell<bool>,
+}
+
+/// What `MemReader::read_batch_partial` read of one request
+pub struct BatchRead {
+    pub mem: Vec<u8>,
+    /// The `(address, length)` ranges that couldn't be read, which are zeros in `mem`
+    pub unreadable: Vec<(u64, usize)>,
+}
+
+/// A contiguous range of remote memory and where it goes in the output
+struct Segment {
+    addr: u64,
+    len: usize,
+    request: usize,
+    offset: usize,
+}
+
+impl MemReader {
+    pub fn new(procfs: &Process) -> Result<Self, Box<dyn Error>> {
+        Ok(Self {
+            pid: procfs.pid,
+            mem_file: procfs.mem()?,
+            vm_readv: Cell::new(true),
+        })
+    }
+
+    /// Reads the whole memory region `map`
+    pub fn read_region(&self, map: &MemoryMap) -> io::Result<Vec<u8>> {
+        let len = (map.address.1 - map.address.0) as usize;
+        self.read_batch(&[vec![(map.address.0, len)]])
+            .pop()
+            .unwrap()
+    }
+
+    /// Reads each request, a list of `(address, length)` ranges, into its own buffer.
+    /// A request fails with `EIO` if any page of it can't be read, see `read_batch_partial`.
+    pub fn read_batch(&self, requests: &[Vec<(u64, usize)>]) -> Vec<io::Result<Vec<u8>>> {
+        self.read_batch_partial(requests)
+            .into_iter()
+            .map(|read| {
+                let read = read?;
+                if !read.unreadable.is_empty() {
+                    debug!(
+                        "{:x?} of process {} can't be read",
+                        read.unreadable, self.pid
+                    );
+                    return Err(io::Error::from_raw_os_error(EIO));
+                }
+                Ok(read.mem)
+            })
+            .collect()
+    }
+
+    /// Like `read_batch`, but reads around the pages that can't be read.
+    ///
+    /// Whenever a bulk read stops short, the page it stopped at is retried by itself,
+    /// and the bulk read goes on after it. Those that still fail are left zeroed, and
+    /// the request only fails for errors other than the memory not being readable.
+    pub fn read_batch_partial(&self, requests: &[Vec<(u64, usize)>]) -> Vec<io::Result<BatchRead>> {
+        let mut segments = vec![];
+        let mut bufs = vec![];
+        for (request, ranges) in requests.iter().enumerate() {
+            let mut offset = 0;
+            for &(addr, len) in ranges.iter().filter(|(_, len)| *len > 0) {
+                segments.push(Segment {
+                    addr,
+                    len,
+                    request,
+                    offset,
+                });
+                offset += len;
+            }
+
+            bufs.push(vec![0u8; offset]);
+        }
+
+        let mut unreadable: Vec<Vec<(u64, usize)>> = requests.iter().map(|_| vec![]).collect();
+        let mut errors: Vec<Option<io::Error>> = requests.iter().map(|_| None).collect();
+
+        let mut seg = 0;
+        while seg < segments.len() {
+            let mut read = self.read_bulk(&segments[seg..], &mut bufs);
+
+            // Skip past everything that was read
+            while seg < segments.len() && read >= segments[seg].len {
+                read -= segments[seg].len;
+                seg += 1;
+            }
+            let Some(segment) = segments.get_mut(seg) else {
+                break;
+            };
+
+            // Something in the way, so try the page it stopped at by itself
+            let addr = segment.addr + read as u64;
+            let len = (PAGE_SIZE - addr % PAGE_SIZE).min((segment.len - read) as u64) as usize;
+            let start = segment.offset + read;
+            let buf = &mut bufs[segment.request][start..start + len];
+
+            match self.read_exact_at(buf, addr) {
+                Ok(()) => {}
+                Err(e) if matches!(e.raw_os_error(), Some(EIO) | Some(EFAULT)) => {
+                    unreadable[segment.request].push((addr, len));
+                }
+                Err(e) => {
+                    errors[segment.request].get_or_insert(e);
+                }
+            }
+
+            // and go on with the rest of the segment in bulk
+            let done = read + len;
+            segment.addr += done as u64;
+            segment.offset += done;
+            segment.len -= done;
+            if segment.len == 0 {
+                seg += 1;
+            }
+        }
+
+        bufs.into_iter()
+            .zip(unreadable)
+            .zip(errors)
+            .map(|((mem, unreadable), error)| match error {
+                Some(e) => Err(e),
+                None => Ok(BatchRead { mem, unreadable }),
+            })
+            .collect()
+    }
+
+    /// Reads as much of `segments` as it can in one go.
+    /// Returns how many bytes were read, stopping at the first that couldn't be.
+    fn read_bulk(&self, segments: &[Segment], bufs: &mut [Vec<u8>]) -> usize {
+        if self.vm_readv.get() {
+            match self.read_bulk_vm(segments, bufs) {
+                Ok(read) => return read,
+                Err(e) => {
+                    warn!("process_vm_readv failed ({e}), falling back to /proc/<pid>/mem");
+                    self.vm_readv.set(false);
+                }
+            }
+        }
+
+        let mut read = 0;
+        for segment in segments {
+            let buf = &mut bufs[segment.request][segment.offset..segment.offset + segment.len];
+
+            let mut n = 0;
+            while n < buf.len() {
+                match self
+                    .mem_file
+                    .read_at(&mut buf[n..], segment.addr + n as u64)
+                {
+                    Ok(0) | Err(_) => return read + n,
+                    Ok(m) => n += m,
+                }
+            }
+            read += n;
+        }
+
+        read
+    }
+
+    /// Reads `/proc/<pid>/mem` at `addr`.
+    ///
+    /// `pread` takes a signed offset, so kernel addresses like `[vsyscall]`
+    /// can only be reached by seeking there first.
+    fn read_at(&self, buf: &mut [u8], addr: u64) -> io::Result<usize> {
+        if addr > i64::MAX as u64 {
+            let mut mem_file = &self.mem_file;
+            mem_file.seek(SeekFrom::Start(addr))?;
+            return mem_file.read(buf);
+        }
+
+        self.mem_file.read_at(buf, addr)
+    }
+
+    fn read_exact_at(&self, mut buf: &mut [u8], mut addr: u64) -> io::Result<()> {
+        while !buf.is_empty() {
+            match self.read_at(buf, addr) {
+                Ok(0) => return Err(io::Error::from_raw_os_error(EIO)),
+                Ok(n) => {
+                    buf = &mut buf[n..];
+                    addr += n as u64;
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// `read_bulk` with `process_vm_readv`, only fails if the kernel won't let us use it
+    fn read_bulk_vm(&self, segments: &[Segment], bufs: &mut [Vec<u8>]) -> io::Result<usize> {
+        let mut read = 0;
+        for chunk in segments.chunks(IOV_MAX) {
+            let mut local = vec![];
+            let mut remote = vec![];
+            let mut expected = 0;
+            for segment in chunk {
+                let buf = &mut bufs[segment.request][segment.offset..];
+
+                local.push(iovec {
+                    iov_base: buf.as_mut_ptr() as *mut c_void,
+                    iov_len: segment.len,
+                });
+                remote.push(iovec {
+                    iov_base: segment.addr as *mut c_void,
+                    iov_len: segment.len,
+                });
+                expected += segment.len;
+            }
+
+            let res = unsafe {
+                process_vm_readv(
+                    self.pid,
+                    local.as_ptr(),
+                    local.len() as _,
+                    remote.as_ptr(),
+                    remote.len() as _,
+                    0,
+                )
+            };
+
+            if res < 0 {
+                let e = io::Error::last_os_error();
+                return match e.raw_os_error() {
+                    Some(ENOSYS) | Some(EPERM) | Some(EACCES) if read == 0 => Err(e),
+                    _ => Ok(read),
+                };
+            }
+
+            read += res as usize;
+            if (res as usize) < expected {
+                break;
+            }
+        }
+
+        Ok(read)
+    }
+}
+
+/// Turns page numbers relative to the start of `map` into
+/// `(address, length)` ranges, merging runs of consecutive pages
+pub fn page_ranges(map: &MemoryMap, pages: &[u64]) -> Vec<(u64, usize)> {
+    let mut ranges: Vec<(u64, usize)> = vec![];
+    for page in pages {
+        let addr = map.address.0 + page * PAGE_SIZE;
+        match ranges.last_mut() {
+            Some((start, len)) if *start + *len as u64 == addr => *len += PAGE_SIZE as usize,
+            _ => ranges.push((addr, PAGE_SIZE as usize)),
+        }
+    }
+
+    ranges
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{ptr, slice};
+
+    use libc::{
+        ftruncate, memfd_create, mmap, munmap, MAP_ANONYMOUS, MAP_PRIVATE, MAP_SHARED, PROT_READ,
+        PROT_WRITE,
+    };
+
+    use super::*;
+
+    /// Maps `pages` pages of `fd`, or of anonymous memory if it's -1
+    fn map(pages: usize, fd: i32) -> &'static mut [u8] {
+        let len = pages * PAGE_SIZE as usize;
+        let flags = match fd {
+            -1 => MAP_PRIVATE | MAP_ANONYMOUS,
+            _ => MAP_SHARED,
+        };
+        unsafe {
+            let addr = mmap(ptr::null_mut(), len, PROT_READ | PROT_WRITE, flags, fd, 0);
+            assert_ne!(addr, libc::MAP_FAILED);
+            slice::from_raw_parts_mut(addr as *mut u8, len)
+        }
+    }
+
+    #[test]
+    fn reads_go_around_what_cant_be_read() {
+        let page = PAGE_SIZE as usize;
+        let anon = map(2, -1);
+        anon.fill(7);
+
+        // Only the first page of the mapping is in the file, the rest can't be read at all
+        let fd = unsafe { memfd_create(c"memory-test".as_ptr(), 0) };
+        assert_eq!(unsafe { ftruncate(fd, page as i64) }, 0);
+        let shared = map(3, fd);
+        shared[..page].fill(3);
+        let shared_addr = shared.as_ptr() as u64;
+
+        let mut expected = vec![3; page];
+        expected.extend(vec![0; 2 * page]);
+        let holes = vec![
+            (shared_addr + page as u64, page),
+            (shared_addr + 2 * page as u64, page),
+        ];
+
+        let reader = MemReader::new(&Process::myself().unwrap()).unwrap();
+        for vm_readv in [true, false] {
+            reader.vm_readv.set(vm_readv);
+            let requests = [
+                vec![(anon.as_ptr() as u64, 2 * page)],
+                vec![(shared_addr, 3 * page)],
+            ];
+
+            let mut reads = reader.read_batch_partial(&requests);
+            let read = reads.pop().unwrap().unwrap();
+            assert_eq!(
+                (read.mem, read.unreadable),
+                (expected.clone(), holes.clone())
+            );
+            let read = reads.pop().unwrap().unwrap();
+            assert_eq!((read.mem.as_slice(), read.unreadable), (&anon[..], vec![]));
+
+            let mut reads = reader.read_batch(&requests);
+            assert_eq!(reads.pop().unwrap().unwrap_err().raw_os_error(), Some(EIO));
+            assert_eq!(reads.pop().unwrap().unwrap(), anon);
+        }
+
+        unsafe {
+            munmap(anon.as_mut_ptr() as _, anon.len());
+            munmap(shared.as_mut_ptr() as _, shared.len());
+            libc::close(fd);
+        }
+    }
+}
@@ -0,0 +1,206 @@
+use std::{error::Error, fs};
+
+use libc::SYS_brk;
+use procfs::process::{MMapPath, MemoryMap, Process};
+use serde::{Deserialize, Serialize};
+
+use crate::ptrace::PTrace;
+
+/// The size of the kernel's `struct prctl_mm_map`
+pub const PRCTL_MM_MAP_SIZE: usize = 104;
+
+/// The layout fields of a process' `mm_struct`, and its auxiliary vector.
+///
+/// The kernel uses these for `brk`, `/proc/<pid>/cmdline` and the like, and none
+/// of them follow the memory when we map it back in, so the restored process
+/// would be stuck with the bootstrapper's unless we set them with `PR_SET_MM_MAP`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MmFields {
+    pub start_code: u64,
+    pub end_code: u64,
+    pub start_data: u64,
+    pub end_data: u64,
+    pub start_brk: u64,
+    pub brk: u64,
+    pub start_stack: u64,
+    pub arg_start: u64,
+    pub arg_end: u64,
+    pub env_start: u64,
+    pub env_end: u64,
+    /// `(type, value)` pairs, without the terminating `AT_NULL`
+    pub auxv: Vec<(u64, u64)>,
+}
+
+impl MmFields {
+    /// Reads the fields of the stopped process `procfs`, which has the memory regions `maps`.
+    ///
+    /// The break isn't in `stat`, so with a syscall `gadget` we ask the process with `brk(0)`
+    pub fn capture(
+        procfs: &Process,
+        ptrace: &PTrace,
+        gadget: Option<u64>,
+        maps: &[MemoryMap],
+    ) -> Result<Self, Box<dyn Error>> {
+        let stat = procfs.stat()?;
+        let field = |f: Option<u64>| f.ok_or("kernel too old to report mm fields in stat");
+
+        let start_brk = field(stat.start_brk)?;
+
+        let brk = match gadget {
+            Some(gadget) => ptrace.syscall(gadget, SYS_brk, &[0])? as u64,
+            // Otherwise the heap runs from `start_brk` up to the page aligned break,
+            // which is close enough since the kernel only ever moves it in pages
+            None => maps
+                .iter()
+                .find(|m| m.pathname == MMapPath::Heap)
+                .map_or(start_brk, |heap| heap.address.1),
+        };
+
+        let raw_auxv = fs::read(format!("/proc/{}/auxv", procfs.pid))?;
+        let auxv = raw_auxv
+            .chunks_exact(16)
+            .map(|pair| {
+                let key = u64::from_ne_bytes(pair[..8].try_into().unwrap());
+                let value = u64::from_ne_bytes(pair[8..].try_into().unwrap());
+                (key, value)
+            })
+            .take_while(|(key, _)| *key != libc::AT_NULL)
+            .collect();
+
+        Ok(Self {
+            start_code: stat.startcode,
+            end_code: stat.endcode,
+            start_data: field(stat.start_data)?,
+            end_data: field(stat.end_data)?,
+            start_brk,
+            brk,
+            start_stack: stat.startstack,
+            arg_start: field(stat.arg_start)?,
+            arg_end: field(stat.arg_end)?,
+            env_start: field(stat.env_start)?,
+            env_end: field(stat.env_end)?,
+            auxv,
+        })
+    }
+
+    /// The auxiliary vector as the kernel lays it out, terminated by `AT_NULL`
+    pub fn auxv_bytes(&self) -> Vec<u8> {
+        self.auxv
+            .iter()
+            .chain([&(libc::AT_NULL, 0)])
+            .flat_map(|(key, value)| [key.to_ne_bytes(), value.to_ne_bytes()])
+            .flatten()
+            .collect()
+    }
+
+    /// Builds the `struct prctl_mm_map` for these fields, with the
+    /// auxiliary vector (see `auxv_bytes`) at `auxv_addr`
+    pub fn prctl_map(&self, auxv_addr: u64) -> Vec<u8> {
+        let mut map = vec![];
+        for field in [
+            self.start_code,
+            self.end_code,
+            self.start_data,
+            self.end_data,
+            self.start_brk,
+            self.brk,
+            self.start_stack,
+            self.arg_start,
+            self.arg_end,
+            self.env_start,
+            self.env_end,
+            auxv_addr,
+        ] {
+            map.extend(field.to_ne_bytes());
+        }
+
+        map.extend((self.auxv_bytes().len() as u32).to_ne_bytes());
+        // exe_fd, -1 leaves /proc/<pid>/exe alone
+        map.extend(u32::MAX.to_ne_bytes());
+
+        assert_eq!(map.len(), PRCTL_MM_MAP_SIZE);
+        map
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process::Command;
+
+    use libc::pid_t;
+
+    use super::*;
+    use crate::{
+        memory::MemReader,
+        pagemap::PAGE_SIZE,
+        thread::{attach_all, detach_all},
+        vdso,
+    };
+
+    #[test]
+    fn capture_reads_our_own_layout() {
+        let procfs = Process::myself().unwrap();
+        let ptrace = PTrace::new(procfs.pid);
+        let mm = MmFields::capture(&procfs, &ptrace, None, &procfs.maps().unwrap().0).unwrap();
+
+        assert!(mm.start_code < mm.end_code && mm.start_brk <= mm.brk);
+        assert!(mm.arg_start < mm.arg_end);
+        let page_size = unsafe { libc::getauxval(libc::AT_PAGESZ) };
+        assert!(mm.auxv.contains(&(libc::AT_PAGESZ, page_size)));
+
+        // The whole auxv round trips, terminator included
+        let bytes = mm.auxv_bytes();
+        assert_eq!(
+            bytes,
+            fs::read(format!("/proc/{}/auxv", procfs.pid)).unwrap()[..bytes.len()]
+        );
+        assert!(bytes.ends_with(&[0; 16]));
+    }
+
+    #[test]
+    fn injected_brk_agrees_with_the_heap() {
+        let mut tracee = Command::new("sleep").arg("30").spawn().unwrap();
+        let procfs = Process::new(tracee.id() as pid_t).unwrap();
+        let tasks = attach_all(&procfs).unwrap();
+        let maps = procfs.maps().unwrap().0;
+        let gadget = vdso::syscall_gadget(&MemReader::new(&procfs).unwrap(), &maps).unwrap();
+
+        // The heap only ever ends on the page after the real break
+        let exact = MmFields::capture(&procfs, &tasks[0], Some(gadget), &maps).unwrap();
+        let paged = MmFields::capture(&procfs, &tasks[0], None, &maps).unwrap();
+        assert!(exact.brk >= exact.start_brk);
+        assert_eq!(paged.brk, exact.brk.next_multiple_of(PAGE_SIZE));
+
+        detach_all(tasks).unwrap();
+        tracee.kill().unwrap();
+        tracee.wait().unwrap();
+    }
+
+    #[test]
+    fn prctl_map_layout() {
+        let mm = MmFields {
+            start_code: 1,
+            end_code: 2,
+            start_data: 3,
+            end_data: 4,
+            start_brk: 5,
+            brk: 6,
+            start_stack: 7,
+            arg_start: 8,
+            arg_end: 9,
+            env_start: 10,
+            env_end: 11,
+            auxv: vec![(libc::AT_PAGESZ, 4096)],
+        };
+
+        let map = mm.prctl_map(0x1234);
+        let field = |i: usize| u64::from_ne_bytes(map[i * 8..i * 8 + 8].try_into().unwrap());
+        assert_eq!(
+            (0..11).map(field).collect::<Vec<_>>(),
+            (1..=11).collect::<Vec<_>>()
+        );
+        assert_eq!(field(11), 0x1234);
+        assert_eq!(map[96..100], 32u32.to_ne_bytes());
+        assert_eq!(map[100..], u32::MAX.to_ne_bytes());
+    }
+}
@@ -0,0 +1,231 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    fs::{self, File},
+    io::ErrorKind,
+    os::unix::fs::FileExt,
+    path::{Path, PathBuf},
+};
+
+use log::debug;
+use procfs::process::MemoryMap;
+use serde::{Deserialize, Serialize};
+
+use crate::{checkpoint::FileIdentity, pagemap::PAGE_SIZE};
+
+/// A private mapping of a file that's saved as just the pages that differ from the
+/// file, which the restore maps from the file again and patches those into
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileOverlay {
+    /// The index of the memory region in the checkpoint's maps
+    pub index: usize,
+    pub path: PathBuf,
+    pub identity: FileIdentity,
+    /// The blake3 hash of the part of the file that's mapped, so that a copy of it
+    /// somewhere else (like on another machine) can stand in for it
+    pub hash: Option<String>,
+    /// The pages that differ from the file, which are all the image has
+    pub pages: Vec<u64>,
+}
+
+impl FileOverlay {
+    /// Keeps the pages of `map` in `mem`, the concatenation of `pages`,
+    /// that differ from the file at `source`, and returns what's in those
+    pub fn diff(
+        &mut self,
+        map: &MemoryMap,
+        source: &Path,
+        pages: &[u64],
+        mem: &[u8],
+    ) -> Result<Vec<u8>, Box<dyn Error>> {
+        let file = File::open(source)?;
+        let page_size = PAGE_SIZE as usize;
+
+        let mut differ = vec![];
+        let mut file_page = vec![0; page_size];
+        for (page, data) in pages.iter().zip(mem.chunks(page_size)) {
+            // Past the end of the file it reads as zeros
+            file_page.fill(0);
+            let mut pos = 0;
+            while pos < page_size {
+                let offset = map.offset + page * PAGE_SIZE + pos as u64;
+                match file.read_at(&mut file_page[pos..], offset)? {
+                    0 => break,
+                    n => pos += n,
+                }
+            }
+
+            if data != file_page {
+                self.pages.push(*page);
+                differ.extend_from_slice(data);
+            }
+        }
+
+        Ok(differ)
+    }
+
+    /// Hashes the `len` bytes of the file that are mapped from `offset`, if the file at
+    /// `path` is still `identity`. `hashes` has the ones we already did.
+    pub fn hash(
+        path: &Path,
+        identity: &FileIdentity,
+        (offset, len): (u64, u64),
+        hashes: &mut HashMap<(FileIdentity, u64, u64), String>,
+    ) -> Result<Option<String>, Box<dyn Error>> {
+        let key = (identity.clone(), offset, len);
+        if let Some(hash) = hashes.get(&key) {
+            return Ok(Some(hash.clone()));
+        }
+
+        let file = File::open(path)?;
+        if FileIdentity::from(&file.metadata()?) != *identity {
+            debug!("{path:?} changed since the checkpoint, not hashing it");
+            return Ok(None);
+        }
+
+        let hash = hash_range(&file, offset, len)?;
+        hashes.insert(key, hash.clone());
+        Ok(Some(hash))
+    }
+
+    /// Writes `overlays` to the checkpoint in `cp_dir`
+    pub fn persist_all(overlays: &[Self], cp_dir: &Path) -> Result<(), Box<dyn Error>> {
+        serde_json::to_writer(File::create(cp_dir.join("overlays"))?, overlays)?;
+        Ok(())
+    }
+
+    /// Reads the overlays of the checkpoint in `cp_dir`,
+    /// which older checkpoints and ones that didn't have any don't have
+    pub fn open_all(cp_dir: &Path) -> Result<Vec<Self>, Box<dyn Error>> {
+        match File::open(cp_dir.join("overlays")) {
+            Ok(file) => Ok(serde_json::from_reader(file)?),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(vec![]),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Checks that the file is still what `map` was mapped from, or at least that
+    /// the part of it that's mapped still has the same contents
+    pub fn verify(&self, map: &MemoryMap) -> Result<(), Box<dyn Error>> {
+        let path = &self.path;
+        let fail = |what: &str| {
+            format!(
+                "{path:?} {what}, so memory at {:#x} can't be restored from it (--dump-file-maps saves it in full)",
+                map.address.0
+            )
+        };
+
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Err(fail("is gone").into()),
+            Err(e) => return Err(e.into()),
+        };
+        if FileIdentity::from(&file.metadata()?) == self.identity {
+            return Ok(());
+        }
+
+        let len = map.address.1 - map.address.0;
+        let same = match &self.hash {
+            Some(hash) => hash_range(&file, map.offset, len)? == *hash,
+            None => false,
+        };
+        if !same {
+            return Err(fail("changed since the checkpoint").into());
+        }
+
+        debug!("{path:?} isn't the same file anymore, but what's mapped of it is the same");
+        Ok(())
+    }
+}
+
+/// The blake3 hash of the `len` bytes of `file` from `offset`, or up to its end
+fn hash_range(file: &File, offset: u64, len: u64) -> Result<String, Box<dyn Error>> {
+    let mut hasher = blake3::Hasher::new();
+    let mut buf = vec![0; 1 << 20];
+    let mut pos = 0;
+    while pos < len {
+        let want = buf.len().min((len - pos) as usize);
+        match file.read_at(&mut buf[..want], offset + pos)? {
+            0 => break,
+            n => {
+                hasher.update(&buf[..n]);
+                pos += n as u64;
+            }
+        }
+    }
+
+    Ok(hasher.finalize().to_hex().to_string())
+}
+
+/// Whether `path` is an image in the checkpoint directory `cp_path`, which goes away
+/// along with its checkpoint, so the restore can't count on mapping it again
+pub fn is_image(path: &Path, cp_path: &Path) -> bool {
+    fs::canonicalize(cp_path).is_ok_and(|cp_path| path.starts_with(cp_path))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, os::fd::AsRawFd, process, ptr};
+
+    use libc::{MAP_FAILED, MAP_PRIVATE, PROT_READ, PROT_WRITE};
+    use procfs::process::Process;
+
+    use super::*;
+
+    #[test]
+    fn overlays_keep_what_differs_from_the_file() {
+        let dir = env::temp_dir().join(format!("overlay-test-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("file");
+        let page = PAGE_SIZE as usize;
+        let contents: Vec<u8> = (0..3 * page).map(|i| (i / page) as u8 + 1).collect();
+        fs::write(&path, &contents).unwrap();
+
+        let file = File::open(&path).unwrap();
+        let prot = PROT_READ | PROT_WRITE;
+        let len = contents.len();
+        let addr =
+            unsafe { libc::mmap(ptr::null_mut(), len, prot, MAP_PRIVATE, file.as_raw_fd(), 0) };
+        assert_ne!(addr, MAP_FAILED);
+        let mem = unsafe { std::slice::from_raw_parts_mut(addr as *mut u8, len) };
+        mem[page + 10] = 0xff;
+
+        let maps = Process::myself().unwrap().maps().unwrap().0;
+        let map = maps.iter().find(|m| m.address.0 == addr as u64).unwrap();
+        let identity = FileIdentity::from(&file.metadata().unwrap());
+        let mut hashes = HashMap::new();
+        let range = (map.offset, map.address.1 - map.address.0);
+        let hash = FileOverlay::hash(&path, &identity, range, &mut hashes).unwrap();
+        assert!(hash.is_some() && hashes.len() == 1);
+
+        let mut overlay = FileOverlay {
+            index: 0,
+            path: path.clone(),
+            identity,
+            hash,
+            pages: vec![],
+        };
+        let differ = overlay.diff(map, &path, &[0, 1, 2], mem).unwrap();
+        assert_eq!(overlay.pages, [1]);
+        assert_eq!(differ, mem[page..2 * page]);
+        overlay.verify(map).unwrap();
+
+        // A copy with the same contents stands in for it, if it was hashed
+        fs::remove_file(&path).unwrap();
+        fs::write(&path, &contents).unwrap();
+        overlay.verify(map).unwrap();
+        let unhashed = FileOverlay {
+            hash: None,
+            ..overlay.clone()
+        };
+        assert!(unhashed.verify(map).is_err());
+
+        fs::write(&path, b"something else").unwrap();
+        assert!(overlay.verify(map).is_err());
+        fs::remove_file(&path).unwrap();
+        assert!(overlay.verify(map).is_err());
+
+        unsafe { libc::munmap(addr, len) };
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
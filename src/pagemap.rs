@@ -0,0 +1,146 @@
+use std::{error::Error, io, ptr};
+
+use libc::{mmap, munmap, MAP_ANONYMOUS, MAP_FAILED, MAP_PRIVATE, PROT_READ, PROT_WRITE};
+use procfs::process::{ClearRefs, MemoryPageFlags, PageInfo, Process, SwapPageFlags};
+
+/// We only support amd64, so we don't bother asking the kernel for this
+pub const PAGE_SIZE: u64 = 0x1000;
+
+/// Returns whether or not the page was written to since the
+/// last time the soft-dirty bits of its process were cleared
+pub fn is_soft_dirty(page: &PageInfo) -> bool {
+    match page {
+        PageInfo::MemoryPage(flags) => flags.contains(MemoryPageFlags::SOFT_DIRTY),
+        PageInfo::SwapPage(flags) => flags.contains(SwapPageFlags::SOFT_DIRTY),
+    }
+}
+
+/// Returns whether or not the page is in memory or swapped out,
+/// i.e. whether it was ever touched (pages of anonymous memory
+/// that never were aren't there and just read as zeros)
+pub fn is_resident(page: &PageInfo) -> bool {
+    match page {
+        PageInfo::MemoryPage(flags) => flags.contains(MemoryPageFlags::PRESENT),
+        PageInfo::SwapPage(_) => true,
+    }
+}
+
+/// Returns whether the page of a private file mapping is a copy of its own,
+/// i.e. whether it was written to and might not be what's in the file anymore
+pub fn is_copied(page: &PageInfo) -> bool {
+    match page {
+        PageInfo::MemoryPage(flags) => {
+            flags.contains(MemoryPageFlags::PRESENT) && !flags.contains(MemoryPageFlags::FILE)
+        }
+        // Only copies get swapped out, the rest is just dropped and read from the file again
+        PageInfo::SwapPage(_) => true,
+    }
+}
+
+/// Copies `mem`, the concatenation of `pages`, into their places in `image`
+pub fn patch_pages(image: &mut [u8], pages: &[u64], mem: &[u8]) {
+    for (page, data) in pages.iter().zip(mem.chunks(PAGE_SIZE as usize)) {
+        let start = (page * PAGE_SIZE) as usize;
+        image[start..start + data.len()].copy_from_slice(data);
+    }
+}
+
+/// Reads the pagemap entries for every page in `[start, end)`
+pub fn page_infos(
+    procfs: &Process,
+    (start, end): (u64, u64),
+) -> Result<Vec<PageInfo>, Box<dyn Error>> {
+    let mut pagemap = procfs.pagemap()?;
+    let first = (start / PAGE_SIZE) as usize;
+    let last = end.div_ceil(PAGE_SIZE) as usize;
+
+    Ok(pagemap.get_range_info(first..last)?)
+}
+
+/// Clears the soft-dirty bit of every page in the process,
+/// so that the next pagemap read only reports pages written after this call.
+pub fn clear_soft_dirty(procfs: &Process) -> Result<(), Box<dyn Error>> {
+    Ok(procfs.clear_refs(ClearRefs::SoftDirty)?)
+}
+
+/// Checks whether the kernel actually tracks soft-dirty bits.
+///
+/// Kernels built without `CONFIG_MEM_SOFT_DIRTY` happily accept writes to `clear_refs`
+/// but never set the bit, which would make every incremental checkpoint empty.
+/// We test this on a scratch page of our own.
+pub fn soft_dirty_supported() -> Result<bool, Box<dyn Error>> {
+    let this = Process::myself()?;
+
+    let page = unsafe {
+        mmap(
+            ptr::null_mut(),
+            PAGE_SIZE as usize,
+            PROT_READ | PROT_WRITE,
+            MAP_PRIVATE | MAP_ANONYMOUS,
+            -1,
+            0,
+        )
+    };
+    if page == MAP_FAILED {
+        return Err(io::Error::last_os_error().into());
+    }
+
+    let res = (|| {
+        unsafe { page.cast::<u8>().write_volatile(1) };
+        clear_soft_dirty(&this)?;
+        unsafe { page.cast::<u8>().write_volatile(2) };
+
+        let addr = page as u64;
+        let infos = page_infos(&this, (addr, addr + PAGE_SIZE))?;
+        Ok(infos.first().is_some_and(is_soft_dirty))
+    })();
+
+    unsafe { munmap(page, PAGE_SIZE as usize) };
+
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn patch_pages_puts_each_page_in_place() {
+        let page = PAGE_SIZE as usize;
+        let mut image = vec![0u8; 4 * page];
+        let mut mem = vec![1u8; page];
+        // The last page can be cut short, at the end of the region
+        mem.extend(vec![2u8; page / 2]);
+
+        patch_pages(&mut image, &[1, 3], &mem);
+        assert!(image[..page].iter().all(|b| *b == 0));
+        assert!(image[page..2 * page].iter().all(|b| *b == 1));
+        assert!(image[2 * page..3 * page].iter().all(|b| *b == 0));
+        assert!(image[3 * page..3 * page + page / 2].iter().all(|b| *b == 2));
+        assert!(image[3 * page + page / 2..].iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn untouched_pages_are_not_resident() {
+        let len = 3 * PAGE_SIZE as usize;
+        let page = unsafe {
+            mmap(
+                ptr::null_mut(),
+                len,
+                PROT_READ | PROT_WRITE,
+                MAP_PRIVATE | MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        assert_ne!(page, MAP_FAILED);
+        unsafe { *(page as *mut u8).add(PAGE_SIZE as usize) = 1 };
+
+        let start = page as u64;
+        let infos = page_infos(&Process::myself().unwrap(), (start, start + len as u64)).unwrap();
+        let resident: Vec<_> = infos.iter().map(is_resident).collect();
+        assert_eq!(resident, [false, true, false]);
+
+        unsafe { munmap(page, len) };
+    }
+}
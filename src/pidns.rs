@@ -0,0 +1,185 @@
+use std::{
+    ffi::CStr,
+    io, mem,
+    os::unix::process::CommandExt,
+    process::{Child, Command},
+    ptr,
+};
+
+use crate::compat::{
+    CapData, CapHeader, CloneArgs, CAP_SYS_ADMIN, LINUX_CAPABILITY_VERSION_3, PR_CAP_AMBIENT,
+    PR_CAP_AMBIENT_RAISE,
+};
+use libc::{
+    c_char, c_ulong, pid_t, SYS_capget, SYS_capset, SYS_clone3, SYS_close_range, CLONE_NEWNS,
+    CLONE_NEWPID, CLONE_NEWUSER, EINTR, MS_NODEV, MS_NOEXEC, MS_NOSUID, MS_PRIVATE, MS_REC,
+    O_WRONLY, PR_SET_PDEATHSIG, SIGCHLD, SIGKILL,
+};
+
+/// Starts the bootstrapper that `command` runs with the pid `pid`, in a new pid namespace.
+///
+/// The pid namespace belongs to a new user namespace, which is what lets us do this without
+/// privileges: we get every capability in there, including the one `clone3` needs to pick the
+/// pid. Our uid and gid are mapped to themselves, so that files look the same from inside.
+/// It also gets a mount namespace with a /proc of its own, so that `/proc/self` and the
+/// like go by the pids in there. Where a new /proc can't be mounted (e.g. in a container
+/// that hides parts of its own) the process sees the outer one, with its new pid.
+///
+/// The first process of a pid namespace is its init, and everything in it dies with that,
+/// so the returned process forks off an init for it, which then starts the bootstrapper. Both
+/// just wait for their child and exit like it did after that, so the returned `Child` exits
+/// like the restored process does.
+///
+/// The bootstrapper keeps `CAP_SYS_ADMIN` in the namespace through its exec (as an ambient
+/// capability, unless we're root), so that it can give the children and threads it starts
+/// their pids too.
+pub fn spawn_with_pid(mut command: Command, pid: pid_t) -> io::Result<Child> {
+    // Nothing can be allocated after forking, so everything is ready beforehand
+    let uid_map = format!("{0} {0} 1", unsafe { libc::getuid() });
+    let gid_map = format!("{0} {0} 1", unsafe { libc::getgid() });
+
+    unsafe {
+        command.pre_exec(move || {
+            if libc::unshare(CLONE_NEWUSER | CLONE_NEWPID | CLONE_NEWNS) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            write_proc(c"/proc/self/uid_map", uid_map.as_bytes())?;
+            write_proc(c"/proc/self/setgroups", b"deny")?;
+            write_proc(c"/proc/self/gid_map", gid_map.as_bytes())?;
+
+            match libc::fork() {
+                -1 => return Err(io::Error::last_os_error()),
+                0 => {}
+                init => reap(init),
+            }
+
+            // We're the init now, which takes the namespace down with it if the restore
+            // is killed. The capabilities we have in there don't survive an exec, so
+            // the bootstrapper has to get its pid straight from here.
+            libc::prctl(PR_SET_PDEATHSIG, SIGKILL);
+
+            // Only the init of the pid namespace can mount a /proc for it
+            let none = ptr::null::<c_char>();
+            if libc::mount(none, c"/".as_ptr(), none, MS_REC | MS_PRIVATE, ptr::null()) == 0 {
+                libc::mount(
+                    c"proc".as_ptr(),
+                    c"/proc".as_ptr(),
+                    c"proc".as_ptr(),
+                    MS_NOSUID | MS_NODEV | MS_NOEXEC,
+                    ptr::null(),
+                );
+            }
+
+            // Root keeps its capabilities through an exec anyways. Otherwise only what's
+            // in both the permitted and inheritable sets can be made ambient.
+            if libc::getuid() != 0 {
+                let mut header = CapHeader {
+                    version: LINUX_CAPABILITY_VERSION_3,
+                    pid: 0,
+                };
+                let mut caps = [CapData::default(); 2];
+                if libc::syscall(SYS_capget, &mut header, caps.as_mut_ptr()) < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                caps[0].inheritable |= 1 << CAP_SYS_ADMIN;
+                let raise = (PR_CAP_AMBIENT_RAISE, CAP_SYS_ADMIN as c_ulong);
+                if libc::syscall(SYS_capset, &header, caps.as_ptr()) < 0
+                    || libc::prctl(PR_CAP_AMBIENT, raise.0, raise.1, 0, 0) < 0
+                {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+
+            let set_tid = [pid];
+            let args = CloneArgs {
+                exit_signal: SIGCHLD as u64,
+                set_tid: set_tid.as_ptr() as u64,
+                set_tid_size: 1,
+                ..Default::default()
+            };
+
+            match libc::syscall(SYS_clone3, &args, mem::size_of::<CloneArgs>()) {
+                -1 => Err(io::Error::last_os_error()),
+                0 => Ok(()),
+                child => reap(child as pid_t),
+            }
+        });
+    }
+
+    command.spawn()
+}
+
+/// Writes `data` to the file at `path`, without allocating
+unsafe fn write_proc(path: &CStr, data: &[u8]) -> io::Result<()> {
+    let fd = libc::open(path.as_ptr(), O_WRONLY);
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let res = libc::write(fd, data.as_ptr().cast(), data.len());
+    libc::close(fd);
+    match res {
+        0.. => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/// Waits for `child` to exit, reaping anything else along the way, and exits with its status
+unsafe fn reap(child: pid_t) -> ! {
+    // `Command::spawn` finds out that the exec happened when the pipe it has for that
+    // is closed everywhere, and we're never going to exec
+    libc::syscall(SYS_close_range, 3, u32::MAX, 0);
+
+    loop {
+        let mut status = 0;
+        let res = libc::waitpid(-1, &mut status, 0);
+
+        if res == child {
+            let code = match libc::WIFEXITED(status) {
+                true => libc::WEXITSTATUS(status),
+                false => 128 + libc::WTERMSIG(status),
+            };
+            libc::_exit(code);
+        }
+
+        if res < 0 && *libc::__errno_location() != EINTR {
+            libc::_exit(127);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, process};
+
+    use super::*;
+
+    #[test]
+    fn spawns_with_the_pid() {
+        let dir = env::temp_dir().join(format!("pidns-test-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let out = dir.join("pid");
+
+        // With what it needs to give its own children their pids, which root has anyways
+        let mut command = Command::new("sh");
+        command.args([
+            "-c",
+            &format!("echo $$ > {out:?}; grep CapAmb /proc/self/status >> {out:?}; exit 3"),
+        ]);
+        let mut outer = spawn_with_pid(command, 4242).unwrap();
+
+        assert_eq!(outer.wait().unwrap().code(), Some(3));
+        let out = fs::read_to_string(&out).unwrap();
+        let (pid, amb) = out.split_once('\n').unwrap();
+        assert_eq!(pid, "4242");
+        let amb = u64::from_str_radix(amb.trim().trim_start_matches("CapAmb:").trim(), 16);
+        let expected = match unsafe { libc::getuid() } {
+            0 => 0,
+            _ => 1 << CAP_SYS_ADMIN,
+        };
+        assert_eq!(amb.unwrap(), expected);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
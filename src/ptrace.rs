@@ -1,13 +1,19 @@
 use std::{error, io, mem::MaybeUninit, ptr};
 
 use libc::{
-    c_int, kill, pid_t, ptrace, user_fpregs_struct, user_regs_struct, waitpid, PTRACE_ATTACH,
-    PTRACE_DETACH, PTRACE_GETFPREGS, PTRACE_GETREGS, PTRACE_SETFPREGS, PTRACE_SETREGS, SIGCONT,
-    SIGSTOP, WUNTRACED,
+    c_int, c_long, c_void, iovec, kill, pid_t, ptrace, user_fpregs_struct, user_regs_struct,
+    waitpid, SYS_tgkill, __WALL, ENODEV, PTRACE_ATTACH, PTRACE_CONT, PTRACE_DETACH,
+    PTRACE_EVENT_STOP, PTRACE_GETFPREGS, PTRACE_GETREGS, PTRACE_GETREGSET, PTRACE_INTERRUPT,
+    PTRACE_SEIZE, PTRACE_SETFPREGS, PTRACE_SETOPTIONS, PTRACE_SETREGS, PTRACE_SETREGSET,
+    PTRACE_SETSIGMASK, PTRACE_SINGLESTEP, SIGCONT, SIGSTOP, SIGTRAP, WUNTRACED,
 };
+use procfs::process::Process;
 use serde::{Deserialize, Serialize};
 
-use crate::compat::{UserFpregs, UserRegs};
+use crate::compat::{UserFpregs, UserRegs, XState, NT_X86_XSTATE};
+
+/// Big enough for the XSAVE area of any CPU we know of (AMX makes it about 11KiB)
+const XSTATE_MAX_SIZE: usize = 1 << 14;
 
 #[derive(Debug)]
 pub struct PTrace {
@@ -22,6 +28,9 @@ pub struct PTrace {
 pub struct Registers {
     pub regs: UserRegs,
     pub fregs: UserFpregs,
+    /// Everything past x87 and SSE, if the CPU has XSAVE (and the checkpoint is new enough)
+    #[serde(default)]
+    pub xstate: Option<XState>,
 }
 
 impl PTrace {
@@ -42,8 +51,8 @@ impl PTrace {
             ptrace(
                 PTRACE_ATTACH,
                 self.pid,
-                ptr::null() as *const (),
-                ptr::null() as *const (),
+                ptr::null::<()>(),
+                ptr::null::<()>(),
             )
         };
 
@@ -56,25 +65,59 @@ impl PTrace {
         }
     }
 
+    /// Attaches ptrace to the given `pid` without stopping it, see `Self::interrupt`.
+    ///
+    /// Unlike `attach` this doesn't send a `SIGSTOP`, which is process wide,
+    /// so it's how we attach to the other threads of a process.
+    pub fn seize(&mut self) -> io::Result<()> {
+        let res = unsafe { ptrace(PTRACE_SEIZE, self.pid, ptr::null::<()>(), 0) };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        self.attached = true;
+        Ok(())
+    }
+
+    /// Stops the seized thread, which then has to be waited on with `Self::wait_pause`
+    pub fn interrupt(&self) -> io::Result<()> {
+        let res = unsafe { ptrace(PTRACE_INTERRUPT, self.pid, ptr::null::<()>(), 0) };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+
     pub fn detach(&mut self) -> Result<(), Box<dyn error::Error>> {
         let res = unsafe {
             ptrace(
                 PTRACE_DETACH,
                 self.pid,
-                ptr::null() as *const (),
-                ptr::null() as *const (),
+                ptr::null::<()>(),
+                ptr::null::<()>(),
             )
         };
 
         match res {
             0.. => {
-                self.attached = true;
+                self.attached = false;
                 Ok(())
             }
             _ => Err(io::Error::last_os_error().into()),
         }
     }
 
+    /// Sets the `PTRACE_O_*` options of the attached process
+    pub fn set_options(&self, options: c_int) -> io::Result<()> {
+        let res = unsafe { ptrace(PTRACE_SETOPTIONS, self.pid, ptr::null::<()>(), options) };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+
     /// Raises `signal` in the attached process
     pub fn signal(&self, signal: c_int) -> io::Result<()> {
         let res = unsafe { kill(self.pid, signal) };
@@ -87,10 +130,10 @@ impl PTrace {
 
     /// Pauses the attached process until the return guard is dropped.
     /// This function also waits for the process to be paused before returning.
-    pub fn pause_guard(&self) -> io::Result<PauseGuard> {
+    pub fn pause_guard(&self) -> io::Result<PauseGuard<'_>> {
         self.stop()?;
         self.wait_pause()?;
-        Ok(PauseGuard { ptrace: &self })
+        Ok(PauseGuard { ptrace: self })
     }
 
     /// Pauses the attached process
@@ -117,7 +160,8 @@ impl PTrace {
 
     /// Blocks until the attached process is paused while being traced by ptrace
     pub fn wait_pause(&self) -> io::Result<()> {
-        self.wait_pause_inner(0)
+        // __WALL so that this also works for clone children that don't report with SIGCHLD
+        self.wait_pause_inner(__WALL)
     }
 
     /// Blocks until the process is paused in general
@@ -135,18 +179,110 @@ impl PTrace {
         Ok(())
     }
 
-    /// Reads the register files of the attached process
+    /// Blocks until the seized process is in a group stop, like the one it puts itself in with
+    /// a `SIGSTOP` (or was in already). Signals that come in first are passed on.
+    pub fn wait_group_stop(&self) -> io::Result<()> {
+        loop {
+            let status = self.wait(__WALL)?;
+            if !libc::WIFSTOPPED(status) {
+                return Err(io::Error::other(format!(
+                    "process {} exited before it stopped",
+                    self.pid
+                )));
+            }
+            if status >> 16 == PTRACE_EVENT_STOP {
+                return Ok(());
+            }
+
+            // Otherwise it's about to get a signal, which only stops it once it does
+            let sig = libc::WSTOPSIG(status);
+            if unsafe { ptrace(PTRACE_CONT, self.pid, ptr::null::<()>(), sig) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+    }
+
+    /// Makes the attached process execute the syscall `nr` with `args`, and returns its result.
     ///
-    /// The process should be paused (e.g. with `Self::Stop`) when calling this
-    pub fn get_regs(&self) -> io::Result<Registers> {
+    /// `gadget` must be the address of a `syscall` instruction in the process' memory.
+    /// The process should be paused when calling this, and its registers are left as they were.
+    pub fn syscall(&self, gadget: u64, nr: c_long, args: &[u64]) -> io::Result<i64> {
+        let saved = self.get_raw_regs()?;
+
+        let mut regs = saved;
+        regs.rip = gadget;
+        regs.rax = nr as u64;
+        // Otherwise the kernel may think we're returning from an interrupted
+        // syscall and "restart" it by rewinding rip before we even get to the gadget
+        regs.orig_rax = u64::MAX;
+        for (reg, arg) in [
+            &mut regs.rdi,
+            &mut regs.rsi,
+            &mut regs.rdx,
+            &mut regs.r10,
+            &mut regs.r8,
+            &mut regs.r9,
+        ]
+        .into_iter()
+        .zip(args)
+        {
+            *reg = *arg;
+        }
+        self.set_raw_regs(&regs)?;
+
+        // Step over the syscall instruction. Anything other than the single step
+        // trap is either a ptrace event (e.g. PTRACE_EVENT_CLONE) or a signal that
+        // came in meanwhile, which we hold onto and raise again once we're done.
+        let mut pending = vec![];
+        loop {
+            let res = unsafe { ptrace(PTRACE_SINGLESTEP, self.pid, ptr::null::<()>(), 0) };
+            if res < 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            let status = self.wait(__WALL)?;
+            if !libc::WIFSTOPPED(status) {
+                return Err(io::Error::other(format!(
+                    "process {} exited while running syscall {nr}",
+                    self.pid
+                )));
+            }
+
+            match libc::WSTOPSIG(status) {
+                _ if status >> 16 != 0 => continue,
+                SIGTRAP => break,
+                sig => pending.push(sig),
+            }
+        }
+
+        let ret = self.get_raw_regs()?.rax as i64;
+        self.set_raw_regs(&saved)?;
+
+        // Back to this thread, which they were being delivered to, rather than
+        // the whole process, which might hand them to any of its threads
+        if !pending.is_empty() {
+            let tgid = Process::new(self.pid)
+                .and_then(|p| p.status())
+                .map_err(io::Error::other)?
+                .tgid;
+            for sig in pending {
+                if unsafe { libc::syscall(SYS_tgkill, tgid, self.pid, sig) } < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+        }
+
+        Ok(ret)
+    }
+
+    fn get_raw_regs(&self) -> io::Result<user_regs_struct> {
         let mut regs: MaybeUninit<user_regs_struct> = MaybeUninit::uninit();
-        let mut fregs: MaybeUninit<user_fpregs_struct> = MaybeUninit::uninit();
 
         let res = unsafe {
             ptrace(
                 PTRACE_GETREGS,
                 self.pid,
-                ptr::null() as *const (),
+                ptr::null::<()>(),
                 regs.as_mut_ptr(),
             )
         };
@@ -154,11 +290,71 @@ impl PTrace {
             return Err(io::Error::last_os_error());
         }
 
+        Ok(unsafe { regs.assume_init() })
+    }
+
+    fn set_raw_regs(&self, regs: &user_regs_struct) -> io::Result<()> {
+        let res = unsafe { ptrace(PTRACE_SETREGS, self.pid, ptr::null::<()>(), regs) };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+
+    /// Reads the XSAVE area of the attached process, or `None` if the CPU doesn't have one
+    fn get_xstate(&self) -> io::Result<Option<XState>> {
+        let mut area = vec![0u8; XSTATE_MAX_SIZE];
+        let mut iov = iovec {
+            iov_base: area.as_mut_ptr() as *mut c_void,
+            iov_len: area.len(),
+        };
+
+        let res = unsafe { ptrace(PTRACE_GETREGSET, self.pid, NT_X86_XSTATE, &mut iov) };
+        if res < 0 {
+            let err = io::Error::last_os_error();
+            return match err.raw_os_error() {
+                Some(ENODEV) => Ok(None),
+                _ => Err(err),
+            };
+        }
+
+        area.truncate(iov.iov_len);
+        Ok(Some(XState::from_area(area)))
+    }
+
+    /// Sets the XSAVE area of the attached process to `xstate`, checking
+    /// that the CPU supports every component that it uses
+    fn set_xstate(&self, xstate: &XState) -> io::Result<()> {
+        let target = self.get_xstate()?.ok_or_else(|| {
+            io::Error::other("the registers have XSAVE state but this CPU doesn't support XSAVE")
+        })?;
+        let mut area = xstate.for_target(&target).map_err(io::Error::other)?;
+
+        let iov = iovec {
+            iov_base: area.as_mut_ptr() as *mut c_void,
+            iov_len: area.len(),
+        };
+        let res = unsafe { ptrace(PTRACE_SETREGSET, self.pid, NT_X86_XSTATE, &iov) };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+
+    /// Reads the register files of the attached process
+    ///
+    /// The process should be paused (e.g. with `Self::Stop`) when calling this
+    pub fn get_regs(&self) -> io::Result<Registers> {
+        let regs = self.get_raw_regs()?;
+        let mut fregs: MaybeUninit<user_fpregs_struct> = MaybeUninit::uninit();
+
         let res = unsafe {
             ptrace(
                 PTRACE_GETFPREGS,
                 self.pid,
-                ptr::null() as *const (),
+                ptr::null::<()>(),
                 fregs.as_mut_ptr(),
             )
         };
@@ -166,27 +362,26 @@ impl PTrace {
             return Err(io::Error::last_os_error());
         }
 
-        unsafe {
-            Ok(Registers {
-                regs: regs.assume_init().into(),
-                fregs: fregs.assume_init().into(),
-            })
-        }
+        Ok(Registers {
+            regs: regs.into(),
+            fregs: unsafe { fregs.assume_init() }.into(),
+            xstate: self.get_xstate()?,
+        })
     }
 
     /// Sets the register files of the attached process
     ///
     /// The process should be paused (e.g. with `Self::Stop`) when calling this
-    pub fn set_regs(&self, Registers { regs, fregs }: Registers) -> io::Result<()> {
+    pub fn set_regs(
+        &self,
+        Registers {
+            regs,
+            fregs,
+            xstate,
+        }: Registers,
+    ) -> io::Result<()> {
         let raw_regs: user_regs_struct = regs.into();
-        let res = unsafe {
-            ptrace(
-                PTRACE_SETREGS,
-                self.pid,
-                ptr::null() as *const (),
-                &raw_regs,
-            )
-        };
+        let res = unsafe { ptrace(PTRACE_SETREGS, self.pid, ptr::null::<()>(), &raw_regs) };
         if res < 0 {
             return Err(io::Error::last_os_error());
         }
@@ -198,14 +393,22 @@ impl PTrace {
             raw_fregs.assume_init()
         };
 
-        let res = unsafe {
-            ptrace(
-                PTRACE_SETFPREGS,
-                self.pid,
-                ptr::null() as *const (),
-                &raw_fregs,
-            )
-        };
+        let res = unsafe { ptrace(PTRACE_SETFPREGS, self.pid, ptr::null::<()>(), &raw_fregs) };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // This covers the x87 and SSE state again, but it's only there in newer checkpoints
+        if let Some(xstate) = &xstate {
+            self.set_xstate(xstate)?;
+        }
+
+        Ok(())
+    }
+
+    /// Sets the signal mask of the attached thread, which has to be stopped
+    pub fn set_sigmask(&self, mask: u64) -> io::Result<()> {
+        let res = unsafe { ptrace(PTRACE_SETSIGMASK, self.pid, size_of::<u64>(), &mask) };
         if res < 0 {
             return Err(io::Error::last_os_error());
         }
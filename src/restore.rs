@@ -1,11 +1,17 @@
 use std::{
+    collections::{hash_map::Entry, HashMap},
     error::Error,
     ffi::CString,
-    fs::{metadata, File, Permissions},
-    io::Write,
-    os::unix::fs::PermissionsExt,
+    fs::{self, metadata, File, OpenOptions, Permissions},
+    io::{ErrorKind, Write},
+    mem,
+    os::{
+        fd::RawFd,
+        unix::fs::{FileExt, PermissionsExt},
+    },
     path::{Path, PathBuf},
     process::{Child, Command, Stdio},
+    str::FromStr,
 };
 
 use goblin::{
@@ -17,17 +23,43 @@ use goblin::{
     },
 };
 use libc::{
-    pid_t, SYS_close, SYS_dup2, SYS_getpid, SYS_kill, SYS_lseek, SYS_mmap, SYS_munmap, SYS_open,
-    MAP_FIXED, MAP_PRIVATE, O_RDONLY, SEEK_SET, SIGSTOP, S_IRGRP, S_IRUSR, S_IWUSR, S_IXGRP,
-    S_IXUSR,
+    c_int, c_long, pid_t, SYS_capset, SYS_chdir, SYS_chroot, SYS_clone, SYS_clone3, SYS_close,
+    SYS_dup3, SYS_execve, SYS_exit_group, SYS_fork, SYS_getpid, SYS_kill, SYS_lseek, SYS_madvise,
+    SYS_mlock, SYS_mmap, SYS_mremap, SYS_munmap, SYS_open, SYS_personality, SYS_prctl,
+    SYS_rt_sigaction, SYS_rt_sigprocmask, SYS_set_tid_address, SYS_shmat, SYS_sigaltstack,
+    SYS_umask, SYS_write, AT_SYSINFO_EHDR, CLONE_CHILD_CLEARTID, CLONE_FILES, CLONE_FS,
+    CLONE_SETTLS, CLONE_SIGHAND, CLONE_SYSVSEM, CLONE_THREAD, CLONE_VM, MAP_ANONYMOUS, MAP_FIXED,
+    MAP_NORESERVE, MAP_PRIVATE, MAP_SHARED, MREMAP_FIXED, MREMAP_MAYMOVE, O_CLOEXEC, O_RDONLY,
+    O_RDWR, PROT_NONE, PR_SET_MM, PR_SET_MM_MAP, PR_SET_NAME, SEEK_SET, SIGCHLD, SIGSTOP,
+    SIG_SETMASK, S_IRGRP, S_IRUSR, S_IWUSR, S_IXGRP, S_IXUSR,
 };
-use log::{debug, info};
-use procfs::process::{FDInfo, FDTarget, MemoryMap};
+use log::{debug, info, warn};
+use procfs::process::{FDTarget, MMPermissions, MMapPath, MemoryMap, Process};
 use scroll::Pwrite;
 
 use crate::{
-    checkpoint::StepData,
-    ptrace::{PTrace, Registers},
+    anon::{is_deleted, AnonFile},
+    attrs::ProcAttrs,
+    checkpoint::{
+        open_reservations, ChunkedImage, DeltaImage, Deltas, FileIdentity, OpenFile, SparseImage,
+        StepData,
+    },
+    compat::{CapData, CloneArgs, LINUX_CAPABILITY_VERSION_3},
+    compress::Compression,
+    fd::{self, FdDump, FdPolicy, FdRestorer},
+    filesnap::{FileSnapshot, RolledBack},
+    memory,
+    mm::{MmFields, PRCTL_MM_MAP_SIZE},
+    overlay::FileOverlay,
+    pagemap::{self, PAGE_SIZE},
+    pidns,
+    ptrace::PTrace,
+    shared::{Backing, NewSegments, SharedMap},
+    signal::{self, Signals, SIGSET_SIZE},
+    store::{self, STORE_DIR},
+    thread::{scratch_addr, Thread},
+    tree::TreeNode,
+    vdso, vma,
 };
 
 // TODO: more portability, this whole thing is pretty messy
@@ -38,21 +70,147 @@ use crate::{
 // post-restoraiton for the checkpointer (or anyone else) to see anyways.
 pub const BS_GUID: &str = "bs_43b39ed1-7e9e-4c8d-9d87-540c42dfccbd";
 
+/// The most file mappings we make for one sparse image, anything past that
+/// gets merged across the smallest holes (which just read as zeros anyways)
+const MAX_EXTENTS: usize = 8;
+
+/// How the memory of a region gets back in at restore time
+#[derive(Debug, Clone)]
+pub enum MemImage {
+    /// An image file the bootstrapper maps in directly, with the `(offset, length)`
+    /// runs of data in it if it's sparse (everything else in the region is zero)
+    File {
+        path: PathBuf,
+        extents: Option<Vec<(u64, u64)>>,
+    },
+    /// An image the bootstrapper can't map in because it's compressed, only a delta or in
+    /// the chunk store, so it maps in zeroed memory instead and we write the contents into
+    /// that once it has stopped
+    Anonymous,
+    /// No image, the address range is just reserved with `PROT_NONE` memory
+    Reserved,
+    /// The vdso or vvar, which the bootstrapper moves its own to (see `vdso::bootstrapper_moves`)
+    Kernel,
+    /// Memory that was left out of the checkpoint on purpose, which comes back zeroed
+    Zeroed,
+    /// A private mapping of `file`, which the bootstrapper maps from it again,
+    /// and we write the `pages` of the image that differ from it into once it has stopped
+    Overlay { file: PathBuf, pages: Vec<u64> },
+    /// A shared mapping, which the bootstrapper maps from what it shared its memory with
+    /// instead of from its image
+    Shared(Backing),
+}
+
+/// Everything the bootstrapper of one process puts back
+#[derive(Debug, Clone)]
+pub struct Bootstrap {
+    pub images: Vec<MemImage>,
+    pub maps: Vec<MemoryMap>,
+    pub files: Vec<OpenFile>,
+    /// The `files` that aren't regular files, which `FdRestorer` puts back once it's stopped
+    pub fds: Vec<FdDump>,
+    pub mm: Option<MmFields>,
+    /// The process wide signal state, which older checkpoints don't have
+    pub signals: Option<Signals>,
+    /// The process' working directory, limits and such, which older checkpoints don't have
+    pub attrs: Option<ProcAttrs>,
+    /// Every thread of the process, the thread group leader first
+    pub threads: Vec<Thread>,
+    /// The bootstrappers of the process' children, which it forks off and runs first,
+    /// and the pids the children had
+    pub children: Vec<(PathBuf, pid_t)>,
+    /// Give the children and the other threads their old pids too, which only works in the
+    /// pid namespace of `pidns::spawn_with_pid`, where the process got its own
+    pub same_pid: bool,
+    /// Where the bootstrapper has its channel to `FdRestorer`, over which it says
+    /// that it's about to stop, as the process `index` of the tree
+    pub channel_fd: RawFd,
+    pub index: usize,
+}
+
+/// How to restore a checkpoint
+#[derive(Debug, Clone, Default)]
+pub struct RestoreConfig {
+    /// Leave the restored process stopped, and print its pid
+    pub hang: bool,
+    /// Give the restored processes and their threads their old pids, see `pidns::spawn_with_pid`
+    pub same_pid: bool,
+    /// What to do with fds that can't be restored
+    pub unsupported_fds: FdPolicy,
+    /// What to do with open files that were replaced or truncated since the checkpoint
+    pub changed_files: FilePolicy,
+}
+
+/// What to do with an open file that isn't the same file anymore, or lost data that the
+/// process may have been relying on, like what's past the offset it was read up to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FilePolicy {
+    /// Refuse to restore the process
+    Fail,
+    /// Open it again anyways, and say so
+    #[default]
+    Warn,
+    /// Open it again anyways
+    Reopen,
+}
+
+impl FromStr for FilePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fail" => Ok(Self::Fail),
+            "warn" => Ok(Self::Warn),
+            "reopen" => Ok(Self::Reopen),
+            _ => Err(format!(
+                "unknown file policy {s:?}, expected fail, warn or reopen"
+            )),
+        }
+    }
+}
+
 pub fn create_bootstrapper(
     output_path: impl AsRef<Path>,
-    checkpoint_dir: &PathBuf,
-    maps: Vec<MemoryMap>,
-    files: Vec<(FDInfo, u64)>,
+    bootstrap: &Bootstrap,
 ) -> Result<(), Box<dyn Error>> {
     // TODO: automatically find a non-conflicting vaddr from maps
     let vaddr = 0xe0000;
-    let data_addr = vaddr + header64::SIZEOF_EHDR as u64 + program_header64::SIZEOF_PHDR as u64;
+    let data_addr = vaddr + BS_HEADERS_SIZE;
+
+    // The code has to keep itself mapped while it unmaps everything else, and
+    // how long it is depends on that, so assemble it until the end settles
+    let mut code_end = vaddr + PAGE_SIZE;
+    loop {
+        let (data, program) = assemble_bs_code(bootstrap, vaddr, data_addr, code_end)?;
 
-    let (data, program) = assemble_bs_code(checkpoint_dir, maps, files, vaddr, data_addr)?;
+        let code_addr = (data_addr + data.len() as u64).next_multiple_of(PAGE_SIZE);
+        let end = (code_addr + program.len() as u64).next_multiple_of(PAGE_SIZE);
+        if end <= code_end {
+            // It'd be mapping the process's memory over itself
+            if let Some(map) = bootstrap
+                .maps
+                .iter()
+                .find(|map| map.address.0 < code_end && map.address.1 > vaddr)
+            {
+                return Err(format!(
+                    "The bootstrapper needs {vaddr:#x}-{code_end:#x}, where there's memory at {:#x}",
+                    map.address.0
+                )
+                .into());
+            }
 
-    write_bs_elf(output_path, vaddr, data, program)
+            return write_bs_elf(output_path, vaddr, data, program);
+        }
+        code_end = end;
+    }
 }
 
+/// The size of the ELF header and the two program headers, which the data goes after
+const BS_HEADERS_SIZE: u64 =
+    header64::SIZEOF_EHDR as u64 + 2 * program_header64::SIZEOF_PHDR as u64;
+
+/// Writes the bootstrapper as an ELF that loads the headers and `data` at `vaddr`,
+/// writable, and `program` at the page after that, executable
 pub fn write_bs_elf(
     output_path: impl AsRef<Path>,
     vaddr: u64,
@@ -63,38 +221,56 @@ pub fn write_bs_elf(
 
     let header_size = header64::SIZEOF_EHDR as u64;
     let pheader_size = program_header64::SIZEOF_PHDR as u64;
-    let program_offset = header_size + pheader_size + data.len() as u64;
+    let data_end = BS_HEADERS_SIZE + data.len() as u64;
+    let program_offset = data_end.next_multiple_of(PAGE_SIZE);
     let entry = vaddr + program_offset;
 
     let header: header64::Header = Header {
         e_type: ET_EXEC,
         e_machine: EM_X86_64,
         e_entry: entry,
-        e_phoff: header_size as u64,
-        e_phnum: 1,
+        e_phoff: header_size,
+        e_phnum: 2,
 
         ..Header::new(Ctx::new(Container::Big, Endian::Little))
     }
     .into();
 
-    let pheader: program_header64::ProgramHeader = ProgramHeader {
-        p_flags: PF_R | PF_W | PF_X,
+    let data_pheader: program_header64::ProgramHeader = ProgramHeader {
+        p_flags: PF_R | PF_W,
+        p_offset: 0,
+        p_vaddr: vaddr,
+        p_filesz: data_end,
+        p_memsz: data_end,
+        p_align: PAGE_SIZE,
+
+        ..ProgramHeader::new()
+    }
+    .into();
+
+    let program_pheader: program_header64::ProgramHeader = ProgramHeader {
+        p_flags: PF_R | PF_X,
         p_offset: program_offset,
         p_vaddr: entry,
         p_filesz: program.len() as u64,
         p_memsz: program.len() as u64,
+        p_align: PAGE_SIZE,
 
         ..ProgramHeader::new()
     }
     .into();
 
-    let mut headers = vec![0u8; (header_size + pheader_size) as usize];
+    let mut headers = vec![0u8; BS_HEADERS_SIZE as usize];
     headers.pwrite(header, 0).unwrap();
-    headers.pwrite(pheader, header_size as usize).unwrap();
+    headers.pwrite(data_pheader, header_size as usize).unwrap();
+    headers
+        .pwrite(program_pheader, (header_size + pheader_size) as usize)
+        .unwrap();
 
     let mut outfile = File::create(output_path)?;
     outfile.write_all(&headers)?;
     outfile.write_all(&data)?;
+    outfile.write_all(&vec![0; (program_offset - data_end) as usize])?;
     outfile.write_all(&program)?;
 
     let perms = Permissions::from_mode(S_IRUSR | S_IWUSR | S_IXUSR | S_IRGRP | S_IXGRP);
@@ -103,171 +279,1269 @@ pub fn write_bs_elf(
     Ok(())
 }
 
-/// Returns (data, program)
+/// Reads memory images out of a checkpoint directory, following them back through
+/// the parent chains of incremental checkpoints and into the chunk store
+pub struct ImageReader<'a> {
+    pub path: &'a Path,
+    pub compression: Compression,
+    deltas: HashMap<u64, Option<Deltas>>,
+    sparse: HashMap<u64, Vec<SparseImage>>,
+    chunked: HashMap<u64, Vec<ChunkedImage>>,
+}
+
+impl<'a> ImageReader<'a> {
+    pub fn new(path: &'a Path) -> Result<Self, Box<dyn Error>> {
+        Ok(Self {
+            path,
+            compression: Compression::open(path)?,
+            deltas: HashMap::new(),
+            sparse: HashMap::new(),
+            chunked: HashMap::new(),
+        })
+    }
+
+    fn cp_dir(&self, seq: u64) -> PathBuf {
+        self.path.join(seq.to_string())
+    }
+
+    /// Returns the delta for image `index` of checkpoint `seq`, or `None` if it is a full image
+    pub fn delta(&mut self, seq: u64, index: usize) -> Result<Option<DeltaImage>, Box<dyn Error>> {
+        let cp_dir = self.cp_dir(seq);
+        let deltas = cached(&mut self.deltas, seq, || Deltas::open(&cp_dir))?;
+
+        Ok(deltas
+            .as_ref()
+            .and_then(|deltas| deltas.images.iter().find(|d| d.index == index).cloned()))
+    }
+
+    /// Returns the runs of data in image `index` of checkpoint `seq` if it's sparse
+    pub fn sparse(
+        &mut self,
+        seq: u64,
+        index: usize,
+    ) -> Result<Option<SparseImage>, Box<dyn Error>> {
+        let cp_dir = self.cp_dir(seq);
+        let sparse = cached(&mut self.sparse, seq, || SparseImage::open_all(&cp_dir))?;
+
+        Ok(sparse.iter().find(|s| s.index == index).cloned())
+    }
+
+    /// Returns image `index` of checkpoint `seq` if it's in the chunk store
+    pub fn chunked(
+        &mut self,
+        seq: u64,
+        index: usize,
+    ) -> Result<Option<ChunkedImage>, Box<dyn Error>> {
+        let cp_dir = self.cp_dir(seq);
+        let chunked = cached(&mut self.chunked, seq, || ChunkedImage::open_all(&cp_dir))?;
+
+        Ok(chunked.iter().find(|c| c.index == index).cloned())
+    }
+
+    /// Whether checkpoint `seq` saved the memory of region `index` at all
+    pub fn exists(&mut self, seq: u64, index: usize) -> Result<bool, Box<dyn Error>> {
+        Ok(
+            self.chunked(seq, index)?.is_some()
+                || self.cp_dir(seq).join(index.to_string()).exists(),
+        )
+    }
+
+    /// Reads the full contents of image `index`, of a `len` byte region,
+    /// of checkpoint `seq`, walking back through the parent chain if it's a delta
+    pub fn read(&mut self, seq: u64, index: usize, len: u64) -> Result<Vec<u8>, Box<dyn Error>> {
+        let chunked = self.chunked(seq, index)?;
+        let image = match &chunked {
+            Some(image) => {
+                let store = self.path.join(STORE_DIR);
+                store::read_image(&store, image, &self.compression)?
+            }
+            None => {
+                let image = fs::read(self.cp_dir(seq).join(index.to_string()))?;
+                self.compression.decompress(image)?
+            }
+        };
+
+        if let Some(delta) = self.delta(seq, index)? {
+            let mut mem = self.read(seq - 1, delta.parent, len)?;
+            pagemap::patch_pages(&mut mem, &delta.pages, &image);
+            return Ok(mem);
+        }
+        if chunked.is_some() {
+            return Ok(image);
+        }
+
+        // Uncompressed sparse images are already laid out with holes,
+        // compressed ones are just the runs of data concatenated
+        let extents = match self.sparse(seq, index)? {
+            Some(sparse) if !self.compression.is_none() => sparse.extents,
+            _ => return Ok(image),
+        };
+
+        let mut mem = vec![0; len as usize];
+        let mut pos = 0;
+        for (offset, run_len) in extents {
+            let (offset, run_len) = (offset as usize, run_len as usize);
+            mem[offset..offset + run_len].copy_from_slice(&image[pos..pos + run_len]);
+            pos += run_len;
+        }
+
+        Ok(mem)
+    }
+}
+
+fn cached<T>(
+    cache: &mut HashMap<u64, T>,
+    seq: u64,
+    load: impl FnOnce() -> Result<T, Box<dyn Error>>,
+) -> Result<&mut T, Box<dyn Error>> {
+    Ok(match cache.entry(seq) {
+        Entry::Occupied(e) => e.into_mut(),
+        Entry::Vacant(e) => e.insert(load()?),
+    })
+}
+
+/// Works out how the memory of each region of checkpoint `seq` gets restored.
+///
+/// Compressed images, the deltas of incremental checkpoints and the images in the chunk
+/// store can't be mapped in at all, and neither can the heap, see `fill_images`.
+/// The regions in `overlays` are mapped from their files instead.
+pub fn materialize_images(
+    reader: &mut ImageReader,
+    seq: u64,
+    maps: &[MemoryMap],
+    overlays: &[FileOverlay],
+) -> Result<Vec<MemImage>, Box<dyn Error>> {
+    let cp_dir = reader.cp_dir(seq);
+    let reservations = open_reservations(&cp_dir)?;
+
+    let mut images = vec![];
+    for (i, map) in maps.iter().enumerate() {
+        if reservations.contains(&i) {
+            images.push(MemImage::Reserved);
+            continue;
+        }
+
+        if let Some(overlay) = overlays.iter().find(|o| o.index == i) {
+            images.push(MemImage::Overlay {
+                file: overlay.path.clone(),
+                pages: overlay.pages.clone(),
+            });
+            continue;
+        }
+
+        // Older checkpoints saved an image of the vdso, which we keep mapping in for them
+        let exists = reader.exists(seq, i)?;
+        if vdso::is_vdso(map) && !exists {
+            images.push(MemImage::Kernel);
+            continue;
+        }
+
+        if !exists && vma::dont_dump(map) {
+            images.push(MemImage::Zeroed);
+            continue;
+        }
+
+        if !exists {
+            debug!("reserving maps[{i}] because it had no associated checkpoint file");
+            images.push(MemImage::Reserved);
+            continue;
+        }
+
+        // The heap has to be anonymous memory to be the heap as far as `brk` is concerned,
+        // and so does some memory with flags, see `vma::needs_anonymous`
+        if !reader.compression.is_none()
+            || map.pathname == MMapPath::Heap
+            || vma::needs_anonymous(map)
+        {
+            images.push(MemImage::Anonymous);
+            continue;
+        }
+
+        // Images of incremental checkpoints only hold the dirty pages, and the ones in
+        // the chunk store are in pieces, so they're put together and written in instead
+        if reader.delta(seq, i)?.is_some() || reader.chunked(seq, i)?.is_some() {
+            debug!("Putting maps[{i}] together from its parent chain and the chunk store");
+            images.push(MemImage::Anonymous);
+            continue;
+        }
+
+        images.push(MemImage::File {
+            path: cp_dir.join(i.to_string()),
+            extents: reader.sparse(seq, i)?.map(|s| s.extents),
+        });
+    }
+
+    Ok(images)
+}
+
+/// Writes the decompressed contents of the `MemImage::Anonymous` images
+/// into the memory of the stopped bootstrapper `pid`, skipping zero pages
+/// so that untouched memory stays untouched, and the pages of the
+/// `MemImage::Overlay` images over what's mapped from their files.
+pub fn fill_images(
+    pid: pid_t,
+    reader: &mut ImageReader,
+    seq: u64,
+    maps: &[MemoryMap],
+    images: &[MemImage],
+) -> Result<(), Box<dyn Error>> {
+    // Writes through here ignore page protections, like a debugger's would
+    let mem_file = OpenOptions::new()
+        .write(true)
+        .open(format!("/proc/{pid}/mem"))?;
+
+    for (i, (map, image)) in maps.iter().zip(images).enumerate() {
+        let len = map.address.1 - map.address.0;
+        if let MemImage::Overlay { pages, .. } = image {
+            if pages.is_empty() {
+                continue;
+            }
+
+            debug!(
+                "Patching {} pages of maps[{i}] into the bootstrapper",
+                pages.len()
+            );
+            let mem = reader.read(seq, i, len)?;
+            for (addr, run_len) in memory::page_ranges(map, pages) {
+                let offset = (addr - map.address.0) as usize;
+                mem_file.write_all_at(&mem[offset..offset + run_len], addr)?;
+            }
+            continue;
+        }
+        if !matches!(image, MemImage::Anonymous) {
+            continue;
+        }
+
+        let mem = reader.read(seq, i, len)?;
+        debug!(
+            "Writing {} bytes of maps[{i}] into the bootstrapper",
+            mem.len()
+        );
+
+        let page_size = PAGE_SIZE as usize;
+        let mut run_start = None;
+        for (page, data) in mem.chunks(page_size).enumerate() {
+            let zero = data.iter().all(|b| *b == 0);
+            match (run_start, zero) {
+                (None, false) => run_start = Some(page),
+                (Some(start), true) => {
+                    let run = &mem[start * page_size..page * page_size];
+                    mem_file.write_all_at(run, map.address.0 + (start * page_size) as u64)?;
+                    run_start = None;
+                }
+                _ => {}
+            }
+        }
+
+        if let Some(start) = run_start {
+            let run = &mem[start * page_size..];
+            mem_file.write_all_at(run, map.address.0 + (start * page_size) as u64)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Merges `extents` across the smallest holes until there are at most `max` of them
+fn coalesce_extents(mut extents: Vec<(u64, u64)>, max: usize) -> Vec<(u64, u64)> {
+    while extents.len() > max {
+        let (i, _) = extents
+            .windows(2)
+            .enumerate()
+            .min_by_key(|(_, w)| w[1].0 - (w[0].0 + w[0].1))
+            .unwrap();
+
+        let (next, next_len) = extents.remove(i + 1);
+        extents[i].1 = next + next_len - extents[i].0;
+    }
+
+    extents
+}
+
+/// Returns (data, program), where the program goes at the page after the data
+/// and must end by `code_end`
 pub fn assemble_bs_code(
-    cp_dir: &PathBuf,
-    maps: Vec<MemoryMap>,
-    files: Vec<(FDInfo, u64)>,
+    bootstrap: &Bootstrap,
     vaddr: u64,
     data_addr: u64,
+    code_end: u64,
 ) -> Result<(Vec<u8>, Vec<u8>), Box<dyn Error>> {
+    let Bootstrap {
+        images,
+        maps,
+        files,
+        mm,
+        signals,
+        attrs,
+        threads,
+        children,
+        same_pid,
+        channel_fd,
+        index,
+        ..
+    } = bootstrap;
     let mut data: Vec<u8> = vec![];
 
-    let mut mmap_args = vec![];
-    for (i, map) in maps.into_iter().enumerate() {
+    let kernel_maps: Vec<_> = maps
+        .iter()
+        .zip(images)
+        .filter(|(_, image)| matches!(image, MemImage::Kernel))
+        .map(|(map, _)| map.clone())
+        .collect();
+    let vdso_moves = vdso::bootstrapper_moves(&kernel_maps)?;
+    let kernel_ranges: Vec<_> = kernel_maps.iter().map(|map| map.address).collect();
+
+    // What the calls point at goes in first, and the tables of them after it
+    let push_str = |data: &mut Vec<u8>, s: &str| -> Result<u64, Box<dyn Error>> {
+        let addr = data_addr + data.len() as u64;
+        data.extend(CString::new(s)?.as_bytes_with_nul());
+        Ok(addr)
+    };
+
+    // Every child's path, and the clone3 arguments that give it its pid if there are any
+    let mut child_records = vec![];
+    for (child, pid) in children {
+        let path = push_str(&mut data, child.to_str().unwrap())?;
+        let args = match *same_pid {
+            true => {
+                let set_tid = push_table(&mut data, data_addr, &[*pid as u64]).0;
+                let args = CloneArgs {
+                    exit_signal: SIGCHLD as u64,
+                    set_tid,
+                    set_tid_size: 1,
+                    ..Default::default()
+                };
+                push_table(&mut data, data_addr, &[args]).0
+            }
+            false => 0,
+        };
+        child_records.push([path, args]);
+    }
+
+    // The calls that set everything up, before the other threads are started
+    let mut setup = vec![];
+
+    let mut advice = vec![];
+    for (i, (map, image)) in maps.iter().zip(images).enumerate() {
         let addr = map.address.0;
         let len = map.address.1 - addr;
-        let prot = map.perms.bits();
-
-        let flags = MAP_FIXED | MAP_PRIVATE;
-        let (file_path, offset) = match map.pathname {
-            // It seems like there are some parts of an ELF file that will
-            // end up in a read only memory mapping but differ from the on disk
-            // version of the file, so for now we comment this out
-            // MMapPath::Path(path) if !map.perms.contains(MMPermissions::WRITE) => (path, map.offset),
-            _ => {
-                let path = cp_dir.join(i.to_string());
-                if path.exists() {
-                    (path, 0u64)
-                } else {
-                    debug!(
-                        "skipping maps[{i}] at {path:?} because it had no associated checkpoint file"
-                    );
-                    continue;
-                }
+        let prot = map.perms.bits() as u64;
+
+        if !matches!(image, MemImage::Kernel) {
+            for a in vma::advice(map) {
+                advice.push(BsCall::new(SYS_madvise, [addr, len, a as u64]));
+            }
+            if vma::locked(map) {
+                advice.push(BsCall::new(SYS_mlock, [addr, len]));
+            }
+        }
+
+        let flags = MAP_FIXED | MAP_PRIVATE | vma::mmap_flags(map);
+        let anonymous = |prot: u64, flags: c_int| {
+            BsCall::new(
+                SYS_mmap,
+                [addr, len, prot, (flags | MAP_ANONYMOUS) as u64, u64::MAX, 0],
+            )
+        };
+
+        let (path, open_flags, mmaps) = match image {
+            MemImage::Reserved => {
+                setup.push(anonymous(PROT_NONE as u64, flags | MAP_NORESERVE));
+                continue;
+            }
+            MemImage::Anonymous | MemImage::Zeroed => {
+                setup.push(anonymous(prot, flags));
+                continue;
+            }
+            MemImage::Kernel => continue,
+            MemImage::Overlay { file, .. } => {
+                (file, O_RDONLY, vec![(addr, len, flags, map.offset)])
+            }
+            MemImage::Shared(Backing::SysV { shmid, .. }) => {
+                let shmat_flags = Backing::shmat_flags(map.perms) as u64;
+                setup.push(BsCall::new(SYS_shmat, [*shmid as u64, addr, shmat_flags]));
+                continue;
+            }
+            MemImage::Shared(Backing::File { path, .. }) => {
+                // Mapping it writable takes having it open for writing
+                let open_flags = match map.perms.contains(MMPermissions::WRITE) {
+                    true => O_RDWR,
+                    false => O_RDONLY,
+                };
+                let flags = MAP_FIXED | MAP_SHARED | vma::mmap_flags(map);
+                (path, open_flags, vec![(addr, len, flags, map.offset)])
+            }
+            MemImage::File {
+                path,
+                extents: None,
+            } => (path, O_RDONLY, vec![(addr, len, flags, 0)]),
+            MemImage::File {
+                path,
+                extents: Some(extents),
+            } => {
+                // Sparse images go in as zeroed memory with the parts that
+                // actually had data mapped over it from the image
+                let extents = coalesce_extents(extents.clone(), MAX_EXTENTS);
+                debug!(
+                    "mapping maps[{i}] as anonymous memory with {} extents from its image",
+                    extents.len()
+                );
+
+                setup.push(anonymous(prot, flags));
+                let mmaps = extents
+                    .into_iter()
+                    .map(|(offset, ext_len)| (addr + offset, ext_len, flags, offset))
+                    .collect();
+                (path, O_RDONLY, mmaps)
             }
         };
 
-        let path_ptr = data.len() as u64;
-        let raw_path = CString::new(file_path.to_str().unwrap())?;
-        data.extend(raw_path.as_bytes_with_nul());
+        // open the file, map each part of it from the fd, and close it again
+        let path = push_str(&mut data, path.to_str().unwrap())?;
+        setup.push(BsCall::new(SYS_open, [path, open_flags as u64, 0o666]).save());
+        for (addr, len, flags, offset) in mmaps {
+            setup.push(BsCall::new(SYS_mmap, [addr, len, prot, flags as u64, 0, offset]).saved(4));
+        }
+        setup.push(BsCall::new(SYS_close, []).saved(0));
+    }
+
+    // Then give them back what they were madvised with, and lock the ones that were.
+    // Locking can fail with the bootstrapper's RLIMIT_MEMLOCK, see `vma::check`.
+    setup.extend(advice);
+
+    // Point the kernel's idea of where the heap, arguments, etc. are back at the
+    // checkpointed memory. This needs a kernel with CONFIG_CHECKPOINT_RESTORE and
+    // fields the kernel accepts, so it's allowed to fail (we check once we're stopped)
+    if let Some(mm) = mm {
+        let auxv = data_addr + data.len() as u64;
+        data.extend(mm.auxv_bytes());
 
-        mmap_args.push((addr, len, prot, flags, path_ptr, offset));
+        let prctl_map = data_addr + data.len() as u64;
+        data.extend(mm.prctl_map(auxv));
+        setup.push(BsCall::new(
+            SYS_prctl,
+            [
+                PR_SET_MM as u64,
+                PR_SET_MM_MAP as u64,
+                prctl_map,
+                PRCTL_MM_MAP_SIZE as u64,
+                0,
+            ],
+        ));
     }
 
-    let mut open_args = vec![];
-    for (file, offset) in files {
-        let FDTarget::Path(path) = file.target else {
-            // TODO: these files probably shouldn't even be checkpointed if we're just going to ignore them
+    // open all the checkpointed files
+    for file in files {
+        // Everything else is put back by `FdRestorer`
+        let FDTarget::Path(path) = &file.target else {
             continue;
         };
 
-        let meta = metadata(&path)?;
-        if !meta.is_file() {
+        // Anything that's gone by now is snapshotted, and made again before we start,
+        // other than what was gone already, which `FdRestorer` makes again
+        if is_deleted(path) || metadata(path).is_ok_and(|meta| !meta.is_file()) {
             continue;
         }
 
         // TODO: make the path absolute
-        let path_ptr = data.len();
-        let raw_path = CString::new(path.to_str().unwrap())?;
-        data.extend(raw_path.as_bytes_with_nul());
+        let path = push_str(&mut data, path.to_str().unwrap())?;
+        let fd = file.fd as u64;
 
-        open_args.push((file.fd, path_ptr, file.mode, offset));
+        // dup3 it to the right fd number, and close the one it was opened
+        // as unless that already was the right one. dup3 is what keeps
+        // O_CLOEXEC, which the open had from the flags already.
+        let cloexec = (file.flags & O_CLOEXEC) as u64;
+        setup.push(BsCall::new(SYS_open, [path, file.flags as u64, 0o666]).save());
+        setup.push(
+            BsCall::new(SYS_dup3, [0, fd, cloexec])
+                .saved(0)
+                .unless_saved(),
+        );
+        setup.push(BsCall::new(SYS_close, [0, fd]).saved(0).unless_saved());
+        setup.push(BsCall::new(SYS_lseek, [fd, file.offset, SEEK_SET as u64]));
     }
 
+    // These go after everything that's opened by a path, which is relative
+    // to our working directory, and before the threads that inherit some of them
+    if let Some(attrs) = attrs {
+        let cwd = push_str(&mut data, attrs.cwd.to_str().unwrap())?;
+        setup.push(BsCall::new(SYS_chdir, [cwd]));
+
+        // This needs CAP_SYS_CHROOT, so it's only tried when it'd do anything
+        if attrs.root != Path::new("/") {
+            let root = push_str(&mut data, attrs.root.to_str().unwrap())?;
+            setup.push(BsCall::new(SYS_chroot, [root]));
+        }
+
+        if let Some(umask) = attrs.umask {
+            setup.push(BsCall::new(SYS_umask, [umask as u64]));
+        }
+
+        setup.push(BsCall::new(SYS_personality, [attrs.personality as u64]));
+
+        // PR_SET_NAME takes at most 16 bytes including the nul, which comm already fits in
+        let comm = push_str(&mut data, &attrs.comm)?;
+        setup.push(BsCall::new(SYS_prctl, [PR_SET_NAME as u64, comm]));
+    }
+
+    // Every thread's alternate signal stack (or 0 if it has none). Their signal masks
+    // are set with ptrace once they're stopped, until then everything stays blocked
+    // since the bootstrapper has no stack to take a signal on.
+    let all_signals = data_addr + data.len() as u64;
+    data.extend(u64::MAX.to_ne_bytes());
+
+    let mut altstacks = vec![];
+    for thread in threads {
+        altstacks.push(thread.altstack.as_ref().map_or(0, |altstack| {
+            let addr = data_addr + data.len() as u64;
+            data.extend(altstack.bytes());
+            addr
+        }));
+    }
+
+    // The calls the leader makes once the other threads are going
+    let mut finish = vec![];
+    if let Some(leader) = threads.first() {
+        if leader.clear_child_tid != 0 {
+            finish.push(BsCall::new(SYS_set_tid_address, [leader.clear_child_tid]));
+        }
+
+        if altstacks[0] != 0 {
+            finish.push(BsCall::new(SYS_sigaltstack, [altstacks[0]]));
+        }
+    }
+
+    // Put the signal handlers back last, they're for the restored process and
+    // would make a mess of the bootstrapper if a signal came in before this
+    for action in signals.iter().flat_map(|signals| &signals.actions) {
+        let addr = data_addr + data.len() as u64;
+        data.extend(action.bytes());
+        finish.push(BsCall::new(
+            SYS_rt_sigaction,
+            [action.signal as u64, addr, 0, SIGSET_SIZE],
+        ));
+    }
+
+    // Giving the other threads and the children their pids takes CAP_SYS_ADMIN in the pid
+    // namespace, which the process shouldn't keep, unless it's root in there anyways. Each
+    // thread has its own, the others drop theirs as they start.
+    let drop_caps = *same_pid && unsafe { libc::getuid() } != 0;
+    let no_caps = data_addr + data.len() as u64;
+    data.extend(LINUX_CAPABILITY_VERSION_3.to_ne_bytes());
+    data.extend([0; 4 + 2 * mem::size_of::<CapData>()]);
+    if drop_caps {
+        finish.push(BsCall::new(SYS_capset, [no_caps, no_caps + 8]));
+    }
+
+    // Say which process of the tree this is over the channel, and stop, which stops
+    // every thread. We find out about the bootstrapper from this, see `FdRestorer::next_stopping`.
+    let report = data_addr + data.len() as u64;
+    data.extend((*index as u64).to_ne_bytes());
+    finish.push(BsCall::new(SYS_write, [*channel_fd as u64, report, 8]));
+    finish.push(BsCall::new(SYS_getpid, []).save());
+    finish.push(BsCall::new(SYS_kill, [0, SIGSTOP as u64]).saved(0));
+
+    // The tables are all 8 byte values. The threads `lock inc` the count of the ones
+    // that have checked in, which has to be aligned so that it can't straddle two
+    // cache lines (a split lock).
+    data.resize(data.len().next_multiple_of(8), 0);
+    let ready = data_addr + data.len() as u64;
+    data.extend(0u64.to_ne_bytes());
+
+    let child_table = push_table(&mut data, data_addr, &child_records);
+    let setup_table = push_table(&mut data, data_addr, &setup);
+    let finish_table = push_table(&mut data, data_addr, &finish);
+
+    let mut thread_records = vec![];
+    for (i, thread) in threads.iter().enumerate().skip(1) {
+        let mut flags = CLONE_VM
+            | CLONE_FS
+            | CLONE_FILES
+            | CLONE_SIGHAND
+            | CLONE_THREAD
+            | CLONE_SYSVSEM
+            | CLONE_SETTLS;
+        if thread.clear_child_tid != 0 {
+            flags |= CLONE_CHILD_CLEARTID;
+        }
+
+        let args = match *same_pid {
+            true => {
+                let set_tid = push_table(&mut data, data_addr, &[thread.tid as u64]).0;
+                let args = CloneArgs {
+                    flags: flags as u64,
+                    child_tid: thread.clear_child_tid,
+                    tls: thread.regs.regs.fs_base,
+                    set_tid,
+                    set_tid_size: 1,
+                    ..Default::default()
+                };
+                push_table(&mut data, data_addr, &[args]).0
+            }
+            false => 0,
+        };
+
+        thread_records.push([
+            flags as u64,
+            thread.regs.regs.rsp,
+            thread.clear_child_tid,
+            thread.regs.regs.fs_base,
+            i as u64,
+            altstacks[i],
+            args,
+            0,
+        ]);
+    }
+    let thread_table = push_table(&mut data, data_addr, &thread_records);
+
     {
         use iced_x86::code_asm::*;
 
         let mut c = CodeAssembler::new(64)?;
 
-        // unmap everything but our own code section
-        // We make the assumption that our required space is vaddr to vaddr + 0x1000
-        let code_len = 0x1000;
-        c.xor(rdi, rdi)?;
-        c.mov(rsi, vaddr)?;
-        c.mov(rax, SYS_munmap)?;
+        // Block every signal until the restorer gives the threads their masks,
+        // a handler couldn't run on the stack we're about to unmap anyway
+        c.mov(rdi, SIG_SETMASK as u64)?;
+        c.mov(rsi, all_signals)?;
+        c.xor(rdx, rdx)?;
+        c.mov(r10, SIGSET_SIZE)?;
+        c.mov(rax, SYS_rt_sigprocmask)?;
         c.syscall()?;
 
-        // TODO: I'm pretty sure this munmap call returns an error
-        c.mov(rdi, vaddr + code_len)?;
-        c.mov(rsi, u64::MAX - (vaddr + code_len))?;
-        c.mov(rax, SYS_munmap)?;
+        // Find our vdso in the auxiliary vector, which is on the stack after
+        // argc, argv and envp, and keep it in r15 (0 if there's none)
+        let mut skip_env = c.create_label();
+        let mut next_aux = c.create_label();
+        let mut found_vdso = c.create_label();
+        c.mov(rsi, rsp)?;
+        c.mov(rax, qword_ptr(rsi))?;
+        c.lea(rsi, qword_ptr(rsi + rax * 8 + 16))?;
+        c.set_label(&mut skip_env)?;
+        c.add(rsi, 8)?;
+        c.cmp(qword_ptr(rsi - 8), 0)?;
+        c.jne(skip_env)?;
+        c.set_label(&mut next_aux)?;
+        c.mov(rax, qword_ptr(rsi))?;
+        c.mov(r15, qword_ptr(rsi + 8))?;
+        c.add(rsi, 16)?;
+        c.test(rax, rax)?;
+        c.jz(found_vdso)?;
+        c.cmp(rax, AT_SYSINFO_EHDR as i32)?;
+        c.jne(next_aux)?;
+        c.set_label(&mut found_vdso)?;
+
+        // Start the children's bootstrappers first, so that they're our children again
+        let mut next_child = c.create_label();
+        let mut parent = c.create_label();
+        let mut check_child = c.create_label();
+        c.mov(rbx, child_table.0)?;
+        c.jmp(check_child)?;
+
+        c.set_label(&mut next_child)?;
+        match *same_pid {
+            true => {
+                c.mov(rdi, qword_ptr(rbx + 8))?;
+                c.mov(rsi, mem::size_of::<CloneArgs>() as u64)?;
+                c.mov(rax, SYS_clone3)?;
+            }
+            false => c.mov(rax, SYS_fork)?,
+        }
         c.syscall()?;
+        c.test(rax, rax)?;
+        c.jnz(parent)?;
 
-        // Now go through and mmap in all the checkpoint mappings
-        // TODO: this loop shouldn't be unrolled
-        for (addr, len, prot, flags, path_ptr, offset) in mmap_args {
-            let path_ptr = data_addr + path_ptr;
+        c.mov(rdi, qword_ptr(rbx))?;
+        c.xor(rsi, rsi)?;
+        c.xor(rdx, rdx)?;
+        c.mov(rax, SYS_execve)?;
+        c.syscall()?;
 
-            // open the file
-            c.mov(rdi, path_ptr)?;
-            c.mov(rsi, O_RDONLY as u64)?;
-            c.mov(rdx, 0o666u64)?;
-            c.mov(rax, SYS_open)?;
-            c.syscall()?;
+        // the exec failed
+        c.mov(rdi, 127u64)?;
+        c.mov(rax, SYS_exit_group)?;
+        c.syscall()?;
+
+        c.set_label(&mut parent)?;
+        c.add(rbx, 16)?;
+        c.set_label(&mut check_child)?;
+        c.mov(rax, child_table.1)?;
+        c.cmp(rbx, rax)?;
+        c.jb(next_child)?;
+
+        // Move the vdso and vvar to where the process had them, before its memory
+        // goes in. They all move by the same amount, so they're moved in the
+        // direction they're going to not land one on another that hasn't moved yet.
+        if let Some(vdso) = vdso_moves.iter().find(|m| m.offset == 0) {
+            let mut moving_up = c.create_label();
+            let mut moved = c.create_label();
+            c.test(r15, r15)?;
+            c.jz(moved)?;
+            c.mov(rax, vdso.target)?;
+            c.cmp(r15, rax)?;
+            c.je(moved)?;
+            c.jb(moving_up)?;
 
-            // mmap it in
+            let emit_moves = |c: &mut CodeAssembler, up: bool| -> Result<(), IcedError> {
+                let mut moves = vdso_moves.clone();
+                if up {
+                    moves.reverse();
+                }
+                for m in moves {
+                    c.mov(rdi, m.offset)?;
+                    c.add(rdi, r15)?;
+                    c.mov(rsi, m.len)?;
+                    c.mov(rdx, m.len)?;
+                    c.mov(r10, (MREMAP_MAYMOVE | MREMAP_FIXED) as u64)?;
+                    c.mov(r8, m.target)?;
+                    c.mov(rax, SYS_mremap)?;
+                    c.syscall()?;
+                }
+                Ok(())
+            };
+            emit_moves(&mut c, false)?;
+            c.jmp(moved)?;
+            c.set_label(&mut moving_up)?;
+            emit_moves(&mut c, true)?;
+            c.set_label(&mut moved)?;
+        }
+
+        // unmap everything but ourselves, from vaddr to code_end, and the vdso
+        for (addr, len) in unmap_ranges(vaddr, code_end, &kernel_ranges) {
             c.mov(rdi, addr)?;
             c.mov(rsi, len)?;
-            c.mov(rdx, prot as u64)?;
-            c.mov(r10, flags as u64)?;
-            c.mov(r8, rax)?;
-            c.mov(r9, offset)?;
-            c.mov(rax, SYS_mmap)?;
-            c.syscall()?;
-
-            // close the file
-            c.mov(rdi, 3u64)?;
-            c.mov(rax, SYS_close)?;
+            c.mov(rax, SYS_munmap)?;
             c.syscall()?;
         }
 
-        // open all the checkpointed files
-        // TODO: this loop shouldn't be unrolled
-        for (fd, path_ptr, mode, offset) in open_args {
-            let path_ptr = data_addr + path_ptr as u64;
+        // Nothing here needs a stack, and the one we had is gone now. The kernel won't
+        // change a thread's alternate signal stack while it's on it, which it goes by
+        // the stack pointer for, so it's cleared to not be on anything.
+        c.xor(esp, esp)?;
 
-            // open the file
-            c.mov(rdi, path_ptr)?;
-            c.mov(rsi, mode as u64)?;
-            c.mov(rdx, 0o666u64)?;
-            c.mov(rax, SYS_open)?;
-            c.syscall()?;
+        // Now go through and map in all the checkpoint mappings, open the files, etc.
+        walk_calls(&mut c, setup_table)?;
 
-            // if it's not already the correct fd number, we're good
-            let mut opened = c.create_label();
-            c.cmp(eax, fd)?;
-            c.je(opened)?;
+        // Start the other threads on their own stacks. Each one sets its alternate
+        // signal stack, checks in, and spins until it's stopped and we give it its registers,
+        // which we tell it apart by with the index of the thread in r13.
+        let mut next_thread = c.create_label();
+        let mut parent = c.create_label();
+        let mut checked_in = c.create_label();
+        let mut check_thread = c.create_label();
+        c.mov(rbx, thread_table.0)?;
+        c.jmp(check_thread)?;
 
-            // otherwise, dup2 it to the right number
-            c.mov(rdi, rax)?;
-            c.mov(rsi, fd as u64)?;
-            c.mov(rax, SYS_dup2)?;
-            c.syscall()?;
+        c.set_label(&mut next_thread)?;
+        c.mov(r13, qword_ptr(rbx + 32))?;
+        match *same_pid {
+            // Which doesn't set a stack, the thread doesn't need one until it has its registers
+            true => {
+                c.mov(rdi, qword_ptr(rbx + 48))?;
+                c.mov(rsi, mem::size_of::<CloneArgs>() as u64)?;
+                c.mov(rax, SYS_clone3)?;
+            }
+            false => {
+                c.mov(rdi, qword_ptr(rbx))?;
+                c.mov(rsi, qword_ptr(rbx + 8))?;
+                c.xor(rdx, rdx)?;
+                c.mov(r10, qword_ptr(rbx + 16))?;
+                c.mov(r8, qword_ptr(rbx + 24))?;
+                c.mov(rax, SYS_clone)?;
+            }
+        }
+        c.syscall()?;
+        c.test(rax, rax)?;
+        c.jnz(parent)?;
 
-            // close the old file descriptor
-            c.mov(rax, SYS_close)?;
+        // The new thread has our registers, so rbx is still its record
+        if drop_caps {
+            c.mov(rdi, no_caps)?;
+            c.mov(rsi, no_caps + 8)?;
+            c.mov(rax, SYS_capset)?;
             c.syscall()?;
+        }
+        c.mov(rdi, qword_ptr(rbx + 40))?;
+        c.test(rdi, rdi)?;
+        c.jz(checked_in)?;
+        c.xor(esp, esp)?;
+        c.xor(rsi, rsi)?;
+        c.mov(rax, SYS_sigaltstack)?;
+        c.syscall()?;
 
-            c.set_label(&mut opened)?;
+        c.set_label(&mut checked_in)?;
+        c.mov(rax, ready)?;
+        c.lock().inc(qword_ptr(rax))?;
 
-            // seek the file to the correct offset
-            c.mov(rdi, fd as u64)?;
-            c.mov(rsi, offset)?;
-            c.mov(rdx, SEEK_SET as u64)?;
-            c.mov(rax, SYS_lseek)?;
-            c.syscall()?;
-        }
+        let mut spin = c.create_label();
+        c.set_label(&mut spin)?;
+        c.pause()?;
+        c.jmp(spin)?;
 
-        // have the bootstrapper stop itself
-        c.mov(rax, SYS_getpid)?;
-        c.syscall()?;
-        c.mov(rdi, rax)?;
-        c.mov(rsi, SIGSTOP as u64)?;
-        c.mov(rax, SYS_kill)?;
-        c.syscall()?;
+        c.set_label(&mut parent)?;
+        c.add(rbx, 64)?;
+        c.set_label(&mut check_thread)?;
+        c.mov(rax, thread_table.1)?;
+        c.cmp(rbx, rax)?;
+        c.jb(next_thread)?;
+
+        // Wait for all of them to check in, otherwise we could stop
+        // one before it has set its alternate signal stack
+        let mut wait = c.create_label();
+        c.mov(rax, ready)?;
+        c.set_label(&mut wait)?;
+        c.pause()?;
+        c.cmp(qword_ptr(rax), thread_records.len() as i32)?;
+        c.jne(wait)?;
+
+        walk_calls(&mut c, finish_table)?;
 
         // loop infinitely (maybe unnecessary)
         let mut loop_loc = c.create_label();
         c.set_label(&mut loop_loc)?;
         c.jmp(loop_loc)?;
 
-        let entry = data_addr + data.len() as u64;
+        let entry = (data_addr + data.len() as u64).next_multiple_of(PAGE_SIZE);
         Ok((data, c.assemble(entry)?))
     }
 }
 
-pub fn restore_checkpoint(path: &PathBuf, hang: bool) -> Result<Child, Box<dyn Error>> {
+/// Where the address space ends with 4-level page tables (the kernel's `TASK_SIZE`),
+/// `munmap` won't take a range that goes past it
+const TASK_SIZE: u64 = (1 << 47) - PAGE_SIZE;
+/// Where it ends with 5-level page tables, which can have memory past `TASK_SIZE`
+const TASK_SIZE_LA57: u64 = (1 << 56) - PAGE_SIZE;
+
+/// The `(addr, len)` ranges the bootstrapper unmaps, which is everything but itself
+/// (from `vaddr` to `code_end`) and the `keep` ranges
+fn unmap_ranges(vaddr: u64, code_end: u64, keep: &[(u64, u64)]) -> Vec<(u64, u64)> {
+    let mut holes = keep.to_vec();
+    holes.push((vaddr, code_end));
+    holes.sort();
+
+    let mut ranges = vec![];
+    let mut start = 0;
+    for (hole_start, hole_end) in holes {
+        if start < hole_start {
+            ranges.push((start, hole_start - start));
+        }
+        start = start.max(hole_end);
+    }
+    if start < TASK_SIZE {
+        ranges.push((start, TASK_SIZE - start));
+    }
+    // This one fails without 5-level page tables, which is fine
+    ranges.push((TASK_SIZE, TASK_SIZE_LA57 - TASK_SIZE));
+    ranges
+}
+
+/// The bootstrapper keeps the result of the call in r12, for the ones after it
+const CALL_SAVE: u64 = 1;
+/// The bootstrapper skips the call if the result it kept is the call's second argument
+const CALL_UNLESS_SAVED: u64 = 1 << 1;
+
+/// A syscall that the bootstrapper makes from one of the tables in its data
+#[derive(Debug, Clone, Copy)]
+struct BsCall {
+    nr: c_long,
+    args: [u64; 6],
+    /// The `CALL_*` flags, and which arguments are the kept result from bit 2 on
+    flags: u64,
+}
+
+impl BsCall {
+    fn new<const N: usize>(nr: c_long, args: [u64; N]) -> Self {
+        let mut all = [0; 6];
+        all[..N].copy_from_slice(&args);
+        Self {
+            nr,
+            args: all,
+            flags: 0,
+        }
+    }
+
+    /// Keeps the result for the calls after it, like the fd that an open returns
+    fn save(mut self) -> Self {
+        self.flags |= CALL_SAVE;
+        self
+    }
+
+    /// Passes the kept result as argument `arg`
+    fn saved(mut self, arg: usize) -> Self {
+        self.flags |= 1 << (2 + arg);
+        self
+    }
+
+    fn unless_saved(mut self) -> Self {
+        self.flags |= CALL_UNLESS_SAVED;
+        self
+    }
+}
+
+/// Something that goes in a table in the bootstrapper's data as 8 byte values
+trait BsRecord {
+    fn values(&self) -> Vec<u64>;
+}
+
+impl BsRecord for u64 {
+    fn values(&self) -> Vec<u64> {
+        vec![*self]
+    }
+}
+
+impl<const N: usize> BsRecord for [u64; N] {
+    fn values(&self) -> Vec<u64> {
+        self.to_vec()
+    }
+}
+
+impl BsRecord for CloneArgs {
+    fn values(&self) -> Vec<u64> {
+        vec![
+            self.flags,
+            self.pidfd,
+            self.child_tid,
+            self.parent_tid,
+            self.exit_signal,
+            self.stack,
+            self.stack_size,
+            self.tls,
+            self.set_tid,
+            self.set_tid_size,
+            self.cgroup,
+        ]
+    }
+}
+
+impl BsRecord for BsCall {
+    fn values(&self) -> Vec<u64> {
+        let mut values = vec![self.nr as u64];
+        values.extend(self.args);
+        values.push(self.flags);
+        values
+    }
+}
+
+/// Puts `records` at the end of `data` as a table, returning the addresses
+/// it starts and ends at once `data` is at `data_addr`
+fn push_table(data: &mut Vec<u8>, data_addr: u64, records: &[impl BsRecord]) -> (u64, u64) {
+    data.resize(data.len().next_multiple_of(8), 0);
+    let start = data_addr + data.len() as u64;
+    for value in records.iter().flat_map(|record| record.values()) {
+        data.extend(value.to_ne_bytes());
+    }
+    (start, data_addr + data.len() as u64)
+}
+
+/// Emits the loop that makes the calls in the table from `start` to `end`, one after the
+/// other. It walks it with rbx, and keeps the result of the ones that ask for it in r12.
+fn walk_calls(
+    c: &mut iced_x86::code_asm::CodeAssembler,
+    (start, end): (u64, u64),
+) -> Result<(), Box<dyn Error>> {
+    use iced_x86::code_asm::*;
+
+    let mut next = c.create_label();
+    let mut call = c.create_label();
+    let mut done_call = c.create_label();
+    let mut check = c.create_label();
+
+    c.mov(rbx, start)?;
+    c.jmp(check)?;
+
+    c.set_label(&mut next)?;
+    c.mov(r14, qword_ptr(rbx + 56))?;
+    c.test(r14, CALL_UNLESS_SAVED as i32)?;
+    c.jz(call)?;
+    c.cmp(r12, qword_ptr(rbx + 16))?;
+    c.je(done_call)?;
+
+    c.set_label(&mut call)?;
+    for (i, reg) in [rdi, rsi, rdx, r10, r8, r9].into_iter().enumerate() {
+        c.mov(reg, qword_ptr(rbx + 8 * (i as i32 + 1)))?;
+        c.test(r14, 1 << (i + 2))?;
+        c.cmovnz(reg, r12)?;
+    }
+    c.mov(rax, qword_ptr(rbx))?;
+    c.syscall()?;
+
+    c.test(r14, CALL_SAVE as i32)?;
+    c.jz(done_call)?;
+    c.mov(r12, rax)?;
+
+    c.set_label(&mut done_call)?;
+    c.add(rbx, 64)?;
+    c.set_label(&mut check)?;
+    c.mov(rax, end)?;
+    c.cmp(rbx, rax)?;
+    c.jb(next)?;
+
+    Ok(())
+}
+
+/// A process of the checkpoint being restored, and where its bootstrapper goes
+struct RestoreProcess<'a> {
+    seq: u64,
+    cp_path: PathBuf,
+    bs_path: PathBuf,
+    reader: ImageReader<'a>,
+    bootstrap: Bootstrap,
+    /// The files to roll back before the restored process can write to them
+    snapshots: Vec<FileSnapshot>,
+    /// The files that `FdRestorer` makes again for the fds and mappings that had them
+    anon_files: Vec<AnonFile>,
+}
+
+/// Checks that the regular files in `files` are still the ones the process had open, with
+/// at least as much in them as there was, and goes by `policy` for the ones that aren't.
+/// The ones in `snapshots` are going to be just like they were anyways, as long as
+/// they're still the same file (or gone, then they're made again).
+fn check_files(
+    files: &[OpenFile],
+    snapshots: &[FileSnapshot],
+    policy: FilePolicy,
+) -> Result<(), Box<dyn Error>> {
+    for file in files {
+        let (FDTarget::Path(path), Some(old)) = (&file.target, &file.identity) else {
+            continue;
+        };
+        if is_deleted(path) {
+            continue;
+        }
+        let snapshot = snapshots.iter().find(|s| s.path == *path);
+
+        let meta = match metadata(path) {
+            Ok(meta) => meta,
+            Err(e) if e.kind() == ErrorKind::NotFound && snapshot.is_some() => continue,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                return Err(format!("fd {} was open to {path:?}, which is gone", file.fd).into())
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if !meta.is_file() {
+            continue;
+        }
+
+        let new = FileIdentity::from(&meta);
+        if snapshot.is_some_and(|s| s.is_file(&new)) {
+            continue;
+        }
+        let change = if (new.dev, new.inode) != (old.dev, old.inode) {
+            "was replaced".to_string()
+        } else if new.size < old.size {
+            format!("was truncated from {} to {} bytes", old.size, new.size)
+        } else {
+            if new.mtime != old.mtime {
+                debug!("{path:?} was written to since the checkpoint");
+            }
+            continue;
+        };
+
+        let what = format!("{path:?} at fd {} {change} since the checkpoint", file.fd);
+        match policy {
+            FilePolicy::Fail => {
+                return Err(format!("{what} (--changed-files reopen opens it anyways)").into())
+            }
+            FilePolicy::Warn => warn!("{what}, opening it anyways"),
+            FilePolicy::Reopen => debug!("{what}"),
+        }
+    }
+
+    Ok(())
+}
+
+/// Builds the bootstrapper for checkpoint `seq` of the process checkpointed at `path`,
+/// which forks off the bootstrappers at `children`
+fn prepare_process<'a>(
+    path: &'a Path,
+    seq: u64,
+    children: Vec<(PathBuf, pid_t)>,
+    changed_files: FilePolicy,
+    new_segments: &mut NewSegments,
+) -> Result<RestoreProcess<'a>, Box<dyn Error>> {
+    let cp_path = path.join(seq.to_string());
+    info!("Reading in last checkpoint data from {cp_path:?}");
+
+    let threads = Thread::open_all(&cp_path)?;
+    let maps: Vec<MemoryMap> = serde_json::from_reader(File::open(cp_path.join("maps"))?)?;
+    let files = OpenFile::open_all(&cp_path)?;
+    let snapshots = FileSnapshot::open_all(&cp_path)?;
+    let anon_files = AnonFile::open_all(&cp_path)?;
+    check_files(&files, &snapshots, changed_files)?;
+    let fds = FdDump::open_all(&cp_path)?;
+    let signals = Signals::open(&cp_path)?;
+    let attrs = ProcAttrs::open(&cp_path)?;
+    let mm: Option<MmFields> = match File::open(cp_path.join("mm")) {
+        Ok(file) => Some(serde_json::from_reader(file)?),
+        Err(e) if e.kind() == ErrorKind::NotFound => None,
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut reader = ImageReader::new(path)?;
+    let overlays = FileOverlay::open_all(&cp_path)?;
+    for overlay in &overlays {
+        overlay.verify(&maps[overlay.index])?;
+    }
+    let mut images = materialize_images(&mut reader, seq, &maps, &overlays)?;
+
+    // What's still there gets shared again, what isn't is mapped from its image
+    for shared in SharedMap::open_all(&cp_path)? {
+        let Some(i) = maps.iter().position(|m| m.address.0 == shared.addr) else {
+            continue;
+        };
+        let map = &maps[i];
+        let image = || match images[i] {
+            MemImage::Reserved => Ok(vec![]),
+            _ => reader.read(seq, i, map.address.1 - map.address.0),
+        };
+        if let Some(backing) = shared.backing.reattach(map, image, new_segments)? {
+            images[i] = MemImage::Shared(backing);
+        }
+    }
+
+    let bootstrap = Bootstrap {
+        images,
+        maps,
+        files,
+        fds,
+        mm,
+        signals,
+        attrs,
+        threads,
+        children,
+        same_pid: false,
+        channel_fd: 0,
+        index: 0,
+    };
+    let bs_path = cp_path.join(BS_GUID);
+
+    Ok(RestoreProcess {
+        seq,
+        cp_path,
+        bs_path,
+        reader,
+        bootstrap,
+        snapshots,
+        anon_files,
+    })
+}
+
+/// Puts the rest of `process`, which was `old_pid` when it was checkpointed, into its
+/// stopped bootstrapper `pid`. That is left stopped with the registers of the checkpointed
+/// process and the signals it had pending queued up again.
+fn finish_process(
+    pid: pid_t,
+    old_pid: pid_t,
+    process: &mut RestoreProcess,
+    fds: &mut FdRestorer,
+) -> Result<(), Box<dyn Error>> {
+    let Bootstrap {
+        images,
+        maps,
+        fds: fd_dumps,
+        mm,
+        signals,
+        attrs,
+        threads,
+        ..
+    } = &process.bootstrap;
+
+    // It may not have stopped yet, it only said that it's about to
+    let mut ptrace = PTrace::new(pid);
+    ptrace.seize()?;
+    ptrace.wait_group_stop()?;
+    fill_images(pid, &mut process.reader, process.seq, maps, images)?;
+
+    let user_maps: Vec<_> = maps
+        .iter()
+        .zip(images)
+        .filter(|(_, image)| !matches!(image, MemImage::Kernel))
+        .map(|(map, _)| map.clone())
+        .collect();
+    vma::check(pid, &user_maps)?;
+
+    for image in images {
+        if let MemImage::Shared(backing) = image {
+            backing.finish()?;
+        }
+    }
+
+    let kernel_maps: Vec<_> = maps
+        .iter()
+        .zip(images)
+        .filter(|(_, image)| matches!(image, MemImage::Kernel))
+        .map(|(map, _)| map.clone())
+        .collect();
+    vdso::check_moved(pid, &kernel_maps)?;
+
+    if let Some(mm) = mm {
+        let stat = Process::new(pid)?.stat()?;
+        if stat.start_brk != Some(mm.start_brk) || stat.arg_start != Some(mm.arg_start) {
+            warn!("Couldn't restore the process' heap and argument locations (PR_SET_MM_MAP needs CONFIG_CHECKPOINT_RESTORE, or the kernel rejected the layout), brk will misbehave");
+        }
+    }
+
+    let scratch = scratch_addr(&threads[0].regs, fd::SCRATCH_LEN);
+    fds.restore(
+        old_pid,
+        &ptrace,
+        scratch,
+        fd_dumps,
+        &process.anon_files,
+        maps,
+    )?;
+
+    // The other threads are stopped along with the leader, spinning until we take over
+    let mut tasks = vec![];
+    for task in Process::new(pid)?.tasks()? {
+        let mut task = PTrace::new(task?.tid);
+        if task.pid == pid {
+            continue;
+        }
+
+        task.seize()?;
+        task.wait_pause()?;
+
+        let i = task.get_regs()?.regs.r13 as usize;
+        debug!("Restoring thread {} as thread {}", threads[i].tid, task.pid);
+        task.set_regs(threads[i].restore_regs())?;
+        task.set_sigmask(threads[i].sigmask)?;
+        tasks.push((i, task));
+    }
+
+    if let Some(attrs) = attrs {
+        let sched: Vec<_> = [(0, pid)]
+            .into_iter()
+            .chain(tasks.iter().map(|(i, t)| (*i, t.pid)))
+            .map(|(i, tid)| (tid, threads[i].sched.as_ref().unwrap_or(&attrs.sched)))
+            .collect();
+        attrs.apply(pid, &sched)?;
+    }
+
+    ptrace.set_regs(threads[0].restore_regs())?;
+    ptrace.set_sigmask(threads[0].sigmask)?;
+    for (_, task) in &mut tasks {
+        task.detach()?;
+    }
+    ptrace.detach()?;
+
+    // The process stays stopped until it's resumed, and gets these then
+    signal::queue(pid, Some(pid), threads[0].pending)?;
+    for (i, task) in &tasks {
+        signal::queue(pid, Some(task.pid), threads[*i].pending)?;
+    }
+    if let Some(signals) = signals {
+        signal::queue(pid, None, signals.pending)?;
+    }
+
+    Ok(())
+}
+
+/// Restores the last checkpoint at `path`, and returns the process that exits like the restored
+/// process does, which is the restored process itself unless it's given its old pid with `same_pid`
+/// (see `pidns::spawn_with_pid`)
+pub fn restore_checkpoint(path: &PathBuf, config: &RestoreConfig) -> Result<Child, Box<dyn Error>> {
+    let RestoreConfig { hang, same_pid, .. } = *config;
+
     info!("Restoring checkpoint from {path:?}");
 
     // Read in the last checkpoint
@@ -276,47 +1550,352 @@ pub fn restore_checkpoint(path: &PathBuf, hang: bool) -> Result<Child, Box<dyn E
         return Err("No checkpoints found".into());
     }
 
-    let cp_path = path.join(step.seq.to_string());
-    info!("Reading in last checkpoint data from {cp_path:?}");
+    // Checkpoints of just the one process have no tree
+    let mut tree = TreeNode::open_all(&path.join(step.seq.to_string()))?;
+    if tree.is_empty() {
+        tree.push(TreeNode {
+            pid: 0,
+            parent: 0,
+            seq: step.seq,
+        });
+    }
 
-    let regs: Registers = serde_json::from_reader(File::open(cp_path.join("regs"))?)?;
-    let maps: Vec<MemoryMap> = serde_json::from_reader(File::open(cp_path.join("maps"))?)?;
-    let files: Vec<(FDInfo, u64)> = serde_json::from_reader(File::open(cp_path.join("files"))?)?;
+    let paths: Vec<_> = tree.iter().map(|node| node.path(path)).collect();
+    let bs_paths: Vec<_> = tree
+        .iter()
+        .zip(&paths)
+        .map(|(node, path)| path.join(node.seq.to_string()).join(BS_GUID))
+        .collect();
 
-    // Create the bootstrapper for the last checkpoint
-    info!("Creating bootstrapper binary");
-    let bs_path = cp_path.join(BS_GUID);
-    create_bootstrapper(&bs_path, &cp_path, maps, files)?;
+    let mut new_segments = NewSegments::default();
+    let mut processes = vec![];
+    for (node, path) in tree.iter().zip(&paths) {
+        let children = tree
+            .iter()
+            .zip(&bs_paths)
+            .filter(|(child, _)| child.parent != 0 && child.parent == node.pid)
+            .map(|(child, bs_path)| (bs_path.clone(), child.pid))
+            .collect();
+
+        processes.push(prepare_process(
+            path,
+            node.seq,
+            children,
+            config.changed_files,
+            &mut new_segments,
+        )?);
+    }
+
+    let old_pid = processes[0].bootstrap.threads[0].tid;
+    let mut threads = processes.iter().flat_map(|p| &p.bootstrap.threads);
+    if same_pid && threads.any(|t| t.tid == 0) {
+        return Err("the checkpoint is too old to have the pid of the process".into());
+    }
+
+    // The bootstrappers get the channel for their fds above every fd they open themselves
+    let channel_fd = processes
+        .iter()
+        .flat_map(|p| &p.bootstrap.files)
+        .map(|file| file.fd + 1)
+        .fold(3, i32::max);
+    // A checkpoint of just the one process doesn't have its pid in the tree
+    let old_pids: Vec<_> = tree
+        .iter()
+        .zip(&processes)
+        .map(|(node, p)| match node.pid {
+            0 => p.bootstrap.threads[0].tid,
+            pid => pid,
+        })
+        .collect();
+    let dumps: Vec<_> = old_pids
+        .iter()
+        .zip(&processes)
+        .map(|(pid, p)| (*pid, p.bootstrap.fds.as_slice()))
+        .collect();
+    let mut fds = FdRestorer::new(config.unsupported_fds, &dumps, channel_fd)?;
+    for process in &processes {
+        fds.add_anon_files(&process.anon_files, &process.cp_path)?;
+    }
+
+    info!("Creating bootstrapper binaries");
+    for (i, process) in processes.iter_mut().enumerate() {
+        process.bootstrap.same_pid = same_pid;
+        process.bootstrap.channel_fd = channel_fd;
+        process.bootstrap.index = i;
+        create_bootstrapper(&process.bs_path, &process.bootstrap)?;
+    }
+
+    // Before anything of the restored processes opens them, and put back as they
+    // were if the restore fails after all
+    let mut rolled_back = RolledBack::default();
+    for process in &processes {
+        FileSnapshot::roll_back_all(&process.snapshots, &process.cp_path, &mut rolled_back)?;
+    }
 
     // Run the bootstrapper
     info!("Running bootstrapper");
-    let mut bootstrap = Command::new(&bs_path)
+    let mut command = Command::new(&processes[0].bs_path);
+    command
         .stdin(Stdio::inherit())
         .stdout(Stdio::inherit())
-        .stderr(Stdio::inherit())
-        .spawn()?;
+        .stderr(Stdio::inherit());
+    fds.pass_channel(&mut command);
+
+    let mut bootstrap = match same_pid {
+        true => pidns::spawn_with_pid(command, old_pid)?,
+        false => command.spawn()?,
+    };
 
     // TODO: the process could exit here leading to
     // the following code producing an error even though
     // it just means that the restored process has completed
 
-    {
-        let mut ptrace = PTrace::new(bootstrap.id() as pid_t);
-        ptrace.wait_pause_unattached()?;
+    // Every other bootstrapper is started by its parent's, and they all say when they're done
+    let mut pids = vec![0; tree.len()];
+    for _ in 0..tree.len() {
+        let (i, pid) = fds.next_stopping()?;
+        pids[i] = pid;
+    }
+    let root = pids[0];
+
+    // Each parent comes first
+    for (i, node) in tree.iter().enumerate() {
+        if i > 0 {
+            debug!("Restoring process {} as {}", node.pid, pids[i]);
+        }
+        finish_process(pids[i], old_pids[i], &mut processes[i], &mut fds)?;
+    }
 
-        ptrace.attach()?;
-        ptrace.set_regs(regs)?;
-        ptrace.detach()?;
+    // Whatever we still have open of the restored files could keep them from seeing an EOF
+    drop(fds);
+    new_segments.keep();
+    rolled_back.keep();
 
-        if hang {
-            println!("The restored proccess's pid is: {}", ptrace.pid);
-            bootstrap.wait()?;
-        } else {
-            info!("The process is fully restored");
-            ptrace.resume()?;
+    if hang {
+        println!("The restored proccess's pid is: {root}");
+        if same_pid {
+            println!("It is {old_pid} in its own pid namespace, like its descendants are");
+        }
+        for (node, pid) in tree.iter().zip(&pids).skip(1) {
+            println!("Its descendant {} was restored as {pid}", node.pid);
+        }
+        bootstrap.wait()?;
+    } else {
+        info!("The process is fully restored");
+        for pid in pids {
+            PTrace::new(pid).resume()?;
         }
     }
 
     // The bootstrapper should now be the restored process
-    return Ok(bootstrap);
+    Ok(bootstrap)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+
+    use libc::O_RDONLY;
+    use procfs::process::MMapExtension;
+
+    use super::*;
+    use crate::{compress::Algorithm, pagemap::PAGE_SIZE};
+
+    fn anonymous_map(start: u64, pages: u64) -> MemoryMap {
+        MemoryMap {
+            address: (start, start + pages * PAGE_SIZE),
+            perms: MMPermissions::READ | MMPermissions::WRITE | MMPermissions::PRIVATE,
+            offset: 0,
+            dev: (0, 0),
+            inode: 0,
+            pathname: MMapPath::Anonymous,
+            extension: MMapExtension::default(),
+        }
+    }
+
+    fn code_len(n_maps: u64) -> usize {
+        // Every other page, so none of them get merged into one unmapped hole
+        let maps: Vec<_> = (0..n_maps)
+            .map(|i| anonymous_map(0x10000000 + 2 * i * PAGE_SIZE, 1))
+            .collect();
+        let bootstrap = Bootstrap {
+            images: vec![MemImage::Anonymous; maps.len()],
+            maps,
+            files: vec![],
+            fds: vec![],
+            mm: None,
+            signals: None,
+            attrs: None,
+            threads: vec![],
+            children: vec![],
+            same_pid: false,
+            channel_fd: 3,
+            index: 0,
+        };
+
+        let vaddr = 0xe0000;
+        let (_, program) = assemble_bs_code(
+            &bootstrap,
+            vaddr,
+            vaddr + BS_HEADERS_SIZE,
+            vaddr + 2 * PAGE_SIZE,
+        )
+        .unwrap();
+        program.len()
+    }
+
+    #[test]
+    fn call_record_layout() {
+        // walk_calls reads the flags at +56 and what to compare the kept result to at +16
+        let calls = [
+            BsCall::new(SYS_open, [1, 2, 3]).save(),
+            BsCall::new(SYS_close, [0, 5]).saved(0).unless_saved(),
+        ];
+        let mut data = vec![0; 3];
+        let (start, end) = push_table(&mut data, 0x1000, &calls);
+        assert_eq!((start, end), (0x1008, 0x1008 + 2 * 64));
+
+        let read = |offset: usize| u64::from_ne_bytes(data[offset..offset + 8].try_into().unwrap());
+        let second = 8 + 64;
+        assert_eq!(read(8), SYS_open as u64);
+        assert_eq!(read(8 + 16), 2);
+        assert_eq!(read(8 + 56), CALL_SAVE);
+        assert_eq!(read(second), SYS_close as u64);
+        assert_eq!(read(second + 16), 5);
+        assert_eq!(read(second + 56), CALL_UNLESS_SAVED | 1 << 2);
+    }
+
+    #[test]
+    fn code_length_does_not_depend_on_maps() {
+        assert_eq!(code_len(1), code_len(1000));
+    }
+
+    fn write_deltas(cp_dir: &Path, images: Vec<DeltaImage>) {
+        let deltas = Deltas { base: 1, images };
+        serde_json::to_writer(File::create(cp_dir.join("deltas")).unwrap(), &deltas).unwrap();
+    }
+
+    #[test]
+    fn delta_images_are_rebuilt_from_their_parents() {
+        let path = env::temp_dir().join(format!("deltas-test-{}", process::id()));
+        let page = PAGE_SIZE as usize;
+
+        for algorithm in [Algorithm::None, Algorithm::Lz4, Algorithm::Zstd] {
+            let compression = Compression {
+                algorithm,
+                level: 3,
+            };
+            for seq in 1..=3 {
+                fs::create_dir_all(path.join(seq.to_string())).unwrap();
+            }
+            compression.save(&path).unwrap();
+            let write = |image: &str, data: Vec<u8>| {
+                fs::write(path.join(image), compression.compress(&data).unwrap()).unwrap();
+            };
+
+            // The region is saved in full first, then only the pages written since,
+            // and it's maps[1] by the last checkpoint
+            write("1/0", vec![1; 3 * page]);
+            write("2/0", vec![2; page]);
+            write_deltas(
+                &path.join("2"),
+                vec![DeltaImage {
+                    index: 0,
+                    parent: 0,
+                    depth: 1,
+                    pages: vec![2],
+                }],
+            );
+            write("3/0", vec![4; page]);
+            write("3/1", vec![3; page]);
+            write_deltas(
+                &path.join("3"),
+                vec![DeltaImage {
+                    index: 1,
+                    parent: 0,
+                    depth: 2,
+                    pages: vec![0],
+                }],
+            );
+
+            let mut reader = ImageReader::new(&path).unwrap();
+            assert!(reader.exists(3, 0).unwrap() && !reader.exists(3, 2).unwrap());
+            assert!(reader.delta(3, 0).unwrap().is_none());
+
+            let mut expected = vec![3; page];
+            expected.extend(vec![1; page]);
+            expected.extend(vec![2; page]);
+            assert_eq!(reader.read(3, 1, 3 * PAGE_SIZE).unwrap(), expected);
+
+            fs::remove_dir_all(&path).unwrap();
+        }
+    }
+
+    #[test]
+    fn coalesce_merges_smallest_holes() {
+        let extents = vec![
+            (0, 0x1000),
+            (0x2000, 0x1000),
+            (0x10000, 0x1000),
+            (0x11800, 0x800),
+        ];
+        assert_eq!(coalesce_extents(extents.clone(), 4), extents);
+        assert_eq!(
+            coalesce_extents(extents.clone(), 3),
+            vec![(0, 0x1000), (0x2000, 0x1000), (0x10000, 0x2000)]
+        );
+        assert_eq!(
+            coalesce_extents(extents.clone(), 2),
+            vec![(0, 0x3000), (0x10000, 0x2000)]
+        );
+        assert_eq!(coalesce_extents(extents, 1), vec![(0, 0x12000)]);
+    }
+
+    #[test]
+    fn check_files_goes_by_the_policy() {
+        let dir = env::temp_dir().join(format!("files-test-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("file");
+        fs::write(&path, b"hello world").unwrap();
+
+        let files = [OpenFile {
+            fd: 3,
+            target: FDTarget::Path(path.clone()),
+            offset: 6,
+            flags: O_RDONLY,
+            mnt_id: None,
+            identity: Some(FileIdentity::from(&metadata(&path).unwrap())),
+        }];
+        let policies = [FilePolicy::Fail, FilePolicy::Warn, FilePolicy::Reopen];
+        let results = |files: &[OpenFile]| policies.map(|p| check_files(files, &[], p).is_ok());
+
+        // Writing more to it is fine
+        fs::write(&path, b"hello world, again").unwrap();
+        assert_eq!(results(&files), [true; 3]);
+
+        fs::write(&path, b"hello").unwrap();
+        assert_eq!(results(&files), [false, true, true]);
+
+        fs::write(dir.join("new"), b"hello world").unwrap();
+        fs::rename(dir.join("new"), &path).unwrap();
+        assert_eq!(results(&files), [false, true, true]);
+
+        // Older checkpoints have nothing to check
+        let mut legacy = files.clone();
+        legacy[0].identity = None;
+        fs::remove_file(&path).unwrap();
+        assert_eq!(results(&legacy), [true; 3]);
+        assert_eq!(results(&files), [false; 3]);
+
+        // Unless it's going to be rolled back
+        let snapshot = FileSnapshot {
+            path: path.clone(),
+            identity: files[0].identity.clone().unwrap(),
+            compression: Default::default(),
+            contents: vec![],
+        };
+        assert!(check_files(&files, &[snapshot], FilePolicy::Fail).is_ok());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
 }
@@ -0,0 +1,378 @@
+use std::{
+    error::Error,
+    fs::{self, File},
+    io::{self, ErrorKind},
+    mem::MaybeUninit,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    ptr,
+};
+
+use libc::{
+    c_int, pid_t, shmid_ds, IPC_CREAT, IPC_EXCL, IPC_PRIVATE, IPC_RMID, IPC_STAT, MAP_FAILED,
+    SHM_EXEC, SHM_RDONLY,
+};
+use log::{debug, info, warn};
+use procfs::process::{MMPermissions, MMapPath, MemoryMap, Process};
+use serde::{Deserialize, Serialize};
+
+use crate::{anon::is_deleted, checkpoint::FileIdentity, pagemap::PAGE_SIZE};
+
+/// Set in the mode of a System V segment that was removed but is still attached somewhere
+const SHM_DEST: u16 = 0o1000;
+
+/// What's behind a `MAP_SHARED` mapping, other than an `AnonFile`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Backing {
+    /// A file that can be opened by its path, like the objects in /dev/shm
+    File {
+        path: PathBuf,
+        identity: FileIdentity,
+    },
+    /// A System V shared memory segment
+    SysV {
+        key: i32,
+        shmid: i32,
+        size: u64,
+        mode: u16,
+        /// Whether it was removed already, and only lived on while it was attached
+        removed: bool,
+        /// The process that made it and when it last changed, which is what tells it
+        /// apart from a later segment that got the same shmid. Older checkpoints don't have them.
+        #[serde(default)]
+        cpid: pid_t,
+        #[serde(default)]
+        ctime: i64,
+    },
+}
+
+/// A shared mapping of the process, which goes back to sharing its memory with whatever
+/// else it did instead of getting a private copy on restore
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SharedMap {
+    /// The start address of the mapping
+    pub addr: u64,
+    pub backing: Backing,
+}
+
+impl SharedMap {
+    /// Works out what's behind each shared mapping in `maps` of the stopped process `procfs`.
+    /// The memfds, deleted files and shared anonymous memory are left to `AnonFile`.
+    pub fn capture_all(procfs: &Process, maps: &[MemoryMap]) -> Result<Vec<Self>, Box<dyn Error>> {
+        let mut shared = vec![];
+        for map in maps {
+            if !map.perms.contains(MMPermissions::SHARED) {
+                continue;
+            }
+
+            let (start, end) = map.address;
+            let backing = match &map.pathname {
+                MMapPath::Path(path) if !is_deleted(path) => {
+                    let Some(identity) = FileIdentity::of_map(procfs.pid, map, path)? else {
+                        warn!("{path:?} was replaced while it was mapped at {start:#x}, that mapping will be restored private");
+                        continue;
+                    };
+
+                    Backing::File {
+                        path: path.clone(),
+                        identity,
+                    }
+                }
+                MMapPath::Vsys(key) => {
+                    // /proc has the shmid where the inode goes
+                    let shmid = map.inode as i32;
+                    let (size, mode, cpid, ctime) = match shm_stat(shmid) {
+                        Ok(stat) => (
+                            stat.shm_segsz as u64,
+                            stat.shm_perm.mode,
+                            stat.shm_cpid,
+                            stat.shm_ctime,
+                        ),
+                        Err(e) => {
+                            debug!("Can't stat System V segment {shmid}: {e}");
+                            (end - start, 0o600, 0, 0)
+                        }
+                    };
+
+                    Backing::SysV {
+                        key: *key,
+                        shmid,
+                        size,
+                        mode: mode & 0o777,
+                        removed: mode & SHM_DEST != 0,
+                        cpid,
+                        ctime,
+                    }
+                }
+                _ => continue,
+            };
+
+            debug!("Memory at {start:#x} is shared with {backing:?}");
+            shared.push(Self {
+                addr: start,
+                backing,
+            });
+        }
+
+        Ok(shared)
+    }
+
+    /// Writes `shared` to the checkpoint in `cp_dir`
+    pub fn persist_all(shared: &[Self], cp_dir: &Path) -> Result<(), Box<dyn Error>> {
+        serde_json::to_writer(File::create(cp_dir.join("shared"))?, shared)?;
+        Ok(())
+    }
+
+    /// Reads the shared mappings of the checkpoint in `cp_dir`,
+    /// which older checkpoints and ones that didn't have any don't have
+    pub fn open_all(cp_dir: &Path) -> Result<Vec<Self>, Box<dyn Error>> {
+        match File::open(cp_dir.join("shared")) {
+            Ok(file) => Ok(serde_json::from_reader(file)?),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(vec![]),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+impl Backing {
+    /// Finds what to map `map` from again: the same file or segment if it's still there,
+    /// or a new segment with the contents from `image` if the segment is gone, which goes
+    /// in `new` until the restore is done. `None` means that it has to be restored
+    /// private, from its image.
+    pub fn reattach(
+        &self,
+        map: &MemoryMap,
+        image: impl FnOnce() -> Result<Vec<u8>, Box<dyn Error>>,
+        new: &mut NewSegments,
+    ) -> Result<Option<Self>, Box<dyn Error>> {
+        let (start, end) = map.address;
+        match self {
+            Backing::File { path, identity } => match fs::metadata(path) {
+                Ok(meta) if (meta.dev(), meta.ino()) == (identity.dev, identity.inode) => {
+                    Ok(Some(self.clone()))
+                }
+                Ok(_) => {
+                    warn!("{path:?} was replaced since the checkpoint, mapping it at {start:#x} private");
+                    Ok(None)
+                }
+                Err(e) if e.kind() == ErrorKind::NotFound => {
+                    warn!("{path:?} is gone, mapping it at {start:#x} private");
+                    Ok(None)
+                }
+                Err(e) => Err(e.into()),
+            },
+            &Backing::SysV {
+                key,
+                shmid,
+                size,
+                mode,
+                removed,
+                cpid,
+                ctime,
+            } => {
+                // shmat can only map the whole segment
+                if map.offset != 0 || end - start != size.next_multiple_of(PAGE_SIZE) {
+                    warn!("Only part of System V segment {shmid} was mapped at {start:#x}, mapping it private");
+                    return Ok(None);
+                }
+
+                // Other processes find it by its key, if it has one, so it's whatever
+                // has that key now. Private ones (and removed ones) only have the shmid,
+                // which could belong to another segment by now, unless it was made by
+                // the same process at the same time.
+                let existing = match key {
+                    IPC_PRIVATE if cpid == 0 => -1,
+                    IPC_PRIVATE => shmid,
+                    key => unsafe { libc::shmget(key, 0, 0) },
+                };
+                if existing >= 0
+                    && shm_stat(existing).is_ok_and(|stat| {
+                        stat.shm_perm.__key == key
+                            && stat.shm_segsz as u64 == size
+                            && (key != IPC_PRIVATE
+                                || (stat.shm_cpid, stat.shm_ctime) == (cpid, ctime))
+                    })
+                {
+                    return Ok(Some(Backing::SysV {
+                        key,
+                        shmid: existing,
+                        size,
+                        mode,
+                        removed,
+                        cpid,
+                        ctime,
+                    }));
+                }
+
+                // Removed ones are IPC_PRIVATE by then, so they stay that way
+                let new_id = unsafe {
+                    libc::shmget(key, size as usize, IPC_CREAT | IPC_EXCL | mode as c_int)
+                };
+                if new_id < 0 {
+                    let e = io::Error::last_os_error();
+                    warn!("Can't make System V segment {key:#x} again ({e}), mapping it at {start:#x} private");
+                    return Ok(None);
+                }
+                info!("Made System V segment {shmid} again as {new_id}");
+                new.0.push(new_id);
+
+                let image = image()?;
+                let addr = unsafe { libc::shmat(new_id, ptr::null(), 0) };
+                if addr == MAP_FAILED {
+                    return Err(io::Error::last_os_error().into());
+                }
+                let len = image.len().min(size as usize);
+                unsafe {
+                    ptr::copy_nonoverlapping(image.as_ptr(), addr.cast(), len);
+                    libc::shmdt(addr);
+                }
+
+                Ok(Some(Backing::SysV {
+                    key,
+                    shmid: new_id,
+                    size,
+                    mode,
+                    removed,
+                    cpid,
+                    ctime,
+                }))
+            }
+        }
+    }
+
+    /// The flags for shmat to map a segment with `perms`
+    pub fn shmat_flags(perms: MMPermissions) -> c_int {
+        let mut flags = 0;
+        if !perms.contains(MMPermissions::WRITE) {
+            flags |= SHM_RDONLY;
+        }
+        if perms.contains(MMPermissions::EXECUTE) {
+            flags |= SHM_EXEC;
+        }
+        flags
+    }
+
+    /// Takes away the System V segment again if it was removed at checkpoint time,
+    /// which can only happen once it's attached
+    pub fn finish(&self) -> Result<(), Box<dyn Error>> {
+        if let &Backing::SysV {
+            shmid,
+            removed: true,
+            ..
+        } = self
+        {
+            if unsafe { libc::shmctl(shmid, IPC_RMID, ptr::null_mut()) } < 0 {
+                return Err(io::Error::last_os_error().into());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The System V segments that a restore made again, which are removed again
+/// when this is dropped, unless the restore got through and `keep`s them
+#[derive(Debug, Default)]
+pub struct NewSegments(pub Vec<i32>);
+
+impl NewSegments {
+    pub fn keep(mut self) {
+        self.0.clear();
+    }
+}
+
+impl Drop for NewSegments {
+    fn drop(&mut self) {
+        for &shmid in &self.0 {
+            debug!("Removing System V segment {shmid}, the restore failed");
+            unsafe { libc::shmctl(shmid, IPC_RMID, ptr::null_mut()) };
+        }
+    }
+}
+
+fn shm_stat(shmid: i32) -> io::Result<shmid_ds> {
+    let mut stat = MaybeUninit::<shmid_ds>::uninit();
+    match unsafe { libc::shmctl(shmid, IPC_STAT, stat.as_mut_ptr()) } {
+        0 => Ok(unsafe { stat.assume_init() }),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs::OpenOptions, os::fd::AsRawFd, process};
+
+    use libc::{MAP_SHARED, PROT_READ, PROT_WRITE};
+
+    use super::*;
+
+    #[test]
+    fn shared_maps_are_attached_again() {
+        let path = env::temp_dir().join(format!("shared-test-{}", process::id()));
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        file.set_len(PAGE_SIZE).unwrap();
+        let len = PAGE_SIZE as usize;
+        let prot = PROT_READ | PROT_WRITE;
+        let file_addr =
+            unsafe { libc::mmap(ptr::null_mut(), len, prot, MAP_SHARED, file.as_raw_fd(), 0) };
+        assert_ne!(file_addr, MAP_FAILED);
+
+        let shmid = unsafe { libc::shmget(IPC_PRIVATE, len, IPC_CREAT | 0o600) };
+        assert!(shmid >= 0);
+        let shm_addr = unsafe { libc::shmat(shmid, ptr::null(), 0) };
+        assert_ne!(shm_addr, MAP_FAILED);
+
+        let procfs = Process::myself().unwrap();
+        let maps = procfs.maps().unwrap().0;
+        let shared = SharedMap::capture_all(&procfs, &maps).unwrap();
+        let find = |addr: *mut libc::c_void| {
+            let shared = shared.iter().find(|s| s.addr == addr as u64).unwrap();
+            let map = maps.iter().find(|m| m.address.0 == addr as u64).unwrap();
+            (shared.backing.clone(), map.clone())
+        };
+
+        let (backing, map) = find(file_addr);
+        assert!(matches!(&backing, Backing::File { path: p, .. } if *p == path));
+        let image = || -> Result<Vec<u8>, Box<dyn Error>> { unreachable!() };
+        let mut new = NewSegments::default();
+        assert!(backing.reattach(&map, image, &mut new).unwrap().is_some());
+        fs::remove_file(&path).unwrap();
+        assert!(backing.reattach(&map, image, &mut new).unwrap().is_none());
+
+        // A segment that's still there is used as is, and one that's gone is made again
+        let (backing, map) = find(shm_addr);
+        assert!(matches!(backing, Backing::SysV { shmid: id, removed: false, .. } if id == shmid));
+        let again = backing.reattach(&map, image, &mut new).unwrap().unwrap();
+        assert!(matches!(again, Backing::SysV { shmid: id, .. } if id == shmid));
+
+        unsafe {
+            libc::shmctl(shmid, IPC_RMID, ptr::null_mut());
+            libc::shmdt(shm_addr);
+        }
+        let again = backing
+            .reattach(&map, || Ok(b"contents".to_vec()), &mut new)
+            .unwrap()
+            .unwrap();
+        let Backing::SysV { shmid: new_id, .. } = again else {
+            panic!("{again:?} isn't a System V segment");
+        };
+        assert_ne!(new_id, shmid);
+        assert_eq!(new.0, [new_id]);
+        let addr = unsafe { libc::shmat(new_id, ptr::null(), SHM_RDONLY) };
+        let contents = unsafe { std::slice::from_raw_parts(addr as *const u8, 8) };
+        assert_eq!(contents, b"contents");
+        unsafe {
+            libc::shmdt(addr);
+            libc::munmap(file_addr, len);
+        }
+
+        // Which goes away again unless the restore keeps it
+        drop(new);
+        assert!(shm_stat(new_id).is_err());
+    }
+}
@@ -0,0 +1,310 @@
+use std::{
+    error::Error,
+    fs::File,
+    io::{self, ErrorKind},
+    path::Path,
+};
+
+use libc::{
+    c_int, pid_t, SYS_rt_sigaction, SYS_sigaltstack, SYS_tgkill, SIGCHLD, SIGKILL, SIGSTOP,
+    SS_DISABLE, SS_ONSTACK,
+};
+use log::{debug, warn};
+use procfs::process::Process;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    memory::MemReader,
+    ptrace::{PTrace, Registers},
+    thread::scratch_addr,
+};
+
+/// The size of the kernel's `struct sigaction`, which is what `rt_sigaction` takes
+pub const SIGACTION_SIZE: usize = 32;
+
+/// The size of `stack_t`
+pub const STACK_SIZE: usize = 24;
+
+/// The size of a signal set as far as the kernel is concerned
+pub const SIGSET_SIZE: u64 = 8;
+
+/// The signals that can't be caught, blocked or left pending in any way that matters
+const UNQUEUEABLE: u64 = bit(SIGKILL) | bit(SIGSTOP);
+
+/// The bit for `signal` in a signal set
+pub const fn bit(signal: c_int) -> u64 {
+    1 << (signal - 1)
+}
+
+/// The signal state shared by every thread of a process.
+///
+/// The per thread parts (the blocked and pending signals, and the alternate
+/// signal stack) are saved with each `Thread`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Signals {
+    /// The signals that aren't left at their default action
+    pub actions: Vec<SigAction>,
+    /// The signals pending for the whole process
+    pub pending: u64,
+}
+
+/// What a process does with one signal, as `rt_sigaction` has it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SigAction {
+    pub signal: c_int,
+    /// The address of the handler, or `SIG_IGN`/`SIG_DFL`
+    pub handler: u64,
+    pub flags: u64,
+    /// Where the handler returns to, which `rt_sigreturn`s (libc sets this with `SA_RESTORER`)
+    pub restorer: u64,
+    /// The signals blocked while the handler runs
+    pub mask: u64,
+}
+
+/// An alternate signal stack, as `sigaltstack` has it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AltStack {
+    pub sp: u64,
+    pub flags: c_int,
+    pub size: u64,
+}
+
+impl Signals {
+    /// Reads the signal state of the stopped process `procfs`, through its attached leader `ptrace`.
+    ///
+    /// `/proc` only says which signals are caught or ignored, so the handlers are read by
+    /// making the process call `rt_sigaction` with our syscall `gadget`, onto its stack like
+    /// `Thread::capture` does. `SIGCHLD` is always read since `SA_NOCLDWAIT` and friends
+    /// matter even when it's left at its default action.
+    pub fn capture(
+        procfs: &Process,
+        ptrace: &PTrace,
+        mem: &MemReader,
+        gadget: Option<u64>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let status = procfs.status()?;
+        let pending = status.shdpnd & !UNQUEUEABLE;
+
+        let Some(gadget) = gadget else {
+            if status.sigcgt != 0 {
+                warn!("Can't read the signal handlers of the process without a syscall gadget, they won't be restored");
+            }
+
+            let actions = signals(status.sigign)
+                .map(|signal| SigAction {
+                    signal,
+                    handler: libc::SIG_IGN as u64,
+                    flags: 0,
+                    restorer: 0,
+                    mask: 0,
+                })
+                .collect();
+            return Ok(Self { actions, pending });
+        };
+
+        let regs = ptrace.get_regs()?;
+        let scratch = scratch_addr(&regs, SIGACTION_SIZE as u64);
+
+        let mut actions = vec![];
+        for signal in signals(status.sigcgt | status.sigign | bit(SIGCHLD)) {
+            let res = ptrace.syscall(
+                gadget,
+                SYS_rt_sigaction,
+                &[signal as u64, 0, scratch, SIGSET_SIZE],
+            )?;
+            if res < 0 {
+                return Err(format!(
+                    "couldn't read the action for signal {signal}: {}",
+                    io::Error::from_raw_os_error(-res as i32)
+                )
+                .into());
+            }
+
+            let raw = read_scratch(mem, scratch, SIGACTION_SIZE)?;
+            let action = SigAction {
+                signal,
+                handler: word(&raw, 0),
+                flags: word(&raw, 1),
+                restorer: word(&raw, 2),
+                mask: word(&raw, 3),
+            };
+
+            if action.handler != libc::SIG_DFL as u64 || action.flags != 0 {
+                debug!("Signal {signal} has {action:x?}");
+                actions.push(action);
+            }
+        }
+
+        Ok(Self { actions, pending })
+    }
+
+    /// Reads the signal state of the checkpoint in `cp_dir`,
+    /// which older checkpoints don't have
+    pub fn open(cp_dir: &Path) -> Result<Option<Self>, Box<dyn Error>> {
+        match File::open(cp_dir.join("signals")) {
+            Ok(file) => Ok(Some(serde_json::from_reader(file)?)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+impl SigAction {
+    /// The `struct sigaction` to pass to `rt_sigaction`
+    pub fn bytes(&self) -> Vec<u8> {
+        [self.handler, self.flags, self.restorer, self.mask]
+            .iter()
+            .flat_map(|field| field.to_ne_bytes())
+            .collect()
+    }
+}
+
+impl AltStack {
+    /// Reads the alternate signal stack of the stopped thread `ptrace` with our syscall `gadget`
+    pub fn capture(
+        ptrace: &PTrace,
+        mem: &MemReader,
+        regs: &Registers,
+        gadget: u64,
+    ) -> Result<Option<Self>, Box<dyn Error>> {
+        let scratch = scratch_addr(regs, STACK_SIZE as u64);
+        let res = ptrace.syscall(gadget, SYS_sigaltstack, &[0, scratch])?;
+        if res < 0 {
+            return Err(format!(
+                "couldn't read the alternate signal stack of thread {}: {}",
+                ptrace.pid,
+                io::Error::from_raw_os_error(-res as i32)
+            )
+            .into());
+        }
+
+        let raw = read_scratch(mem, scratch, STACK_SIZE)?;
+        let stack = Self {
+            sp: word(&raw, 0),
+            flags: c_int::from_ne_bytes(raw[8..12].try_into().unwrap()),
+            size: word(&raw, 2),
+        };
+
+        Ok((stack.flags & SS_DISABLE == 0).then_some(stack))
+    }
+
+    /// The `stack_t` to pass to `sigaltstack`.
+    ///
+    /// `SS_ONSTACK` is only ever reported, whether the thread is on the
+    /// stack comes from its stack pointer once it's running again.
+    pub fn bytes(&self) -> Vec<u8> {
+        let mut stack = vec![];
+        stack.extend(self.sp.to_ne_bytes());
+        stack.extend((self.flags & !SS_ONSTACK).to_ne_bytes());
+        stack.extend([0; 4]);
+        stack.extend(self.size.to_ne_bytes());
+        stack
+    }
+}
+
+/// The signals that are pending for the task `tid` by itself, out of `/proc`
+pub fn thread_pending(procfs: &Process, tid: pid_t) -> Result<u64, Box<dyn Error>> {
+    Ok(procfs.task_from_tid(tid)?.status()?.sigpnd & !UNQUEUEABLE)
+}
+
+/// Raises the signals in `pending` again for the thread `tid` of the process `pid`,
+/// or the whole process if `tid` is `None`.
+///
+/// All that `/proc` tells us is which signals were pending, so each one is queued
+/// just once, without whatever `siginfo` it was sent with.
+pub fn queue(pid: pid_t, tid: Option<pid_t>, pending: u64) -> io::Result<()> {
+    for signal in signals(pending) {
+        debug!("Queueing signal {signal} for {pid}/{tid:?}");
+
+        let res = match tid {
+            Some(tid) => unsafe { libc::syscall(SYS_tgkill, pid, tid, signal) },
+            None => unsafe { libc::kill(pid, signal) as i64 },
+        };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    Ok(())
+}
+
+/// The signals in the signal set `set`
+fn signals(set: u64) -> impl Iterator<Item = c_int> {
+    (1..=64)
+        .filter(move |&signal| set & bit(signal) != 0)
+        .filter(|&signal| bit(signal) & UNQUEUEABLE == 0)
+}
+
+fn read_scratch(mem: &MemReader, addr: u64, len: usize) -> io::Result<Vec<u8>> {
+    mem.read_batch(&[vec![(addr, len)]]).pop().unwrap()
+}
+
+/// The `i`th u64 of `raw`
+fn word(raw: &[u8], i: usize) -> u64 {
+    u64::from_ne_bytes(raw[i * 8..i * 8 + 8].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{process::Command, thread::sleep, time::Duration};
+
+    use libc::{SIGUSR1, SIGUSR2};
+
+    use super::*;
+    use crate::{thread::attach_all, thread::detach_all, vdso};
+
+    #[test]
+    fn capture_reads_handlers_and_pending_signals() {
+        let mut tracee = Command::new("sh")
+            .args(["-c", "trap : USR1; trap '' USR2; while :; do sleep 1; done"])
+            .spawn()
+            .unwrap();
+        let procfs = Process::new(tracee.id() as pid_t).unwrap();
+        while procfs.status().unwrap().sigcgt & bit(SIGUSR1) == 0 {
+            sleep(Duration::from_millis(10));
+        }
+
+        let tasks = attach_all(&procfs).unwrap();
+        let mem = MemReader::new(&procfs).unwrap();
+        let gadget = vdso::syscall_gadget(&mem, &procfs.maps().unwrap().0).unwrap();
+
+        // A stopped process keeps what it's sent pending
+        queue(procfs.pid, None, bit(SIGUSR1) | bit(SIGKILL)).unwrap();
+        let signals = Signals::capture(&procfs, &tasks[0], &mem, Some(gadget)).unwrap();
+        assert_eq!(signals.pending, bit(SIGUSR1));
+
+        let action = |signal| signals.actions.iter().find(|a| a.signal == signal);
+        let usr1 = action(SIGUSR1).unwrap();
+        assert!(![libc::SIG_DFL, libc::SIG_IGN].contains(&(usr1.handler as usize)));
+        assert_eq!(usr1.bytes().len(), SIGACTION_SIZE);
+        assert_eq!(action(SIGUSR2).unwrap().handler, libc::SIG_IGN as u64);
+
+        // Without a gadget all we know is what's ignored
+        let signals = Signals::capture(&procfs, &tasks[0], &mem, None).unwrap();
+        let action = |signal| signals.actions.iter().find(|a| a.signal == signal);
+        assert!(action(SIGUSR2).is_some() && action(SIGUSR1).is_none());
+        assert!(signals
+            .actions
+            .iter()
+            .all(|a| a.handler == libc::SIG_IGN as u64));
+
+        detach_all(tasks).unwrap();
+        tracee.kill().unwrap();
+        tracee.wait().unwrap();
+    }
+
+    #[test]
+    fn stack_t_layout() {
+        let stack = AltStack {
+            sp: 0x1000,
+            flags: SS_ONSTACK,
+            size: 0x2000,
+        };
+        let bytes = stack.bytes();
+        assert_eq!(bytes.len(), STACK_SIZE);
+        assert_eq!(
+            (word(&bytes, 0), word(&bytes, 1), word(&bytes, 2)),
+            (0x1000, 0, 0x2000)
+        );
+    }
+}
@@ -0,0 +1,239 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    fs::{self, create_dir_all, read_dir},
+    io::{self, ErrorKind},
+    path::{Path, PathBuf},
+};
+
+use log::debug;
+
+use crate::{checkpoint::ChunkedImage, compress::Compression, pagemap::PAGE_SIZE};
+
+/// The directory under the checkpoint path that holds the chunks
+pub const STORE_DIR: &str = "store";
+
+/// Memory images are split into chunks of this size (the last one may be shorter)
+pub const CHUNK_SIZE: u64 = 16 * PAGE_SIZE;
+
+/// A content-addressed store of memory image chunks, shared by every checkpoint.
+///
+/// Each chunk is a file named after the hash of its contents, so identical data
+/// is only ever stored once. We keep count of how many images reference each chunk,
+/// and a chunk is deleted once the last checkpoint referencing it is.
+pub struct ChunkStore {
+    pub path: PathBuf,
+    pub refs: HashMap<String, u64>,
+    /// How chunks are compressed, which is the same as the images of the checkpoint directory
+    pub compression: Compression,
+    /// How many bytes of new chunks were stored, before and after compression
+    pub bytes_written: (u64, u64),
+}
+
+impl ChunkStore {
+    /// Opens the store of the checkpoint directory `cpath`, counting the references
+    /// from every checkpoint in it and removing any chunk that isn't referenced
+    /// (which can be left behind if we died in the middle of a checkpoint)
+    pub fn open(cpath: &Path, compression: Compression) -> Result<Self, Box<dyn Error>> {
+        let path = cpath.join(STORE_DIR);
+        create_dir_all(&path)?;
+
+        let mut refs = HashMap::new();
+        for entry in read_dir(cpath)? {
+            let entry = entry?;
+            let is_cp = entry
+                .file_name()
+                .to_str()
+                .is_some_and(|name| name.parse::<u64>().is_ok());
+            if !is_cp {
+                continue;
+            }
+
+            for image in ChunkedImage::open_all(&entry.path())? {
+                for chunk in image.chunks.into_iter().flatten() {
+                    *refs.entry(chunk).or_insert(0) += 1;
+                }
+            }
+        }
+
+        for entry in read_dir(&path)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            if !name.to_str().is_some_and(|name| refs.contains_key(name)) {
+                debug!("Removing unreferenced chunk {name:?}");
+                fs::remove_file(entry.path())?;
+            }
+        }
+
+        Ok(Self {
+            path,
+            refs,
+            compression,
+            bytes_written: (0, 0),
+        })
+    }
+
+    /// Stores `data` if it isn't already, returning its hash
+    pub fn put(&mut self, data: &[u8]) -> io::Result<String> {
+        let hash = blake3::hash(data).to_hex().to_string();
+
+        match self.refs.get_mut(&hash) {
+            Some(count) => *count += 1,
+            None => {
+                let compressed = self.compression.compress(data)?;
+                self.bytes_written.0 += data.len() as u64;
+                self.bytes_written.1 += compressed.len() as u64;
+
+                // syncing: this should fsync before it returns
+                fs::write(self.path.join(&hash), compressed)?;
+                self.refs.insert(hash.clone(), 1);
+            }
+        }
+
+        Ok(hash)
+    }
+
+    /// Splits the image of a `len` byte memory region into chunks and stores them.
+    /// `data` holds the `extents` of the region concatenated, the rest is zero.
+    ///
+    /// Chunks of nothing but zeros aren't stored, and are `None` in the returned list.
+    pub fn put_image(
+        &mut self,
+        len: u64,
+        data: &[u8],
+        extents: &[(u64, u64)],
+    ) -> io::Result<Vec<Option<String>>> {
+        let n_chunks = len.div_ceil(CHUNK_SIZE) as usize;
+        let mut bufs: Vec<Option<Vec<u8>>> = vec![None; n_chunks];
+
+        let mut pos = 0;
+        for &(offset, ext_len) in extents {
+            let mut done = 0;
+            while done < ext_len {
+                let addr = offset + done;
+                let chunk = (addr / CHUNK_SIZE) as usize;
+                let chunk_start = chunk as u64 * CHUNK_SIZE;
+                let chunk_len = CHUNK_SIZE.min(len - chunk_start);
+                let n = (chunk_start + chunk_len - addr).min(ext_len - done);
+
+                let buf = bufs[chunk].get_or_insert_with(|| vec![0; chunk_len as usize]);
+                let start = (addr - chunk_start) as usize;
+                buf[start..start + n as usize]
+                    .copy_from_slice(&data[pos + done as usize..pos + (done + n) as usize]);
+
+                done += n;
+            }
+
+            pos += ext_len as usize;
+        }
+
+        let mut chunks = vec![];
+        for buf in bufs {
+            chunks.push(match buf {
+                Some(buf) if buf.iter().any(|b| *b != 0) => Some(self.put(&buf)?),
+                _ => None,
+            });
+        }
+
+        Ok(chunks)
+    }
+
+    /// Adds a reference to each of `chunks`, which are already in the store
+    pub fn add_refs(&mut self, chunks: &[Option<String>]) {
+        for chunk in chunks.iter().flatten() {
+            *self.refs.entry(chunk.clone()).or_insert(0) += 1;
+        }
+    }
+
+    /// Drops a reference to each of `chunks`, deleting the ones nothing references anymore
+    pub fn release(&mut self, chunks: &[Option<String>]) -> io::Result<()> {
+        for chunk in chunks.iter().flatten() {
+            let Some(count) = self.refs.get_mut(chunk) else {
+                continue;
+            };
+
+            *count -= 1;
+            if *count == 0 {
+                self.refs.remove(chunk);
+                match fs::remove_file(self.path.join(chunk)) {
+                    Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+                    _ => {}
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Reads the full contents of a chunked image out of the store in `store`
+pub fn read_image(
+    store: &Path,
+    image: &ChunkedImage,
+    compression: &Compression,
+) -> io::Result<Vec<u8>> {
+    let mut mem = vec![0; image.len as usize];
+    for (i, chunk) in image.chunks.iter().enumerate() {
+        if let Some(chunk) = chunk {
+            let data = compression.decompress(fs::read(store.join(chunk))?)?;
+            let start = i * CHUNK_SIZE as usize;
+            mem[start..start + data.len()].copy_from_slice(&data);
+        }
+    }
+
+    Ok(mem)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+
+    use super::*;
+    use crate::compress::Algorithm;
+
+    #[test]
+    fn put_image_chunks_extents() {
+        let cpath = env::temp_dir().join(format!("store-test-{}", process::id()));
+        let compression = Compression {
+            algorithm: Algorithm::Zstd,
+            level: 3,
+        };
+        let mut store = ChunkStore::open(&cpath, compression).unwrap();
+
+        // Three chunks and a page, with an extent across the first two,
+        // nothing in the third and one at the start of the short last one
+        let chunk = CHUNK_SIZE as usize;
+        let len = 3 * CHUNK_SIZE + PAGE_SIZE;
+        let extents = [(CHUNK_SIZE - 100, 200), (3 * CHUNK_SIZE, 100)];
+        let data = vec![7u8; 300];
+
+        let chunks = store.put_image(len, &data, &extents).unwrap();
+        assert_eq!(chunks.len(), 4);
+        assert!(chunks[0].is_some() && chunks[1].is_some() && chunks[3].is_some());
+        assert_eq!(chunks[2], None);
+
+        let image = ChunkedImage {
+            index: 0,
+            len,
+            chunks: chunks.clone(),
+        };
+        let mut expected = vec![0u8; len as usize];
+        expected[chunk - 100..chunk + 100].fill(7);
+        expected[3 * chunk..3 * chunk + 100].fill(7);
+        assert_eq!(
+            read_image(&store.path, &image, &compression).unwrap(),
+            expected
+        );
+
+        // Storing it again only adds references, and the chunks go with the last one
+        assert_eq!(store.put_image(len, &data, &extents).unwrap(), chunks);
+        let first = store.path.join(chunks[0].as_ref().unwrap());
+        assert_eq!(store.refs[chunks[0].as_ref().unwrap()], 2);
+        store.release(&chunks).unwrap();
+        assert!(first.exists());
+        store.release(&chunks).unwrap();
+        assert!(!first.exists() && store.refs.is_empty());
+
+        fs::remove_dir_all(&cpath).unwrap();
+    }
+}
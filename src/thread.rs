@@ -0,0 +1,371 @@
+use std::{
+    error::Error,
+    fs::File,
+    io::{self, ErrorKind},
+    path::Path,
+};
+
+use libc::{pid_t, SYS_prctl, ESRCH, PR_GET_TID_ADDRESS};
+use log::debug;
+use procfs::process::Process;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    attrs::SchedAttrs,
+    memory::MemReader,
+    ptrace::{PTrace, Registers},
+    signal::{self, AltStack},
+};
+
+/// The state of one thread of a process
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Thread {
+    pub tid: pid_t,
+    /// The general purpose registers include `fs_base`, which is the thread's TLS pointer
+    pub regs: Registers,
+    /// The signals the thread has blocked
+    pub sigmask: u64,
+    /// The signals pending for just this thread
+    #[serde(default)]
+    pub pending: u64,
+    /// The thread's alternate signal stack, if it has one
+    #[serde(default)]
+    pub altstack: Option<AltStack>,
+    /// The address the kernel zeroes (and wakes futex waiters on) when the thread
+    /// exits, which is how `pthread_join` waits for it. Zero if there is none.
+    pub clear_child_tid: u64,
+    /// The syscall the thread was stopped in the middle of, if any
+    #[serde(default)]
+    pub syscall: Option<InterruptedSyscall>,
+    /// Older checkpoints only have the leader's, in `ProcAttrs`
+    #[serde(default)]
+    pub sched: Option<SchedAttrs>,
+}
+
+/// A blocking syscall that was interrupted by us stopping the thread,
+/// which the kernel would have restarted once the thread went on
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InterruptedSyscall {
+    pub nr: u64,
+    pub restart: Restart,
+}
+
+/// How the kernel meant to restart an interrupted syscall, from the error it left in `rax`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Restart {
+    /// `ERESTARTSYS`, restarted unless a signal handler without `SA_RESTART` runs
+    Sys,
+    /// `ERESTARTNOINTR`, always restarted
+    NoIntr,
+    /// `ERESTARTNOHAND`, restarted unless a signal handler runs
+    NoHand,
+    /// `ERESTART_RESTARTBLOCK`, continued through `restart_syscall` with state the
+    /// kernel kept for it (like how much longer to sleep), which doesn't survive a restore
+    RestartBlock,
+}
+
+impl Restart {
+    /// The kernel's internal error numbers for these, see `linux/errno.h`
+    fn from_errno(errno: i64) -> Option<Self> {
+        match errno {
+            512 => Some(Self::Sys),
+            513 => Some(Self::NoIntr),
+            514 => Some(Self::NoHand),
+            516 => Some(Self::RestartBlock),
+            _ => None,
+        }
+    }
+}
+
+impl InterruptedSyscall {
+    /// Works out from the registers of a stopped thread whether it's in the middle of a syscall
+    pub fn detect(regs: &Registers) -> Option<Self> {
+        let nr = regs.regs.orig_rax as i64;
+        let ret = regs.regs.rax as i64;
+        if nr < 0 {
+            return None;
+        }
+
+        Restart::from_errno(-ret).map(|restart| Self {
+            nr: nr as u64,
+            restart,
+        })
+    }
+}
+
+impl Thread {
+    /// Reads the state of the stopped, attached thread `ptrace` of the process `procfs`.
+    ///
+    /// There's no file in `/proc` with the `clear_child_tid` address, so if we have a syscall
+    /// `gadget` we make the thread tell us with `PR_GET_TID_ADDRESS`, which writes it to
+    /// the thread's stack just past the red zone, where it's free to scribble (see
+    /// `scratch_addr`). Its alternate signal stack is read the same way.
+    pub fn capture(
+        procfs: &Process,
+        ptrace: &PTrace,
+        mem: &MemReader,
+        gadget: Option<u64>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let regs = ptrace.get_regs()?;
+        let sigmask = procfs.task_from_tid(ptrace.pid)?.status()?.sigblk;
+        let pending = signal::thread_pending(procfs, ptrace.pid)?;
+
+        let mut clear_child_tid = 0;
+        let mut altstack = None;
+        if let Some(gadget) = gadget {
+            let scratch = scratch_addr(&regs, 8);
+            let res = ptrace.syscall(gadget, SYS_prctl, &[PR_GET_TID_ADDRESS as u64, scratch])?;
+
+            match res {
+                0 => {
+                    let addr = mem.read_batch(&[vec![(scratch, 8)]]).pop().unwrap()?;
+                    clear_child_tid = u64::from_ne_bytes(addr.try_into().unwrap());
+                }
+                // This needs CONFIG_CHECKPOINT_RESTORE
+                _ => debug!(
+                    "Couldn't get the clear_child_tid address of thread {}: {}",
+                    ptrace.pid,
+                    io::Error::from_raw_os_error(-res as i32)
+                ),
+            }
+
+            altstack = AltStack::capture(ptrace, mem, &regs, gadget)?;
+        }
+
+        let syscall = InterruptedSyscall::detect(&regs);
+        if let Some(syscall) = &syscall {
+            debug!("Thread {} is in syscall {syscall:?}", ptrace.pid);
+        }
+
+        Ok(Self {
+            tid: ptrace.pid,
+            regs,
+            sigmask,
+            pending,
+            altstack,
+            clear_child_tid,
+            syscall,
+            sched: Some(SchedAttrs::capture(procfs, ptrace.pid)?),
+        })
+    }
+
+    /// The registers to restore the thread with.
+    ///
+    /// If it was in a syscall, they're rewound to the `syscall` instruction with the
+    /// syscall number back in `rax`, so that the thread makes the same call again as
+    /// soon as it runs. Since there is no `restart_syscall` state to continue from,
+    /// `ERESTART_RESTARTBLOCK` calls start over too, e.g. a `nanosleep` is made again
+    /// with the remaining time it wrote out when it was interrupted (if it was asked to).
+    /// `orig_rax` is cleared so that the kernel doesn't restart it a second time.
+    pub fn restore_regs(&self) -> Registers {
+        let mut regs = self.regs.clone();
+        if let Some(syscall) = &self.syscall {
+            regs.regs.rip -= 2;
+            regs.regs.rax = syscall.nr;
+            regs.regs.orig_rax = u64::MAX;
+        }
+
+        regs
+    }
+
+    /// Reads the threads of the checkpoint in `cp_dir`, the thread group leader first.
+    ///
+    /// Checkpoints from before we saved every thread only have the registers of the leader.
+    pub fn open_all(cp_dir: &Path) -> Result<Vec<Self>, Box<dyn Error>> {
+        match File::open(cp_dir.join("threads")) {
+            Ok(file) => Ok(serde_json::from_reader(file)?),
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                let regs = serde_json::from_reader(File::open(cp_dir.join("regs"))?)?;
+                Ok(vec![Self {
+                    tid: 0,
+                    regs,
+                    sigmask: 0,
+                    pending: 0,
+                    altstack: None,
+                    clear_child_tid: 0,
+                    syscall: None,
+                    sched: None,
+                }])
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+/// Where we can have the stopped thread with the registers `regs` write `len` bytes for us:
+/// just below the red zone under its stack pointer, which nothing it does relies on
+pub fn scratch_addr(regs: &Registers, len: u64) -> u64 {
+    (regs.regs.rsp - 128 - len) & !7
+}
+
+/// Stops every thread of the process `procfs` and attaches to it, the thread group leader first.
+///
+/// The leader is attached to like we always have, which only stops it, and the other
+/// threads are seized and interrupted one by one. Threads can start more threads
+/// until they're stopped, so we go over the list until there's nothing new in it.
+pub fn attach_all(procfs: &Process) -> Result<Vec<PTrace>, Box<dyn Error>> {
+    let mut leader = PTrace::new(procfs.pid);
+    leader.attach()?;
+    leader.wait_pause()?;
+
+    let mut threads = vec![leader];
+    loop {
+        let mut found = false;
+        for task in procfs.tasks()? {
+            let Ok(task) = task else {
+                // It exited while we were listing them
+                continue;
+            };
+            if threads.iter().any(|t| t.pid == task.tid) {
+                continue;
+            }
+
+            let mut thread = PTrace::new(task.tid);
+            match thread.seize() {
+                Err(e) if e.raw_os_error() == Some(ESRCH) => continue,
+                res => res?,
+            }
+            thread.interrupt()?;
+            thread.wait_pause()?;
+
+            debug!("Attached to thread {}", task.tid);
+            threads.push(thread);
+            found = true;
+        }
+
+        if !found {
+            return Ok(threads);
+        }
+    }
+}
+
+/// Detaches from the threads `attach_all` returned and lets the process go
+pub fn detach_all(mut threads: Vec<PTrace>) -> Result<(), Box<dyn Error>> {
+    let mut leader = threads.remove(0);
+    for mut thread in threads {
+        thread.detach()?;
+    }
+
+    // Attaching sent the process a SIGSTOP, which detaching
+    // (or dropping `leader`) doesn't undo, so it needs a SIGCONT
+    leader.resume()?;
+    leader.detach()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        env, fs,
+        process::Command,
+        thread::{self, sleep},
+        time::Duration,
+    };
+
+    use libc::{SYS_clock_nanosleep, SYS_nanosleep, EINTR};
+
+    use super::*;
+
+    /// Not a test by itself, the process `attach_all_stops_every_thread` attaches to
+    #[test]
+    #[ignore]
+    fn threaded_tracee() {
+        for _ in 0..3 {
+            thread::spawn(|| sleep(Duration::from_secs(30)));
+        }
+        sleep(Duration::from_secs(30));
+    }
+
+    #[test]
+    fn attach_all_stops_every_thread() {
+        let mut tracee = Command::new(env::current_exe().unwrap())
+            .args(["--ignored", "--exact", "thread::tests::threaded_tracee"])
+            .spawn()
+            .unwrap();
+        let procfs = Process::new(tracee.id() as pid_t).unwrap();
+        while procfs.tasks().unwrap().count() < 4 {
+            sleep(Duration::from_millis(10));
+        }
+
+        let tasks = attach_all(&procfs).unwrap();
+        assert_eq!(tasks[0].pid, procfs.pid);
+        assert_eq!(tasks.len(), procfs.tasks().unwrap().count());
+
+        let mem = MemReader::new(&procfs).unwrap();
+        for task in &tasks {
+            let state = procfs
+                .task_from_tid(task.pid)
+                .unwrap()
+                .stat()
+                .unwrap()
+                .state;
+            assert_eq!(state, 't');
+
+            let thread = Thread::capture(&procfs, task, &mem, None).unwrap();
+            assert_eq!(thread.tid, task.pid);
+        }
+
+        // Every thread has a TLS of its own
+        let mut tls: Vec<_> = tasks
+            .iter()
+            .map(|t| t.get_regs().unwrap().regs.fs_base)
+            .collect();
+        tls.sort();
+        tls.dedup();
+        assert_eq!(tls.len(), tasks.len());
+
+        detach_all(tasks).unwrap();
+        tracee.kill().unwrap();
+        tracee.wait().unwrap();
+    }
+
+    #[test]
+    fn interrupted_sleep_is_restarted() {
+        let mut tracee = Command::new("sleep").arg("30").spawn().unwrap();
+        let procfs = Process::new(tracee.id() as pid_t).unwrap();
+        while procfs.stat().unwrap().state != 'S' {
+            sleep(Duration::from_millis(10));
+        }
+        sleep(Duration::from_millis(50));
+
+        let tasks = attach_all(&procfs).unwrap();
+        let mem = MemReader::new(&procfs).unwrap();
+        let thread = Thread::capture(&procfs, &tasks[0], &mem, None).unwrap();
+
+        // Its sleep gets continued through restart_syscall, which we can't restore
+        let syscall = thread.syscall.clone().unwrap();
+        assert!([SYS_clock_nanosleep, SYS_nanosleep].contains(&(syscall.nr as i64)));
+        assert_eq!(syscall.restart, Restart::RestartBlock);
+
+        // So it's pointed back at the syscall instruction to make it again
+        let regs = thread.restore_regs();
+        assert_eq!(regs.regs.rip, thread.regs.regs.rip - 2);
+        assert_eq!((regs.regs.rax, regs.regs.orig_rax), (syscall.nr, u64::MAX));
+        let code = mem.read_batch(&[vec![(regs.regs.rip, 2)]]).pop().unwrap();
+        assert_eq!(code.unwrap(), [0x0f, 0x05]);
+
+        // Which it does, and goes right back to sleep
+        tasks[0].set_regs(regs).unwrap();
+        detach_all(tasks).unwrap();
+        sleep(Duration::from_millis(50));
+        let stat = procfs.stat().unwrap();
+        assert_eq!(stat.state, 'S');
+        let current = fs::read_to_string(format!("/proc/{}/syscall", procfs.pid)).unwrap();
+        assert_eq!(
+            current.split(' ').next(),
+            Some(syscall.nr.to_string().as_str())
+        );
+
+        // Anything but a restart error is just a result
+        let mut regs = thread.regs.clone();
+        regs.regs.rax = -(EINTR as i64) as u64;
+        assert!(InterruptedSyscall::detect(&regs).is_none());
+        regs.regs.rax = -512i64 as u64;
+        regs.regs.orig_rax = u64::MAX;
+        assert!(InterruptedSyscall::detect(&regs).is_none());
+
+        tracee.kill().unwrap();
+        tracee.wait().unwrap();
+    }
+}
@@ -0,0 +1,136 @@
+use std::{
+    error::Error,
+    fs::File,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use libc::pid_t;
+use procfs::process::Process;
+use serde::{Deserialize, Serialize};
+
+/// The directory under the checkpoint path that holds the checkpoint directory of every
+/// descendant of the process, as `<pid>/`, each laid out like a checkpoint path of its own
+pub const PROCS_DIR: &str = "procs";
+
+/// One process of a checkpointed process tree
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TreeNode {
+    /// The pid of the process when it was checkpointed
+    pub pid: pid_t,
+    /// The pid of its parent, or 0 for the root of the tree
+    pub parent: pid_t,
+    /// The checkpoint of the process, in its own checkpoint directory, that's part of this one
+    pub seq: u64,
+}
+
+impl TreeNode {
+    /// Reads the process tree of the checkpoint in `cp_dir`, every process after its parent.
+    ///
+    /// This is empty if only the one process was checkpointed.
+    pub fn open_all(cp_dir: &Path) -> Result<Vec<Self>, Box<dyn Error>> {
+        match File::open(cp_dir.join("tree")) {
+            Ok(file) => Ok(serde_json::from_reader(file)?),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(vec![]),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// The checkpoint path that this process is saved under, for a tree saved at `cpath`
+    pub fn path(&self, cpath: &Path) -> PathBuf {
+        match self.parent {
+            0 => cpath.to_path_buf(),
+            _ => cpath.join(PROCS_DIR).join(self.pid.to_string()),
+        }
+    }
+}
+
+/// Returns the `(pid, parent)` of every live descendant of `pid`, each after its parent
+pub fn descendants(pid: pid_t) -> Result<Vec<(pid_t, pid_t)>, Box<dyn Error>> {
+    let mut found = vec![];
+    let mut parents = vec![pid];
+    let mut next = 0;
+
+    while let Some(&parent) = parents.get(next) {
+        next += 1;
+
+        // Either of these fails if the process exited in the meantime
+        let Ok(tasks) = Process::new(parent).and_then(|p| p.tasks()) else {
+            continue;
+        };
+
+        for task in tasks.flatten() {
+            for child in task.children().unwrap_or_default() {
+                let child = child as pid_t;
+                let zombie = Process::new(child)
+                    .and_then(|p| p.stat())
+                    .map_or(true, |stat| stat.state == 'Z');
+
+                // There's nothing left of a zombie to checkpoint
+                if zombie || parents.contains(&child) {
+                    continue;
+                }
+
+                found.push((child, parent));
+                parents.push(child);
+            }
+        }
+    }
+
+    Ok(found)
+}
+
+/// Whether the process `pid` is still around
+pub fn is_alive(pid: pid_t) -> bool {
+    Process::new(pid)
+        .and_then(|p| p.stat())
+        .is_ok_and(|stat| stat.state != 'Z')
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{process::Command, thread::sleep, time::Duration};
+
+    use libc::{kill, SIGKILL};
+
+    use super::*;
+
+    #[test]
+    fn descendants_come_after_their_parents() {
+        let mut root = Command::new("sh")
+            .args(["-c", "sh -c 'sleep 30 & wait' & sleep 30 & wait"])
+            .spawn()
+            .unwrap();
+        let pid = root.id() as pid_t;
+
+        let mut found = descendants(pid).unwrap();
+        while found.len() < 3 {
+            sleep(Duration::from_millis(10));
+            found = descendants(pid).unwrap();
+        }
+
+        assert_eq!(found.len(), 3);
+        for (i, &(child, parent)) in found.iter().enumerate() {
+            assert!(parent == pid || found[..i].iter().any(|&(p, _)| p == parent));
+            assert!(is_alive(child));
+        }
+
+        let node = TreeNode {
+            pid: found[0].0,
+            parent: pid,
+            seq: 1,
+        };
+        let cpath = Path::new("/cp");
+        assert_eq!(
+            node.path(cpath),
+            cpath.join(PROCS_DIR).join(node.pid.to_string())
+        );
+
+        for &(child, _) in found.iter().rev() {
+            unsafe { kill(child, SIGKILL) };
+        }
+        root.kill().unwrap();
+        root.wait().unwrap();
+        assert!(!is_alive(pid));
+    }
+}
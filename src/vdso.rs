@@ -0,0 +1,158 @@
+use std::error::Error;
+
+use libc::pid_t;
+use procfs::process::{MMapPath, MemoryMap, Process};
+
+use crate::memory::MemReader;
+
+/// Whether `map` is the vdso or one of the vvar pages it reads the time out of.
+///
+/// These are never saved: they belong to the running kernel, so the restored
+/// process uses the ones the kernel gives it, moved to where the old ones were.
+pub fn is_vdso(map: &MemoryMap) -> bool {
+    match &map.pathname {
+        MMapPath::Vdso | MMapPath::Vvar => true,
+        // Newer kernels split the vvar pages up, e.g. into [vvar] and [vvar_vclock]
+        MMapPath::Other(name) => name.starts_with("vvar"),
+        _ => false,
+    }
+}
+
+/// Finds a `syscall` instruction in the vdso of the process that we can
+/// point it at to make it run syscalls for us
+pub fn syscall_gadget(mem: &MemReader, maps: &[MemoryMap]) -> Result<u64, Box<dyn Error>> {
+    let vdso = maps
+        .iter()
+        .find(|m| m.pathname == MMapPath::Vdso)
+        .ok_or("process has no vdso to find a syscall instruction in")?;
+
+    let code = mem.read_region(vdso)?;
+
+    let offset = code
+        .windows(2)
+        .position(|w| w == [0x0f, 0x05])
+        .ok_or("no syscall instruction in the vdso")?;
+
+    Ok(vdso.address.0 + offset as u64)
+}
+
+/// The vdso mappings of a process relative to the first one, which is all
+/// that has to match for the vdso code to find its vvar pages after a move
+fn layout(maps: &[MemoryMap]) -> Vec<(&MMapPath, u64, u64)> {
+    let base = maps.first().map_or(0, |m| m.address.0);
+    maps.iter()
+        .map(|m| (&m.pathname, m.address.0 - base, m.address.1 - m.address.0))
+        .collect()
+}
+
+fn check_layout(current: &[MemoryMap], saved: &[MemoryMap]) -> Result<(), Box<dyn Error>> {
+    if layout(current) != layout(saved) {
+        return Err(format!(
+            "can't move the vdso back to where it was, the running kernel's vdso layout {:x?} \
+             doesn't match the checkpointed one {:x?} (or something was restored over it)",
+            layout(current),
+            layout(saved)
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// One vdso mapping that the bootstrapper moves. All it can find of them is
+/// the vdso (in its auxiliary vector), so they're relative to that.
+#[derive(Debug, Clone, Copy)]
+pub struct BsMove {
+    /// How far the mapping is from the vdso, wrapping if it's before it
+    pub offset: u64,
+    pub len: u64,
+    pub target: u64,
+}
+
+/// Works out how the bootstrapper moves its own vdso and vvar to where `saved` were,
+/// before it maps in the process' memory, which could otherwise land on top of them.
+///
+/// It runs on the same kernel as we do, so we go by our own layout.
+pub fn bootstrapper_moves(saved: &[MemoryMap]) -> Result<Vec<BsMove>, Box<dyn Error>> {
+    if saved.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let current: Vec<_> = Process::myself()?
+        .maps()?
+        .into_iter()
+        .filter(is_vdso)
+        .collect();
+    check_layout(&current, saved)?;
+
+    let vdso = current
+        .iter()
+        .find(|m| m.pathname == MMapPath::Vdso)
+        .ok_or("we have no vdso to go by")?
+        .address
+        .0;
+
+    Ok(current
+        .iter()
+        .zip(saved)
+        .map(|(map, target)| BsMove {
+            offset: map.address.0.wrapping_sub(vdso),
+            len: map.address.1 - map.address.0,
+            target: target.address.0,
+        })
+        .collect())
+}
+
+/// Checks that the bootstrapper of the process `pid` moved its vdso and vvar to where
+/// `saved` were (see `bootstrapper_moves`), all together and in one piece
+pub fn check_moved(pid: pid_t, saved: &[MemoryMap]) -> Result<(), Box<dyn Error>> {
+    if saved.is_empty() {
+        return Ok(());
+    }
+
+    let current: Vec<_> = Process::new(pid)?
+        .maps()?
+        .into_iter()
+        .filter(is_vdso)
+        .collect();
+    check_layout(&current, saved)?;
+
+    if current[0].address.0 != saved[0].address.0 {
+        return Err(format!(
+            "the bootstrapper left {:?} at {:x} instead of moving it to {:x}",
+            current[0].pathname, current[0].address.0, saved[0].address.0
+        )
+        .into());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_our_own_vdso() {
+        let procfs = Process::myself().unwrap();
+        let maps = procfs.maps().unwrap().0;
+        let vdso: Vec<_> = maps.iter().filter(|m| is_vdso(m)).cloned().collect();
+        assert!(vdso.iter().any(|m| m.pathname == MMapPath::Vdso));
+
+        let gadget = syscall_gadget(&MemReader::new(&procfs).unwrap(), &maps).unwrap();
+        let code = unsafe { std::slice::from_raw_parts(gadget as *const u8, 2) };
+        assert_eq!(code, [0x0f, 0x05]);
+
+        // Moving everything together keeps the layout, moving one part doesn't
+        let mut moved = vdso.clone();
+        for map in &mut moved {
+            map.address.0 += 0x10000;
+            map.address.1 += 0x10000;
+        }
+        assert_eq!(layout(&moved), layout(&vdso));
+        if moved.len() > 1 {
+            moved[0].address.0 -= 0x1000;
+            moved[0].address.1 -= 0x1000;
+            assert_ne!(layout(&moved), layout(&vdso));
+        }
+    }
+}
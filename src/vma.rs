@@ -0,0 +1,146 @@
+use std::error::Error;
+
+use libc::{
+    c_int, pid_t, MADV_DONTDUMP, MADV_DONTFORK, MADV_HUGEPAGE, MADV_MERGEABLE, MADV_NOHUGEPAGE,
+    MADV_RANDOM, MADV_SEQUENTIAL, MADV_WIPEONFORK, MAP_GROWSDOWN, MAP_NORESERVE,
+};
+use log::warn;
+use procfs::process::{MMPermissions, MMapPath, MemoryMap, MemoryMaps, Process, VmFlags};
+
+/// The flags of a region that we put back on restore, the rest are either implied by
+/// its permissions or say something about it that changes on its own (like `SD`,
+/// which would make every region look new after clearing the soft-dirty bits)
+const RESTORED: VmFlags = VmFlags::GD
+    .union(VmFlags::NR)
+    .union(VmFlags::LO)
+    .union(VmFlags::SR)
+    .union(VmFlags::RR)
+    .union(VmFlags::DC)
+    .union(VmFlags::WF)
+    .union(VmFlags::DD)
+    .union(VmFlags::HG)
+    .union(VmFlags::NH)
+    .union(VmFlags::MG);
+
+/// The flags that come from `madvise`, and what to pass it to set them
+const ADVICE: [(VmFlags, c_int); 8] = [
+    (VmFlags::SR, MADV_SEQUENTIAL),
+    (VmFlags::RR, MADV_RANDOM),
+    (VmFlags::DC, MADV_DONTFORK),
+    (VmFlags::WF, MADV_WIPEONFORK),
+    (VmFlags::DD, MADV_DONTDUMP),
+    (VmFlags::HG, MADV_HUGEPAGE),
+    (VmFlags::NH, MADV_NOHUGEPAGE),
+    (VmFlags::MG, MADV_MERGEABLE),
+];
+
+/// The memory regions of `procfs`, with the flags of each from smaps that we restore
+/// in `extension.vm_flags`. The rest of smaps is usage statistics, which we drop so that
+/// the same region compares equal from one checkpoint to the next.
+pub fn maps(procfs: &Process) -> Result<MemoryMaps, Box<dyn Error>> {
+    let mut maps = procfs.smaps()?;
+    for map in &mut maps.0 {
+        map.extension.map.clear();
+        map.extension.vm_flags &= RESTORED;
+    }
+    Ok(maps)
+}
+
+/// Whether the process asked for `map` to be left out of core dumps, in which case we
+/// leave it out of the checkpoint too, unless it's a mapping of a file (which we'd have
+/// to restore from the file) or shared (which lives on in whatever it's shared with)
+pub fn dont_dump(map: &MemoryMap) -> bool {
+    map.extension.vm_flags.contains(VmFlags::DD)
+        && map.perms.contains(MMPermissions::PRIVATE)
+        && !matches!(map.pathname, MMapPath::Path(_))
+}
+
+/// Whether `map` has to be anonymous memory to be what it was: a stack that grows
+/// down, or memory that's wiped in forked children
+pub fn needs_anonymous(map: &MemoryMap) -> bool {
+    map.extension.vm_flags.intersects(VmFlags::GD | VmFlags::WF)
+}
+
+/// The flags to `mmap` the region with, on top of `MAP_FIXED` and the sharing mode
+pub fn mmap_flags(map: &MemoryMap) -> c_int {
+    let flags = map.extension.vm_flags;
+    let mut mmap_flags = 0;
+    if flags.contains(VmFlags::GD) {
+        mmap_flags |= MAP_GROWSDOWN;
+    }
+    if flags.contains(VmFlags::NR) {
+        mmap_flags |= MAP_NORESERVE;
+    }
+    mmap_flags
+}
+
+/// What to `madvise` the region with once it's mapped
+pub fn advice(map: &MemoryMap) -> Vec<c_int> {
+    ADVICE
+        .iter()
+        .filter(|(flag, _)| map.extension.vm_flags.contains(*flag))
+        .map(|(_, advice)| *advice)
+        .collect()
+}
+
+/// Whether the region has to be `mlock`ed
+pub fn locked(map: &MemoryMap) -> bool {
+    map.extension.vm_flags.contains(VmFlags::LO)
+}
+
+/// Warns about the regions of `maps` that didn't get all of their flags back in the
+/// stopped bootstrapper `pid`, which mostly happens when it can't lock as much memory
+pub fn check(pid: pid_t, maps: &[MemoryMap]) -> Result<(), Box<dyn Error>> {
+    let current = self::maps(&Process::new(pid)?)?;
+    for map in maps {
+        let Some(now) = current.0.iter().find(|m| m.address.0 == map.address.0) else {
+            continue;
+        };
+
+        let missing = map.extension.vm_flags - now.extension.vm_flags;
+        if !missing.is_empty() {
+            warn!(
+                "Memory at {:#x} didn't get {missing:?} back, it's now {:?}",
+                map.address.0, now.extension.vm_flags
+            );
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ptr;
+
+    use libc::{MAP_ANONYMOUS, MAP_FAILED, MAP_PRIVATE, PROT_READ, PROT_WRITE};
+
+    use super::*;
+    use crate::pagemap::PAGE_SIZE;
+
+    #[test]
+    fn advice_round_trips_through_smaps() {
+        let len = 4 * PAGE_SIZE as usize;
+        let flags = MAP_PRIVATE | MAP_ANONYMOUS | MAP_NORESERVE;
+        let addr =
+            unsafe { libc::mmap(ptr::null_mut(), len, PROT_READ | PROT_WRITE, flags, -1, 0) };
+        assert_ne!(addr, MAP_FAILED);
+        for advice in [MADV_RANDOM, MADV_DONTDUMP, MADV_WIPEONFORK] {
+            assert_eq!(unsafe { libc::madvise(addr, len, advice) }, 0);
+        }
+
+        let maps = maps(&Process::myself().unwrap()).unwrap();
+        let map = maps.0.iter().find(|m| m.address.0 == addr as u64).unwrap();
+        assert!(map.extension.map.is_empty());
+        assert_eq!(
+            map.extension.vm_flags,
+            VmFlags::NR | VmFlags::RR | VmFlags::DD | VmFlags::WF
+        );
+
+        assert_eq!(mmap_flags(map), MAP_NORESERVE);
+        assert_eq!(advice(map), [MADV_RANDOM, MADV_WIPEONFORK, MADV_DONTDUMP]);
+        assert!(dont_dump(map) && needs_anonymous(map) && !locked(map));
+
+        unsafe { libc::munmap(addr, len) };
+    }
+}